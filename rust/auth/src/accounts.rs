@@ -0,0 +1,649 @@
+use sustenet_shared as shared;
+
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+
+use argon2::{ Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString };
+use rusqlite::{ Connection, ErrorCode, OptionalExtension, params };
+use sha2::{ Digest, Sha256 };
+
+use shared::packets::Status;
+use shared::roles::Role;
+use shared::utils::unix_time;
+
+use crate::external::{ ExternalIdentity, JwtError };
+use crate::totp;
+
+/// How long a username can be, in bytes.
+pub const USERNAME_LEN: RangeInclusive<usize> = 3..=32;
+/// How long a password can be, in bytes. It has to fit in a u8 length-prefixed string.
+pub const PASSWORD_LEN: RangeInclusive<usize> = 8..=128;
+/// How long a refresh token is, in bytes.
+pub const REFRESH_TOKEN_LEN: usize = 32;
+/// How many wrong one-time codes in a row an account can send before they're refused for `TOTP_LOCKOUT`.
+pub const MAX_TOTP_FAILURES: u32 = 5;
+/// In seconds. Without it, a stolen password could be used to guess every code by reconnecting.
+pub const TOTP_LOCKOUT: u64 = 300;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub id: u64,
+    pub username: String,
+    /// Unix time in seconds.
+    pub created_at: u64,
+    /// Put in the account's tokens so clusters know what the player can do.
+    pub roles: Vec<String>,
+}
+
+/// Whether an account has to give a one-time code when it logs in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TotpStatus {
+    /// The account is just a player and never set it up.
+    NotNeeded,
+    /// The account needs it but hasn't sent a code yet. Holds the secret to put in an authenticator app.
+    Enrolling(Vec<u8>),
+    Enrolled,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    /// The username is the wrong length or has characters that aren't allowed.
+    InvalidUsername,
+    /// The password is the wrong length.
+    InvalidPassword,
+    /// Someone already has the username, ignoring case.
+    Taken,
+    /// The username doesn't exist or the password is wrong. They aren't told
+    /// apart so accounts can't be found by guessing. Also used for refresh
+    /// tokens that don't exist, expired, or were revoked.
+    WrongCredentials,
+    /// The one-time code is wrong or was already used.
+    WrongCode,
+    /// Too many wrong one-time codes were sent for the account recently.
+    TotpLocked,
+    /// Too many failed logins from the IP or for the username. See `limits`.
+    Throttled,
+    /// The identity provider's JWT was refused.
+    External(JwtError),
+    Database(rusqlite::Error),
+    Hash(argon2::password_hash::Error),
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccountError::InvalidUsername =>
+                write!(
+                    f,
+                    "Usernames have to be {} to {} letters, numbers, dots, dashes, or underscores.",
+                    USERNAME_LEN.start(),
+                    USERNAME_LEN.end()
+                ),
+            AccountError::InvalidPassword =>
+                write!(f, "Passwords have to be {} to {} bytes.", PASSWORD_LEN.start(), PASSWORD_LEN.end()),
+            AccountError::Taken => write!(f, "The username is taken."),
+            AccountError::WrongCredentials => write!(f, "The username or password is wrong."),
+            AccountError::WrongCode => write!(f, "The one-time code is wrong."),
+            AccountError::TotpLocked => write!(f, "Too many wrong one-time codes. Try again later."),
+            AccountError::Throttled => write!(f, "Too many failed logins. Try again later."),
+            AccountError::External(e) => write!(f, "{e}"),
+            AccountError::Database(e) => write!(f, "Database error: {e}"),
+            AccountError::Hash(e) => write!(f, "Failed to hash the password: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<rusqlite::Error> for AccountError {
+    fn from(e: rusqlite::Error) -> Self {
+        AccountError::Database(e)
+    }
+}
+
+impl AccountError {
+    /// The status sent to the client.
+    pub fn status(&self) -> Status {
+        match self {
+            AccountError::InvalidUsername | AccountError::InvalidPassword => Status::BadRequest,
+            AccountError::Taken => Status::Conflict,
+            AccountError::WrongCredentials | AccountError::WrongCode => Status::Unauthorized,
+            AccountError::TotpLocked | AccountError::Throttled => Status::TooManyAttempts,
+            AccountError::External(JwtError::Load(_)) => Status::ServerError,
+            AccountError::External(_) => Status::Unauthorized,
+            AccountError::Database(_) | AccountError::Hash(_) => Status::ServerError,
+        }
+    }
+}
+
+/// The accounts in the SQLite database. Passwords are hashed with Argon2 and
+/// saved as PHC strings, so the parameters can change without breaking old hashes.
+///
+/// Hashing is slow on purpose. Call these from `spawn_blocking`.
+pub struct Accounts {
+    db: Mutex<Connection>,
+    /// Checked when the username doesn't exist so it takes as long as a wrong password.
+    dummy_hash: String,
+}
+
+impl Accounts {
+    pub fn open(path: &str) -> Result<Self, AccountError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, AccountError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> Result<Self, AccountError> {
+        db.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE IF NOT EXISTS accounts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS account_roles (
+                account_id INTEGER NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                role TEXT NOT NULL,
+                PRIMARY KEY (account_id, role)
+            );
+            CREATE TABLE IF NOT EXISTS external_accounts (
+                issuer TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                account_id INTEGER NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                PRIMARY KEY (issuer, subject)
+            );
+            CREATE TABLE IF NOT EXISTS refresh_tokens (
+                hash BLOB PRIMARY KEY,
+                account_id INTEGER NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+                issued_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                totp_verified INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS totp (
+                account_id INTEGER PRIMARY KEY REFERENCES accounts (id) ON DELETE CASCADE,
+                secret BLOB NOT NULL,
+                confirmed INTEGER NOT NULL DEFAULT 0,
+                last_step INTEGER,
+                failures INTEGER NOT NULL DEFAULT 0,
+                locked_until INTEGER NOT NULL DEFAULT 0
+            );"
+        )?;
+        // Refresh tokens from before the column was added never had a code checked.
+        if db.prepare("SELECT totp_verified FROM refresh_tokens LIMIT 0").is_err() {
+            db.execute(
+                "ALTER TABLE refresh_tokens ADD COLUMN totp_verified INTEGER NOT NULL DEFAULT 0",
+                []
+            )?;
+        }
+        Ok(Accounts { db: Mutex::new(db), dummy_hash: hash_password("not a real password")? })
+    }
+
+    pub fn register(&self, username: &str, password: &str) -> Result<Account, AccountError> {
+        check_username(username)?;
+        if !PASSWORD_LEN.contains(&password.len()) {
+            return Err(AccountError::InvalidPassword);
+        }
+
+        let password_hash = hash_password(password)?;
+        let created_at = unix_time();
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        match
+            db.execute(
+                "INSERT INTO accounts (username, password_hash, created_at) VALUES (?1, ?2, ?3)",
+                params![username, password_hash, created_at as i64]
+            )
+        {
+            Ok(_) => (),
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+                return Err(AccountError::Taken);
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+        Ok(Account { id: db.last_insert_rowid() as u64, username: username.to_string(), created_at, roles: Vec::new() })
+    }
+
+    pub fn login(&self, username: &str, password: &str) -> Result<Account, AccountError> {
+        let found = {
+            let db = self.db.lock().expect("The accounts lock was poisoned.");
+            match
+                db
+                    .query_row(
+                        "SELECT id, username, created_at, password_hash FROM accounts WHERE username = ?1",
+                        params![username],
+                        |row| Ok((row_to_account(row)?, row.get::<_, String>(3)?))
+                    )
+                    .optional()?
+            {
+                Some((mut account, password_hash)) => {
+                    account.roles = roles(&db, account.id)?;
+                    Some((account, password_hash))
+                }
+                None => None,
+            }
+        };
+
+        // Accounts from an identity provider don't have a password.
+        let (account, password_hash) = match found {
+            Some((account, password_hash)) if !password_hash.is_empty() => (Some(account), password_hash),
+            _ => (None, self.dummy_hash.clone()),
+        };
+        let hash = PasswordHash::new(&password_hash).map_err(AccountError::Hash)?;
+        match (account, Argon2::default().verify_password(password.as_bytes(), &hash)) {
+            (Some(account), Ok(())) => Ok(account),
+            _ => Err(AccountError::WrongCredentials),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<Account>, AccountError> {
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        let account = db
+            .query_row(
+                "SELECT id, username, created_at FROM accounts WHERE id = ?1",
+                params![id as i64],
+                row_to_account
+            )
+            .optional()?;
+        match account {
+            Some(mut account) => {
+                account.roles = roles(&db, account.id)?;
+                Ok(Some(account))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Finds the account linked to the identity, or creates one. New accounts
+    /// take the identity's username if it's allowed and free, and get a random
+    /// `ext_` one otherwise. They can't log in with a password.
+    pub fn link_external(&self, identity: &ExternalIdentity) -> Result<Account, AccountError> {
+        let mut db = self.db.lock().expect("The accounts lock was poisoned.");
+        let tx = db.transaction()?;
+        let linked = tx
+            .query_row(
+                "SELECT account_id FROM external_accounts WHERE issuer = ?1 AND subject = ?2",
+                params![identity.issuer, identity.subject],
+                |row| row.get::<_, i64>(0)
+            )
+            .optional()?;
+        if let Some(id) = linked {
+            let mut account = tx.query_row(
+                "SELECT id, username, created_at FROM accounts WHERE id = ?1",
+                params![id],
+                row_to_account
+            )?;
+            account.roles = roles(&tx, account.id)?;
+            return Ok(account);
+        }
+
+        let created_at = unix_time();
+        let claimed = identity.username.as_deref().filter(|username| check_username(username).is_ok());
+        let mut username = claimed.map_or_else(generated_username, |username| username.to_string());
+        loop {
+            match
+                tx.execute(
+                    "INSERT INTO accounts (username, password_hash, created_at) VALUES (?1, '', ?2)",
+                    params![username, created_at as i64]
+                )
+            {
+                Ok(_) => {
+                    break;
+                }
+                Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+                    username = generated_username();
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        }
+        let id = tx.last_insert_rowid();
+        tx.execute(
+            "INSERT INTO external_accounts (issuer, subject, account_id) VALUES (?1, ?2, ?3)",
+            params![identity.issuer, identity.subject, id]
+        )?;
+        tx.commit()?;
+        Ok(Account { id: id as u64, username, created_at, roles: Vec::new() })
+    }
+
+    /// Changes the password if the old one is right. The account's refresh
+    /// tokens are revoked. The caller should revoke its other tokens too.
+    pub fn change_password(&self, username: &str, old: &str, new: &str) -> Result<Account, AccountError> {
+        if !PASSWORD_LEN.contains(&new.len()) {
+            return Err(AccountError::InvalidPassword);
+        }
+        let account = self.login(username, old)?;
+        self.set_password(account.id, new)?;
+        Ok(account)
+    }
+
+    /// Replaces the password without checking the old one, and revokes the account's refresh tokens.
+    pub fn set_password(&self, id: u64, password: &str) -> Result<(), AccountError> {
+        if !PASSWORD_LEN.contains(&password.len()) {
+            return Err(AccountError::InvalidPassword);
+        }
+        let password_hash = hash_password(password)?;
+        let mut db = self.db.lock().expect("The accounts lock was poisoned.");
+        let tx = db.transaction()?;
+        tx.execute("UPDATE accounts SET password_hash = ?1 WHERE id = ?2", params![password_hash, id as i64])?;
+        tx.execute("DELETE FROM refresh_tokens WHERE account_id = ?1", params![id as i64])?;
+        Ok(tx.commit()?)
+    }
+
+    /// Whether the account needs a one-time code. Accounts with a role above
+    /// player get a secret the first time, which is kept until a code for it is sent.
+    pub fn totp_status(&self, account: &Account) -> Result<TotpStatus, AccountError> {
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        let found = db
+            .query_row(
+                "SELECT secret, confirmed FROM totp WHERE account_id = ?1",
+                params![account.id as i64],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, bool>(1)?))
+            )
+            .optional()?;
+        let needed = account.roles
+            .iter()
+            .filter_map(|role| Role::from_name(role))
+            .any(|role| role > Role::Player);
+        match found {
+            Some((_, true)) => Ok(TotpStatus::Enrolled),
+            Some((secret, false)) if needed => Ok(TotpStatus::Enrolling(secret)),
+            None if needed => {
+                let secret = totp::generate_secret();
+                db.execute("INSERT INTO totp (account_id, secret) VALUES (?1, ?2)", params![account.id as i64, secret])?;
+                Ok(TotpStatus::Enrolling(secret))
+            }
+            _ => Ok(TotpStatus::NotNeeded),
+        }
+    }
+
+    /// Checks a one-time code. The first right one finishes setting it up. Each code only works once.
+    pub fn verify_totp(&self, id: u64, code: &str) -> Result<(), AccountError> {
+        let now = unix_time();
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        let found = db
+            .query_row(
+                "SELECT secret, last_step, failures, locked_until FROM totp WHERE account_id = ?1",
+                params![id as i64],
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, Option<i64>>(1)?,
+                        row.get::<_, u32>(2)?,
+                        row.get::<_, i64>(3)? as u64,
+                    ))
+                }
+            )
+            .optional()?;
+        let Some((secret, last_step, failures, locked_until)) = found else {
+            return Err(AccountError::WrongCode);
+        };
+        if now < locked_until {
+            return Err(AccountError::TotpLocked);
+        }
+        match totp::check(&secret, code, now, last_step.map(|step| step as u64)) {
+            Some(step) => {
+                db.execute(
+                    "UPDATE totp SET confirmed = 1, last_step = ?1, failures = 0 WHERE account_id = ?2",
+                    params![step as i64, id as i64]
+                )?;
+                Ok(())
+            }
+            None if failures + 1 >= MAX_TOTP_FAILURES => {
+                db.execute(
+                    "UPDATE totp SET failures = 0, locked_until = ?1 WHERE account_id = ?2",
+                    params![(now + TOTP_LOCKOUT) as i64, id as i64]
+                )?;
+                Err(AccountError::TotpLocked)
+            }
+            None => {
+                db.execute("UPDATE totp SET failures = failures + 1 WHERE account_id = ?1", params![id as i64])?;
+                Err(AccountError::WrongCode)
+            }
+        }
+    }
+
+    /// Removes the account's one-time password, like when its authenticator
+    /// is lost. It sets up a new one the next time it logs in if it still needs one.
+    pub fn reset_totp(&self, id: u64) -> Result<(), AccountError> {
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        db.execute("DELETE FROM totp WHERE account_id = ?1", params![id as i64])?;
+        Ok(())
+    }
+
+    /// Makes a refresh token for the account. Only its hash is saved, along with
+    /// whether a one-time code was checked for the login it came from.
+    pub fn issue_refresh_token(
+        &self,
+        id: u64,
+        ttl: u64,
+        totp_verified: bool
+    ) -> Result<[u8; REFRESH_TOKEN_LEN], AccountError> {
+        let mut secret = [0u8; REFRESH_TOKEN_LEN];
+        getrandom::fill(&mut secret).expect("Failed to generate a refresh token.");
+        let issued_at = unix_time();
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        db.execute("DELETE FROM refresh_tokens WHERE expires_at <= ?1", params![issued_at as i64])?;
+        db.execute(
+            "INSERT INTO refresh_tokens (hash, account_id, issued_at, expires_at, totp_verified)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                Sha256::digest(secret).as_slice(),
+                id as i64,
+                issued_at as i64,
+                (issued_at + ttl) as i64,
+                totp_verified
+            ]
+        )?;
+        Ok(secret)
+    }
+
+    /// Uses up a refresh token. Returns its account, when it was issued, so it
+    /// can be checked against the revocations, and whether it had a code checked.
+    pub fn refresh(&self, secret: &[u8]) -> Result<(Account, u64, bool), AccountError> {
+        let mut db = self.db.lock().expect("The accounts lock was poisoned.");
+        let tx = db.transaction()?;
+        let hash = Sha256::digest(secret);
+        let found = tx
+            .query_row(
+                "SELECT account_id, issued_at, expires_at, totp_verified FROM refresh_tokens WHERE hash = ?1",
+                params![hash.as_slice()],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)? as u64,
+                        row.get::<_, i64>(2)? as u64,
+                        row.get::<_, bool>(3)?,
+                    ))
+                }
+            )
+            .optional()?;
+        tx.execute("DELETE FROM refresh_tokens WHERE hash = ?1", params![hash.as_slice()])?;
+        let (id, issued_at, totp_verified) = match found {
+            Some((id, issued_at, expires_at, totp_verified)) if unix_time() < expires_at => {
+                (id, issued_at, totp_verified)
+            }
+            _ => {
+                tx.commit()?;
+                return Err(AccountError::WrongCredentials);
+            }
+        };
+        let mut account = tx.query_row(
+            "SELECT id, username, created_at FROM accounts WHERE id = ?1",
+            params![id],
+            row_to_account
+        )?;
+        account.roles = roles(&tx, account.id)?;
+        tx.commit()?;
+        Ok((account, issued_at, totp_verified))
+    }
+
+    /// Deletes every refresh token the account has.
+    pub fn revoke_refresh_tokens(&self, id: u64) -> Result<(), AccountError> {
+        let db = self.db.lock().expect("The accounts lock was poisoned.");
+        db.execute("DELETE FROM refresh_tokens WHERE account_id = ?1", params![id as i64])?;
+        Ok(())
+    }
+
+    /// Replaces the account's roles. Tokens that were already issued keep the old ones until they expire.
+    pub fn set_roles(&self, id: u64, roles: &[Role]) -> Result<(), AccountError> {
+        let mut db = self.db.lock().expect("The accounts lock was poisoned.");
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM account_roles WHERE account_id = ?1", params![id as i64])?;
+        for role in roles {
+            tx.execute("INSERT INTO account_roles (account_id, role) VALUES (?1, ?2)", params![id as i64, role.name()])?;
+        }
+        Ok(tx.commit()?)
+    }
+}
+
+fn row_to_account(row: &rusqlite::Row) -> rusqlite::Result<Account> {
+    Ok(Account {
+        id: row.get::<_, i64>(0)? as u64,
+        username: row.get(1)?,
+        created_at: row.get::<_, i64>(2)? as u64,
+        roles: Vec::new(),
+    })
+}
+
+fn roles(db: &Connection, id: u64) -> rusqlite::Result<Vec<String>> {
+    db.prepare_cached("SELECT role FROM account_roles WHERE account_id = ?1 ORDER BY role")?
+        .query_map(params![id as i64], |row| row.get(0))?
+        .collect()
+}
+
+fn check_username(username: &str) -> Result<(), AccountError> {
+    let valid =
+        USERNAME_LEN.contains(&username.len()) &&
+        username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    match valid {
+        true => Ok(()),
+        false => Err(AccountError::InvalidUsername),
+    }
+}
+
+fn generated_username() -> String {
+    let mut bytes = [0u8; 6];
+    getrandom::fill(&mut bytes).expect("Failed to generate a username.");
+    bytes.iter().fold("ext_".to_string(), |username, byte| format!("{username}{byte:02x}"))
+}
+
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    let mut salt = [0u8; 16];
+    getrandom::fill(&mut salt).expect("Failed to generate a salt.");
+    let salt = SaltString::encode_b64(&salt).map_err(AccountError::Hash)?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(AccountError::Hash)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_register_and_login() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        let account = accounts.register("Player_1", "hunter22").unwrap();
+        assert_eq!(accounts.get(account.id).unwrap(), Some(account.clone()));
+
+        assert!(matches!(accounts.register("player_1", "hunter22"), Err(AccountError::Taken)));
+        assert!(matches!(accounts.register("no spaces", "hunter22"), Err(AccountError::InvalidUsername)));
+        assert!(matches!(accounts.register("player_2", "short"), Err(AccountError::InvalidPassword)));
+
+        // Usernames are matched without case, like they're reserved.
+        accounts.set_roles(account.id, &[Role::Moderator, Role::Admin]).unwrap();
+        let roles = vec!["admin".to_string(), "moderator".to_string()];
+        assert_eq!(accounts.login("player_1", "hunter22").unwrap(), Account { roles, ..account });
+        assert!(matches!(accounts.login("Player_1", "hunter23"), Err(AccountError::WrongCredentials)));
+        assert!(matches!(accounts.login("nobody", "hunter22"), Err(AccountError::WrongCredentials)));
+    }
+
+    #[test]
+    pub fn test_refresh_and_change_password() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        let account = accounts.register("player_1", "hunter22").unwrap();
+
+        // Refresh tokens only work once.
+        let secret = accounts.issue_refresh_token(account.id, 60, false).unwrap();
+        let (refreshed, _, totp_verified) = accounts.refresh(&secret).unwrap();
+        assert_eq!(refreshed, account);
+        assert!(!totp_verified);
+        assert!(matches!(accounts.refresh(&secret), Err(AccountError::WrongCredentials)));
+        let expired = accounts.issue_refresh_token(account.id, 0, false).unwrap();
+        assert!(matches!(accounts.refresh(&expired), Err(AccountError::WrongCredentials)));
+        let verified = accounts.issue_refresh_token(account.id, 60, true).unwrap();
+        assert!(accounts.refresh(&verified).unwrap().2);
+
+        let secret = accounts.issue_refresh_token(account.id, 60, false).unwrap();
+        assert!(matches!(accounts.change_password("player_1", "hunter23", "hunter24"), Err(AccountError::WrongCredentials)));
+        assert!(matches!(accounts.change_password("player_1", "hunter22", "short"), Err(AccountError::InvalidPassword)));
+        assert_eq!(accounts.change_password("player_1", "hunter22", "hunter24").unwrap(), account);
+        assert!(matches!(accounts.login("player_1", "hunter22"), Err(AccountError::WrongCredentials)));
+        assert_eq!(accounts.login("player_1", "hunter24").unwrap(), account);
+        assert!(matches!(accounts.refresh(&secret), Err(AccountError::WrongCredentials)));
+    }
+
+    #[test]
+    pub fn test_totp() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        let mut account = accounts.register("player_1", "hunter22").unwrap();
+        assert_eq!(accounts.totp_status(&account).unwrap(), TotpStatus::NotNeeded);
+
+        // The secret stays the same until a code for it is sent.
+        accounts.set_roles(account.id, &[Role::Moderator]).unwrap();
+        account.roles = vec!["moderator".to_string()];
+        let TotpStatus::Enrolling(secret) = accounts.totp_status(&account).unwrap() else {
+            panic!("A moderator needs a one-time code.");
+        };
+        assert_eq!(accounts.totp_status(&account).unwrap(), TotpStatus::Enrolling(secret.clone()));
+
+        let code = totp_rs::TOTP::new_unchecked(totp_rs::Algorithm::SHA1, 6, 0, totp::STEP, secret, None, String::new())
+            .generate(unix_time());
+        assert!(matches!(accounts.verify_totp(account.id, "12345a"), Err(AccountError::WrongCode)));
+        accounts.verify_totp(account.id, &code).unwrap();
+        assert!(matches!(accounts.verify_totp(account.id, &code), Err(AccountError::WrongCode)));
+        assert_eq!(accounts.totp_status(&account).unwrap(), TotpStatus::Enrolled);
+
+        // The reused code was the first failure since the right one.
+        for _ in 2..MAX_TOTP_FAILURES {
+            assert!(matches!(accounts.verify_totp(account.id, "12345a"), Err(AccountError::WrongCode)));
+        }
+        assert!(matches!(accounts.verify_totp(account.id, "12345a"), Err(AccountError::TotpLocked)));
+        assert!(matches!(accounts.verify_totp(account.id, &code), Err(AccountError::TotpLocked)));
+
+        // It's still needed after the roles are taken away, until it's reset.
+        accounts.set_roles(account.id, &[]).unwrap();
+        account.roles = Vec::new();
+        assert_eq!(accounts.totp_status(&account).unwrap(), TotpStatus::Enrolled);
+        accounts.reset_totp(account.id).unwrap();
+        assert_eq!(accounts.totp_status(&account).unwrap(), TotpStatus::NotNeeded);
+    }
+
+    #[test]
+    pub fn test_link_external() {
+        let accounts = Accounts::open_in_memory().unwrap();
+        accounts.register("player_1", "hunter22").unwrap();
+
+        let identity = ExternalIdentity {
+            issuer: "https://idp.example".to_string(),
+            subject: "d0c8f1e2".to_string(),
+            username: Some("player_2".to_string()),
+        };
+        let account = accounts.link_external(&identity).unwrap();
+        assert_eq!(account.username, "player_2");
+        // The same identity always gets the same account, even if its username changes.
+        let renamed = ExternalIdentity { username: Some("player_3".to_string()), ..identity.clone() };
+        assert_eq!(accounts.link_external(&renamed).unwrap(), account);
+        assert!(matches!(accounts.login("player_2", ""), Err(AccountError::WrongCredentials)));
+
+        // Taken usernames are replaced.
+        let other = ExternalIdentity { subject: "a7b3".to_string(), username: Some("Player_1".to_string()), ..identity };
+        assert!(accounts.link_external(&other).unwrap().username.starts_with("ext_"));
+    }
+}
@@ -0,0 +1,388 @@
+//! Logs players in with JWTs from an outside identity provider, like Supabase.
+//!
+//! The provider's public keys are read from a JWKS document, either a local
+//! file or a URL that's fetched and cached. A JWT is accepted if it's signed by
+//! one of those keys, hasn't expired, and has the configured issuer and audience.
+
+use sustenet_shared as shared;
+
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use jsonwebtoken::jwk::{ AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm };
+use jsonwebtoken::{ Algorithm, DecodingKey, Validation, decode, decode_header };
+
+use shared::config::auth::Settings;
+
+/// How often an unknown `kid` can make the JWKS be loaded again. The provider
+/// may have rotated its keys, but a bad JWT shouldn't be able to hammer it.
+/// Failed loads count too, so a provider that's down isn't asked on every login.
+const MIN_RELOAD: Duration = Duration::from_secs(60);
+/// How long fetching the JWKS can take before it's given up on.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JwksSource {
+    File(String),
+    Url(String),
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    /// The JWT couldn't be parsed, or its header doesn't have a `kid`.
+    Malformed,
+    /// None of the provider's keys have the JWT's `kid`.
+    UnknownKey(String),
+    /// The key can't check signatures, or doesn't allow the JWT's algorithm.
+    UnsupportedKey(String),
+    /// The signature is wrong, or it's expired or for another issuer or audience.
+    Invalid(jsonwebtoken::errors::Error),
+    /// The JWT doesn't say who the player is.
+    MissingSubject,
+    /// The JWKS couldn't be read or fetched.
+    Load(String),
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JwtError::Malformed => write!(f, "The JWT is malformed or doesn't have a key ID."),
+            JwtError::UnknownKey(kid) => write!(f, "The JWT was signed by an unknown key {kid}."),
+            JwtError::UnsupportedKey(kid) => write!(f, "The key {kid} can't be used to check the JWT."),
+            JwtError::Invalid(e) => write!(f, "The JWT is invalid: {e}"),
+            JwtError::MissingSubject => write!(f, "The JWT doesn't have a subject."),
+            JwtError::Load(e) => write!(f, "Failed to load the JWKS: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for JwtError {}
+
+/// Who the identity provider says the player is. Accounts are linked by the
+/// issuer and subject, so the username can change on the provider's side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExternalIdentity {
+    /// The `iss` claim. Empty if the JWT doesn't have one.
+    pub issuer: String,
+    /// The `sub` claim.
+    pub subject: String,
+    /// The username claim, if the JWT has it.
+    pub username: Option<String>,
+}
+
+struct Cache {
+    keys: JwkSet,
+    loaded_at: Option<Instant>,
+    /// When the JWKS was last loaded or failed to, and why it failed.
+    tried_at: Option<Instant>,
+    error: Option<String>,
+}
+
+pub struct JwtVerifier {
+    source: JwksSource,
+    refresh: Duration,
+    issuer: Option<String>,
+    audience: Option<String>,
+    username_claim: String,
+    client: reqwest::Client,
+    cache: Mutex<Cache>,
+    /// Held while the JWKS is loaded, so logins that need it wait for one fetch
+    /// instead of each starting their own. Logins with a known key don't wait.
+    loading: tokio::sync::Mutex<()>,
+}
+
+impl JwtVerifier {
+    pub fn new(source: JwksSource) -> Self {
+        JwtVerifier {
+            source,
+            refresh: Duration::from_secs(3600),
+            issuer: None,
+            audience: None,
+            username_claim: "preferred_username".to_string(),
+            client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build().unwrap_or_default(),
+            cache: Mutex::new(Cache { keys: JwkSet { keys: Vec::new() }, loaded_at: None, tried_at: None, error: None }),
+            loading: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Returns `None` if neither `jwks_file` nor `jwks_url` is set.
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let source = match (&settings.jwks_file, &settings.jwks_url) {
+            (Some(file), _) => JwksSource::File(file.clone()),
+            (None, Some(url)) => JwksSource::Url(url.clone()),
+            (None, None) => {
+                return None;
+            }
+        };
+        let mut verifier = JwtVerifier::new(source)
+            .with_refresh(Duration::from_secs(settings.jwks_refresh))
+            .with_username_claim(settings.jwt_username_claim.as_str());
+        verifier.issuer = settings.jwt_issuer.clone();
+        verifier.audience = settings.jwt_audience.clone();
+        Some(verifier)
+    }
+
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    pub fn with_username_claim(mut self, claim: &str) -> Self {
+        self.username_claim = claim.to_string();
+        self
+    }
+
+    pub fn source(&self) -> &JwksSource {
+        &self.source
+    }
+
+    /// Checks the JWT and returns who it's for. The JWKS is loaded the first
+    /// time, again once it's older than the refresh, and again if the JWT has
+    /// a `kid` we don't know, but not more than once every `MIN_RELOAD`.
+    pub async fn verify(&self, jwt: &str) -> Result<ExternalIdentity, JwtError> {
+        let kid = decode_header(jwt)
+            .map_err(|_| JwtError::Malformed)?
+            .kid.ok_or(JwtError::Malformed)?;
+
+        if self.needs_load(&kid) {
+            let _loading = self.loading.lock().await;
+            // Another login may have loaded it while this one waited.
+            if self.needs_load(&kid) {
+                let loaded = self.load().await;
+                let mut cache = self.cache.lock().expect("The JWKS cache lock was poisoned.");
+                cache.tried_at = Some(Instant::now());
+                match loaded {
+                    Ok(keys) => {
+                        cache.keys = keys;
+                        cache.loaded_at = cache.tried_at;
+                        cache.error = None;
+                    }
+                    Err(e) => {
+                        cache.error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let jwk = {
+            let cache = self.cache.lock().expect("The JWKS cache lock was poisoned.");
+            match (cache.keys.find(&kid), &cache.error) {
+                (Some(jwk), _) => jwk.clone(),
+                (None, Some(e)) => {
+                    return Err(JwtError::Load(e.clone()));
+                }
+                (None, None) => {
+                    return Err(JwtError::UnknownKey(kid));
+                }
+            }
+        };
+        self.verify_with(&jwk, jwt)
+    }
+
+    /// Whether the JWKS should be loaded before checking a JWT with the `kid`.
+    fn needs_load(&self, kid: &str) -> bool {
+        let cache = self.cache.lock().expect("The JWKS cache lock was poisoned.");
+        if cache.tried_at.is_some_and(|tried_at| tried_at.elapsed() < MIN_RELOAD) {
+            return false;
+        }
+        let stale = cache.loaded_at.is_none_or(|loaded_at| {
+            match &self.source {
+                JwksSource::File(_) => false,
+                JwksSource::Url(_) => loaded_at.elapsed() >= self.refresh,
+            }
+        });
+        stale || cache.keys.find(kid).is_none()
+    }
+
+    /// Checks the JWT against one key from the JWKS.
+    pub fn verify_with(&self, jwk: &Jwk, jwt: &str) -> Result<ExternalIdentity, JwtError> {
+        let kid = jwk.common.key_id.clone().unwrap_or_default();
+        // Only the key's own algorithm is allowed, so a JWT can't pick a weaker one.
+        let algorithm = algorithm(jwk).ok_or(JwtError::UnsupportedKey(kid.clone()))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|_| JwtError::UnsupportedKey(kid))?;
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => {
+                validation.validate_aud = false;
+            }
+        }
+
+        let claims = decode::<serde_json::Value>(jwt, &key, &validation).map_err(JwtError::Invalid)?.claims;
+        let subject = match claims.get("sub").and_then(|sub| sub.as_str()) {
+            Some(subject) if !subject.is_empty() => subject.to_string(),
+            _ => {
+                return Err(JwtError::MissingSubject);
+            }
+        };
+        Ok(ExternalIdentity {
+            issuer: claims
+                .get("iss")
+                .and_then(|iss| iss.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            subject,
+            username: claim(&claims, &self.username_claim).map(|username| username.to_string()),
+        })
+    }
+
+    async fn load(&self) -> Result<JwkSet, String> {
+        let json = match &self.source {
+            JwksSource::File(path) => std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?,
+            JwksSource::Url(url) => {
+                let response = self.client
+                    .get(url)
+                    .send().await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("{url}: {e}"))?;
+                response.text().await.map_err(|e| format!("{url}: {e}"))?
+            }
+        };
+        serde_json::from_str(&json).map_err(|e| e.to_string())
+    }
+}
+
+/// The algorithm a key is for. It's the key's `alg` if it has one, and the
+/// usual one for its type otherwise.
+fn algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(alg) = jwk.common.key_algorithm {
+        return match alg {
+            KeyAlgorithm::HS256 => Some(Algorithm::HS256),
+            KeyAlgorithm::HS384 => Some(Algorithm::HS384),
+            KeyAlgorithm::HS512 => Some(Algorithm::HS512),
+            KeyAlgorithm::ES256 => Some(Algorithm::ES256),
+            KeyAlgorithm::ES384 => Some(Algorithm::ES384),
+            KeyAlgorithm::RS256 => Some(Algorithm::RS256),
+            KeyAlgorithm::RS384 => Some(Algorithm::RS384),
+            KeyAlgorithm::RS512 => Some(Algorithm::RS512),
+            KeyAlgorithm::PS256 => Some(Algorithm::PS256),
+            KeyAlgorithm::PS384 => Some(Algorithm::PS384),
+            KeyAlgorithm::PS512 => Some(Algorithm::PS512),
+            KeyAlgorithm::EdDSA => Some(Algorithm::EdDSA),
+            // Encryption algorithms.
+            _ => None,
+        };
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) =>
+            match params.curve {
+                EllipticCurve::P256 => Some(Algorithm::ES256),
+                EllipticCurve::P384 => Some(Algorithm::ES384),
+                _ => None,
+            }
+        AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+        AlgorithmParameters::OctetKey(_) => Some(Algorithm::HS256),
+    }
+}
+
+/// Finds a string claim. `user_metadata.username` looks inside `user_metadata`.
+fn claim<'a>(claims: &'a serde_json::Value, path: &str) -> Option<&'a str> {
+    path.split('.')
+        .try_fold(claims, |value, key| value.get(key))
+        .and_then(|value| value.as_str())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use jsonwebtoken::{ EncodingKey, Header, encode };
+    use serde_json::json;
+
+    use shared::security::base64engine::base64_encode;
+    use shared::utils::unix_time;
+
+    /// Wraps an Ed25519 seed in PKCS#8 v1 so `jsonwebtoken` can sign with it.
+    fn pkcs8(seed: &[u8; 32]) -> Vec<u8> {
+        let mut der = vec![0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+        der.extend_from_slice(seed);
+        der
+    }
+
+    fn sign(seed: &[u8; 32], kid: &str, claims: serde_json::Value) -> String {
+        let header = Header { kid: Some(kid.to_string()), ..Header::new(Algorithm::EdDSA) };
+        encode(&header, &claims, &EncodingKey::from_ed_der(&pkcs8(seed))).unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn test_verify_jwt() {
+        let seed = [7u8; 32];
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&seed).verifying_key().to_bytes();
+        let jwks = json!({
+            "keys": [
+                { "kty": "OKP", "crv": "Ed25519", "kid": "idp-1", "x": base64_encode(&public_key) },
+                { "kty": "oct", "kid": "idp-hmac", "k": base64_encode(b"a shared secret") }
+            ]
+        });
+        let path = std::env::temp_dir().join(format!("sustenet-jwks-{}.json", std::process::id()));
+        std::fs::write(&path, jwks.to_string()).unwrap();
+
+        let verifier = JwtVerifier::new(JwksSource::File(path.to_string_lossy().to_string()))
+            .with_issuer("https://idp.example")
+            .with_audience("game")
+            .with_username_claim("user_metadata.username");
+
+        let now = unix_time();
+        let claims = json!({
+            "iss": "https://idp.example",
+            "aud": "game",
+            "sub": "d0c8f1e2",
+            "exp": now + 60,
+            "user_metadata": { "username": "player_1" }
+        });
+        let identity = verifier.verify(&sign(&seed, "idp-1", claims.clone())).await.unwrap();
+        assert_eq!(identity, ExternalIdentity {
+            issuer: "https://idp.example".to_string(),
+            subject: "d0c8f1e2".to_string(),
+            username: Some("player_1".to_string()),
+        });
+
+        let mut expired = claims.clone();
+        expired["exp"] = json!(now - 3600);
+        assert!(matches!(verifier.verify(&sign(&seed, "idp-1", expired)).await, Err(JwtError::Invalid(_))));
+
+        let mut other_issuer = claims.clone();
+        other_issuer["iss"] = json!("https://evil.example");
+        assert!(matches!(verifier.verify(&sign(&seed, "idp-1", other_issuer)).await, Err(JwtError::Invalid(_))));
+
+        assert!(matches!(verifier.verify(&sign(&[8u8; 32], "idp-1", claims.clone())).await, Err(JwtError::Invalid(_))));
+        assert!(matches!(verifier.verify(&sign(&seed, "idp-2", claims.clone())).await, Err(JwtError::UnknownKey(_))));
+        // The HMAC key only checks HS256, so an EdDSA JWT can't use it.
+        assert!(matches!(verifier.verify(&sign(&seed, "idp-hmac", claims)).await, Err(JwtError::Invalid(_))));
+        assert!(matches!(verifier.verify("not a jwt").await, Err(JwtError::Malformed)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_failed_load_waits() {
+        let path = std::env::temp_dir().join(format!("sustenet-jwks-missing-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let verifier = JwtVerifier::new(JwksSource::File(path.to_string_lossy().to_string()));
+        let jwt = sign(&[7u8; 32], "idp-1", json!({ "sub": "d0c8f1e2", "exp": unix_time() + 60 }));
+        assert!(matches!(verifier.verify(&jwt).await, Err(JwtError::Load(_))));
+
+        // The JWKS isn't loaded again until `MIN_RELOAD` is up, even though it's there now.
+        std::fs::write(&path, json!({ "keys": [] }).to_string()).unwrap();
+        assert!(!verifier.needs_load("idp-1"));
+        assert!(matches!(verifier.verify(&jwt).await, Err(JwtError::Load(_))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,575 @@
+use sustenet_shared as shared;
+
+use std::net::SocketAddr;
+use std::sync::{ Arc, LazyLock, OnceLock };
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt, BufReader };
+use tokio::net::tcp::WriteHalf;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::Semaphore;
+use tokio::time::{ Duration, timeout };
+
+use shared::bans;
+use shared::config::auth::{ Settings, read };
+use shared::logging::{ LogType, Logger };
+use shared::network::{ LinkReader, read_string };
+use shared::packets::Status;
+use shared::packets::auth::{ FromClient, ToClient };
+use shared::revocations::{ self, Revocation };
+use shared::security::aes::{ KeyStore, set_key_store };
+use shared::security::exchange::{ Exchange, Identity, PUBLIC_KEY_LEN, transcript };
+use shared::security::session::{ Opener, SecureReader, Sealer };
+use shared::security::token::{ Token, key_id };
+use shared::utils::constants::DEFAULT_IP;
+
+use accounts::{ Account, AccountError, Accounts, PASSWORD_LEN, REFRESH_TOKEN_LEN, TotpStatus };
+use external::JwtVerifier;
+use limits::Limits;
+
+pub mod accounts;
+pub mod external;
+pub mod limits;
+pub mod totp;
+
+pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::Auth));
+
+/// Signs each session so clients know they're giving their password to us.
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+
+/// How long a client has to start a session after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection can sit without sending anything.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub async fn start_with_config() {
+    start(read()).await;
+}
+
+pub async fn start(settings: Settings) {
+    let settings = Arc::new(settings);
+    let mut store = KeyStore::new(settings.keys_dir.as_str());
+    if let Some(passphrase) = &settings.key_passphrase {
+        store = match store.with_passphrase(passphrase) {
+            Ok(store) => store,
+            Err(e) => {
+                LOGGER.error(format!("Failed to open the key store: {e}").as_str());
+                panic!("Failed to open the key store: {e:?}");
+            }
+        };
+    }
+    set_key_store(store);
+
+    if let Err(e) = bans::set_ban_file(settings.bans_file.as_str()) {
+        LOGGER.error(format!("Failed to load the bans at '{}': {e}", settings.bans_file).as_str());
+        panic!("Failed to load the bans at '{}': {e}", settings.bans_file);
+    }
+
+    if let Err(e) = revocations::set_revocation_file(settings.revocations_file.as_str()) {
+        LOGGER.error(format!("Failed to load the revocations at '{}': {e}", settings.revocations_file).as_str());
+        panic!("Failed to load the revocations at '{}': {e}", settings.revocations_file);
+    }
+    // Picks up revocations from `sustenet-bans`.
+    revocations::watch(Duration::from_secs(1));
+
+    match Identity::load_or_generate(settings.key_name.as_str()) {
+        Ok(identity) => {
+            LOGGER.info(
+                format!(
+                    "Tokens are signed with the key {}. Give clusters 'identity/{}.pub' so they can check them.",
+                    key_id(&identity.public_key()),
+                    settings.key_name
+                ).as_str()
+            );
+            let _ = IDENTITY.set(identity);
+        }
+        Err(e) => {
+            LOGGER.error(format!("Failed to load the identity {}: {e}", settings.key_name).as_str());
+            panic!("Failed to load the identity {}: {e:?}", settings.key_name);
+        }
+    }
+
+    let accounts = match Accounts::open(settings.database.as_str()) {
+        Ok(accounts) => Arc::new(accounts),
+        Err(e) => {
+            LOGGER.error(format!("Failed to open the database at {}: {e}", settings.database).as_str());
+            panic!("Failed to open the database at {}: {e:?}", settings.database);
+        }
+    };
+
+    let verifier = JwtVerifier::from_settings(&settings).map(Arc::new);
+    if let Some(verifier) = &verifier {
+        LOGGER.info(format!("Players can log in with JWTs checked against {:?}.", verifier.source()).as_str());
+    }
+
+    // 0 means there's no limit.
+    let slots = Arc::new(
+        Semaphore::new(match settings.max_connections {
+            0 => Semaphore::MAX_PERMITS,
+            max => max as usize,
+        })
+    );
+
+    let tcp_listener = TcpListener::bind(
+        format!("{DEFAULT_IP}:{}", settings.port)
+    ).await.expect("Failed to bind to the specified port.");
+    LOGGER.success(format!("{} is listening on port {}.", settings.server_name, settings.port).as_str());
+
+    loop {
+        let (stream, addr) = match tcp_listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                LOGGER.error(format!("Failed to accept a connection: {e}").as_str());
+                continue;
+            }
+        };
+        if let Some(ban) = bans::check_ip(addr.ip()) {
+            LOGGER.warning(format!("Refusing {addr}. It's banned. {}", ban.reason).as_str());
+            tokio::spawn(bans::refuse(stream, ban, ToClient::Banned as u8));
+            continue;
+        }
+        let Ok(slot) = Arc::clone(&slots).try_acquire_owned() else {
+            LOGGER.warning(format!("Refusing {addr}. The server is full.").as_str());
+            continue;
+        };
+
+        let accounts = Arc::clone(&accounts);
+        let settings = Arc::clone(&settings);
+        let verifier = verifier.clone();
+        tokio::spawn(async move {
+            handle_client(stream, addr, accounts, verifier, settings).await;
+            drop(slot);
+        });
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    accounts: Arc<Accounts>,
+    verifier: Option<Arc<JwtVerifier>>,
+    settings: Arc<Settings>
+) {
+    let max_failed_logins = settings.max_failed_logins;
+    let limits = Limits::from(settings.as_ref());
+    let ip = addr.ip();
+    let addr = addr.to_string();
+    let (reader, mut writer) = stream.split();
+    let mut reader = SecureReader::new(BufReader::new(reader));
+
+    let mut sealer = match timeout(HANDSHAKE_TIMEOUT, start_session(&mut reader, &mut writer)).await {
+        Ok(Some((sealer, opener))) => {
+            reader.set_opener(opener);
+            sealer
+        }
+        _ => {
+            LOGGER.warning(format!("{addr} didn't start a session.").as_str());
+            let _ = writer.shutdown().await;
+            return;
+        }
+    };
+
+    let mut failed_logins = 0;
+    // An account that logged in but still has to send a one-time code, and the
+    // password it's changing to if it sent `ChangePassword`.
+    let mut pending: Option<(Account, Option<String>)> = None;
+    loop {
+        let command = match timeout(IDLE_TIMEOUT, reader.read_u8()).await {
+            Ok(Ok(command)) => command,
+            _ => break,
+        };
+        if command != FromClient::RequestKey as u8 && limits::ip_throttled(ip, limits) {
+            LOGGER.warning(format!("Disconnecting {addr}. Its IP failed to log in too many times.").as_str());
+            let _ = send_status(&mut writer, &mut sealer, Status::TooManyAttempts, None).await;
+            break;
+        }
+
+        // The username a password was checked for, so its failures are counted against it.
+        let mut username_tried = None;
+        // Set by `ChangePassword`. The password is only changed once the account passed its one-time code.
+        let mut new_password = None;
+        // Whether this account passed its one-time code, either just now or for the
+        // login its refresh token came from.
+        let mut totp_verified = false;
+        let result = match command {
+            x if x == FromClient::RequestKey as u8 => {
+                if let Err(e) = send_key(&mut writer, &mut sealer).await {
+                    LOGGER.error(format!("Failed to send our key to {addr}: {e}").as_str());
+                    break;
+                }
+                continue;
+            }
+            x if x == FromClient::Register as u8 || x == FromClient::Login as u8 => {
+                let (Ok(username), Ok(password)) = (read_string(&mut reader).await, read_string(&mut reader).await) else {
+                    break;
+                };
+                let accounts = Arc::clone(&accounts);
+                let register = x == FromClient::Register as u8;
+                if !register {
+                    username_tried = Some(username.clone());
+                }
+                if !register && limits::account_throttled(&username, limits) {
+                    Err(AccountError::Throttled)
+                } else {
+                    // Hashing takes long enough that it shouldn't hold up the other connections.
+                    let joined = tokio::task::spawn_blocking(move || {
+                        match register {
+                            true => accounts.register(&username, &password),
+                            false => accounts.login(&username, &password),
+                        }
+                    }).await;
+                    match joined {
+                        Ok(result) => result,
+                        Err(e) => {
+                            LOGGER.error(format!("Failed to authenticate {addr}: {e}").as_str());
+                            if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                }
+            }
+            x if x == FromClient::LoginExternal as u8 => {
+                let Ok(jwt) = read_jwt(&mut reader).await else {
+                    break;
+                };
+                let Some(verifier) = &verifier else {
+                    // Nothing to check it against.
+                    if send_status(&mut writer, &mut sealer, Status::NotFound, None).await.is_err() {
+                        break;
+                    }
+                    continue;
+                };
+                match verifier.verify(&jwt).await {
+                    Ok(identity) => {
+                        let accounts = Arc::clone(&accounts);
+                        match tokio::task::spawn_blocking(move || accounts.link_external(&identity)).await {
+                            Ok(result) => result,
+                            Err(e) => {
+                                LOGGER.error(format!("Failed to authenticate {addr}: {e}").as_str());
+                                if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    Err(e) => Err(AccountError::External(e)),
+                }
+            }
+            x if x == FromClient::Refresh as u8 => {
+                let mut secret = [0u8; REFRESH_TOKEN_LEN];
+                if reader.read_exact(&mut secret).await.is_err() {
+                    break;
+                }
+                let accounts = Arc::clone(&accounts);
+                match tokio::task::spawn_blocking(move || accounts.refresh(&secret)).await {
+                    // Revocations from `sustenet-bans` don't touch the database, so they're checked here.
+                    Ok(Ok((account, issued_at, _))) if revocations::revoked_at(account.id).is_some_and(|revoked_at| issued_at <= revoked_at) =>
+                        Err(AccountError::WrongCredentials),
+                    Ok(result) => result.map(|(account, _, verified)| {
+                        totp_verified = verified;
+                        account
+                    }),
+                    Err(e) => {
+                        LOGGER.error(format!("Failed to refresh {addr}'s token: {e}").as_str());
+                        if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+            x if x == FromClient::ChangePassword as u8 => {
+                let (Ok(username), Ok(old), Ok(new)) = (
+                    read_string(&mut reader).await,
+                    read_string(&mut reader).await,
+                    read_string(&mut reader).await,
+                ) else {
+                    break;
+                };
+                username_tried = Some(username.clone());
+                if !PASSWORD_LEN.contains(&new.len()) {
+                    Err(AccountError::InvalidPassword)
+                } else if limits::account_throttled(&username, limits) {
+                    Err(AccountError::Throttled)
+                } else {
+                    new_password = Some(new);
+                    let accounts = Arc::clone(&accounts);
+                    match tokio::task::spawn_blocking(move || accounts.login(&username, &old)).await {
+                        Ok(result) => result,
+                        Err(e) => {
+                            LOGGER.error(format!("Failed to authenticate {addr}: {e}").as_str());
+                            if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                }
+            }
+            x if x == FromClient::SendTotp as u8 => {
+                let Ok(code) = read_string(&mut reader).await else {
+                    break;
+                };
+                let Some(id) = pending.as_ref().map(|(account, _)| account.id) else {
+                    LOGGER.warning(format!("{addr} sent a one-time code before logging in.").as_str());
+                    break;
+                };
+                let accounts = Arc::clone(&accounts);
+                match tokio::task::spawn_blocking(move || accounts.verify_totp(id, &code)).await {
+                    Ok(Ok(())) => {
+                        let (account, password) = pending.take().expect("The account is pending.");
+                        new_password = password;
+                        totp_verified = true;
+                        Ok(account)
+                    }
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => {
+                        LOGGER.error(format!("Failed to check {addr}'s one-time code: {e}").as_str());
+                        if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                LOGGER.warning(format!("{addr} sent an unknown command: {command}").as_str());
+                break;
+            }
+        };
+
+        if let Ok(account) = &result && let Some(ban) = bans::check_account(account.id) {
+            LOGGER.warning(format!("{addr} logged in as banned account #{}. {}", account.id, ban.reason).as_str());
+            if let Ok(frame) = sealer.seal(&ban.to_packet(ToClient::Banned as u8)) {
+                let _ = writer.write_all(&frame).await;
+            }
+            break;
+        }
+
+        // Roles above player need a one-time code before they get a token. Refresh
+        // tokens remember whether their login passed one, so a token from before the
+        // account needed a code has to pass it too.
+        let result = match result {
+            Ok(account) if !totp_verified => {
+                let status = {
+                    let accounts = Arc::clone(&accounts);
+                    let account = account.clone();
+                    tokio::task::spawn_blocking(move || accounts.totp_status(&account)).await
+                };
+                let status = match status {
+                    Ok(status) => status,
+                    Err(e) => {
+                        LOGGER.error(format!("Failed to check whether {addr} needs a one-time code: {e}").as_str());
+                        if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                match status {
+                    Ok(TotpStatus::NotNeeded) => Ok(account),
+                    Ok(status) => {
+                        let url = match status {
+                            TotpStatus::Enrolling(secret) => totp::url(&secret, &settings.totp_issuer, &account.username),
+                            _ => Some(String::new()),
+                        };
+                        let Some(url) = url else {
+                            LOGGER.error(format!("Failed to make a TOTP URL for {addr}. The issuer can't have a colon.").as_str());
+                            if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        };
+                        LOGGER.info(format!("{addr} needs a one-time code for {}#{}.", account.username, account.id).as_str());
+                        pending = Some((account, new_password));
+                        if let Err(e) = send_totp_required(&mut writer, &mut sealer, &url).await {
+                            LOGGER.error(format!("Failed to answer {addr}: {e}").as_str());
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        };
+
+        let result = match (result, new_password) {
+            (Ok(account), Some(password)) => {
+                let id = account.id;
+                let accounts = Arc::clone(&accounts);
+                match tokio::task::spawn_blocking(move || accounts.set_password(id, &password)).await {
+                    Ok(Ok(())) => {
+                        LOGGER.info(format!("{addr} changed {}#{}'s password.", account.username, account.id).as_str());
+                        if let Err(e) = revocations::revoke(Revocation::now(account.id, settings.token_ttl, settings.refresh_ttl)) {
+                            LOGGER.error(format!("Failed to revoke {}#{}'s tokens: {e}", account.username, account.id).as_str());
+                        }
+                        Ok(account)
+                    }
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => {
+                        LOGGER.error(format!("Failed to change {addr}'s password: {e}").as_str());
+                        if send_status(&mut writer, &mut sealer, Status::ServerError, None).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+            (result, _) => result,
+        };
+
+        let status = match &result {
+            Ok(account) => {
+                LOGGER.info(format!("{addr} authenticated as {}#{}.", account.username, account.id).as_str());
+                if let Some(username) = &username_tried {
+                    limits::record_success(username);
+                }
+                Status::Ok
+            }
+            Err(e) if e.status() == Status::Unauthorized => {
+                if let AccountError::External(e) = e {
+                    LOGGER.warning(format!("Refused {addr}'s JWT. {e}").as_str());
+                }
+                limits::record_failure(ip, username_tried.as_deref(), limits);
+                failed_logins += 1;
+                match failed_logins >= max_failed_logins && max_failed_logins > 0 {
+                    true => Status::TooManyAttempts,
+                    false => Status::Unauthorized,
+                }
+            }
+            Err(e) if e.status() == Status::ServerError => {
+                LOGGER.error(format!("Failed to authenticate {addr}: {e}").as_str());
+                e.status()
+            }
+            Err(e) => e.status(),
+        };
+
+        let signed = match result {
+            Ok(account) => issue(account, totp_verified, &accounts, &settings, &addr),
+            Err(_) => None,
+        };
+        let status = match signed {
+            None if status == Status::Ok => Status::ServerError,
+            _ => status,
+        };
+
+        if let Err(e) = send_status(&mut writer, &mut sealer, status, signed.as_ref()).await {
+            LOGGER.error(format!("Failed to answer {addr}: {e}").as_str());
+            break;
+        }
+        if status == Status::TooManyAttempts {
+            LOGGER.warning(format!("Disconnecting {addr}. It failed to log in too many times.").as_str());
+            break;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+/// What's sent to a client that authenticated.
+struct Issued {
+    id: u64,
+    username: String,
+    token: Vec<u8>,
+    refresh_token: [u8; REFRESH_TOKEN_LEN],
+}
+
+/// Signs a token and makes a refresh token for the account.
+fn issue(account: Account, totp_verified: bool, accounts: &Accounts, settings: &Settings, addr: &str) -> Option<Issued> {
+    let mut token = Token::new(account.id, account.username.clone(), account.roles, settings.token_ttl);
+    // A token from the same second as a revocation would be covered by it.
+    if let Some(revoked_at) = revocations::revoked_at(account.id) && token.issued_at <= revoked_at {
+        let shift = revoked_at + 1 - token.issued_at;
+        token.issued_at += shift;
+        token.expires_at += shift;
+    }
+    let token = match IDENTITY.get().map(|identity| token.sign(identity)) {
+        Some(Ok(token)) => token,
+        _ => {
+            LOGGER.error(format!("Failed to sign a token for {addr}.").as_str());
+            return None;
+        }
+    };
+    let refresh_token = match accounts.issue_refresh_token(account.id, settings.refresh_ttl, totp_verified) {
+        Ok(refresh_token) => refresh_token,
+        Err(e) => {
+            LOGGER.error(format!("Failed to make a refresh token for {addr}: {e}").as_str());
+            return None;
+        }
+    };
+    Some(Issued { id: account.id, username: account.username, token, refresh_token })
+}
+
+/// Answers the client's `StartSession` with our half of the exchange, signed
+/// by our identity. Returns `None` if the client sent anything else.
+async fn start_session(reader: &mut LinkReader<'_>, writer: &mut WriteHalf<'_>) -> Option<(Sealer, Opener)> {
+    match reader.read_u8().await {
+        Ok(command) if command == FromClient::StartSession as u8 => (),
+        _ => {
+            return None;
+        }
+    }
+    let mut client_public = [0u8; PUBLIC_KEY_LEN];
+    reader.read_exact(&mut client_public).await.ok()?;
+
+    let identity = IDENTITY.get()?;
+    let exchange = Exchange::new();
+    let server_public = exchange.public_key();
+    let mut data = vec![ToClient::SessionStarted as u8];
+    data.extend_from_slice(&identity.version().to_be_bytes());
+    data.extend_from_slice(&server_public);
+    data.extend_from_slice(&identity.sign(&transcript(identity.version(), &client_public, &server_public)));
+    writer.write_all(&data).await.ok()?;
+    writer.flush().await.ok()?;
+
+    exchange.finish(&client_public, false)
+}
+
+async fn send_key(writer: &mut WriteHalf<'_>, sealer: &mut Sealer) -> std::io::Result<()> {
+    let identity = IDENTITY.get().ok_or(std::io::Error::other("The identity isn't loaded."))?;
+    let mut data = vec![ToClient::SendPubKey as u8];
+    data.extend_from_slice(&identity.version().to_be_bytes());
+    data.extend_from_slice(&identity.public_key());
+    writer.write_all(&sealer.seal(&data).map_err(std::io::Error::other)?).await?;
+    writer.flush().await
+}
+
+/// Asks for a one-time code. The URL is empty if the account already set up its authenticator.
+async fn send_totp_required(writer: &mut WriteHalf<'_>, sealer: &mut Sealer, url: &str) -> std::io::Result<()> {
+    let mut data = vec![ToClient::Authenticate as u8, Status::TotpRequired as u8];
+    data.extend_from_slice(&(url.len() as u16).to_be_bytes());
+    data.extend_from_slice(url.as_bytes());
+    writer.write_all(&sealer.seal(&data).map_err(std::io::Error::other)?).await?;
+    writer.flush().await
+}
+
+/// Reads the JWT sent with `LoginExternal`.
+async fn read_jwt(reader: &mut LinkReader<'_>) -> std::io::Result<String> {
+    let len = reader.read_u16().await? as usize;
+    let mut jwt = vec![0u8; len];
+    reader.read_exact(&mut jwt).await?;
+    String::from_utf8(jwt).map_err(std::io::Error::other)
+}
+
+/// Sends the status, followed by the account's ID, username, token, and refresh token if it's `Ok`.
+async fn send_status(
+    writer: &mut WriteHalf<'_>,
+    sealer: &mut Sealer,
+    status: Status,
+    issued: Option<&Issued>
+) -> std::io::Result<()> {
+    let mut data = vec![ToClient::Authenticate as u8, status as u8];
+    if let Some(issued) = issued {
+        data.extend_from_slice(&issued.id.to_be_bytes());
+        data.push(issued.username.len() as u8);
+        data.extend_from_slice(issued.username.as_bytes());
+        data.extend_from_slice(&(issued.token.len() as u16).to_be_bytes());
+        data.extend_from_slice(&issued.token);
+        data.extend_from_slice(&issued.refresh_token);
+    }
+    writer.write_all(&sealer.seal(&data).map_err(std::io::Error::other)?).await?;
+    writer.flush().await
+}
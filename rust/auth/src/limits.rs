@@ -0,0 +1,124 @@
+use sustenet_shared as shared;
+
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{ Duration, Instant };
+
+use dashmap::DashMap;
+
+use shared::config::auth::Settings;
+
+/// How many failed logins an IP or account gets before it has to wait. They're
+/// counted across connections, so reconnecting doesn't reset them. See the `[auth]` config.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub failures_per_ip: u32,
+    pub failures_per_account: u32,
+    pub window: Duration,
+}
+
+impl From<&Settings> for Limits {
+    fn from(settings: &Settings) -> Self {
+        Limits {
+            failures_per_ip: settings.login_failures_per_ip,
+            failures_per_account: settings.login_failures_per_account,
+            window: Duration::from_secs(settings.login_failure_window),
+        }
+    }
+}
+
+/// Failed logins in the current window.
+struct Failures {
+    window_start: Instant,
+    count: u32,
+}
+
+static IP_FAILURES: LazyLock<DashMap<IpAddr, Failures>> = LazyLock::new(DashMap::new);
+/// Keyed by the lowercase username, whether or not the account exists.
+static ACCOUNT_FAILURES: LazyLock<DashMap<String, Failures>> = LazyLock::new(DashMap::new);
+
+/// Whether the IP has failed too many times to try again yet.
+pub(crate) fn ip_throttled(ip: IpAddr, limits: Limits) -> bool {
+    prune(limits.window);
+    IP_FAILURES.get(&ip).is_some_and(|failures| over(&failures, limits.failures_per_ip))
+}
+
+/// Whether the username has failed too many times to try again yet, from any IP.
+pub(crate) fn account_throttled(username: &str, limits: Limits) -> bool {
+    prune(limits.window);
+    ACCOUNT_FAILURES.get(&username.to_lowercase()).is_some_and(|failures| over(&failures, limits.failures_per_account))
+}
+
+/// Records a failed login from the IP, and against the username if there was one.
+pub(crate) fn record_failure(ip: IpAddr, username: Option<&str>, limits: Limits) {
+    count(&mut IP_FAILURES.entry(ip).or_insert_with(Failures::new), limits.window);
+    if let Some(username) = username {
+        count(&mut ACCOUNT_FAILURES.entry(username.to_lowercase()).or_insert_with(Failures::new), limits.window);
+    }
+}
+
+/// Forgets the failures against an account that logged in. The IP's are kept,
+/// so logging in to one account doesn't reset the guesses against others.
+pub(crate) fn record_success(username: &str) {
+    ACCOUNT_FAILURES.remove(&username.to_lowercase());
+}
+
+impl Failures {
+    fn new() -> Self {
+        Failures { window_start: Instant::now(), count: 0 }
+    }
+}
+
+fn count(failures: &mut Failures, window: Duration) {
+    if failures.window_start.elapsed() >= window {
+        failures.window_start = Instant::now();
+        failures.count = 0;
+    }
+    failures.count = failures.count.saturating_add(1);
+}
+
+/// A `limit` of 0 means there's no limit.
+fn over(failures: &Failures, limit: u32) -> bool {
+    limit > 0 && failures.count >= limit
+}
+
+/// Drops windows that are over so made-up usernames don't pile up.
+fn prune(window: Duration) {
+    IP_FAILURES.retain(|_, failures| failures.window_start.elapsed() < window);
+    ACCOUNT_FAILURES.retain(|_, failures| failures.window_start.elapsed() < window);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    pub fn test_failures_outlive_connections() {
+        let limits = Limits { failures_per_ip: 3, failures_per_account: 2, window: Duration::from_secs(60) };
+
+        // Guesses at one account from different IPs add up.
+        let first = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 44));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 45));
+        record_failure(first, Some("limits_testrunner"), limits);
+        assert!(!account_throttled("Limits_Testrunner", limits));
+        record_failure(second, Some("LIMITS_TESTRUNNER"), limits);
+        assert!(account_throttled("limits_testrunner", limits));
+        assert!(!ip_throttled(first, limits));
+        record_success("limits_testrunner");
+        assert!(!account_throttled("limits_testrunner", limits));
+
+        // So do guesses at different accounts from one IP.
+        record_failure(first, Some("limits_testrunner2"), limits);
+        assert!(!ip_throttled(first, limits));
+        record_failure(first, None, limits);
+        assert!(ip_throttled(first, limits));
+        assert!(!ip_throttled(second, limits));
+        record_success("limits_testrunner2");
+        assert!(ip_throttled(first, limits));
+
+        let unlimited = Limits { failures_per_ip: 0, failures_per_account: 0, ..limits };
+        assert!(!ip_throttled(first, unlimited));
+    }
+}
@@ -1,3 +1,21 @@
-fn main() {
-    println!("Hello, world!");
+use sustenet_shared as shared;
+
+use sustenet_auth::{ LOGGER, start_with_config };
+
+use tokio::select;
+
+use shared::utils;
+
+#[tokio::main]
+async fn main() {
+    let mut shutdown_rx = utils::shutdown_channel().expect("Error creating shutdown channel.");
+
+    select! {
+        _ = shutdown_rx.recv() => {
+            LOGGER.warning("Shutting down...");
+        }
+        _ = start_with_config() => {}
+    }
+
+    LOGGER.success("The Auth Server has been shut down.");
 }
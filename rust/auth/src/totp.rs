@@ -0,0 +1,59 @@
+//! Time-based one-time passwords (RFC 6238). Accounts with a role above player
+//! need one to log in.
+//!
+//! Codes are 6 digits from HMAC-SHA1 with 30-second steps, which is what
+//! authenticator apps expect.
+
+use totp_rs::{ Algorithm, TOTP };
+
+/// How long a secret is, in bytes. RFC 4226 recommends 160 bits.
+pub const SECRET_LEN: usize = 20;
+/// How many seconds each code is for.
+pub const STEP: u64 = 30;
+/// How many steps before or after now a code is still accepted, for clocks that are a little off.
+pub const SKEW: u64 = 1;
+const DIGITS: usize = 6;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    getrandom::fill(&mut secret).expect("Failed to generate a TOTP secret.");
+    secret
+}
+
+/// The `otpauth://` URL that authenticator apps read from a QR code. Returns
+/// `None` if the issuer has a colon, since it separates the issuer from the username.
+pub fn url(secret: &[u8], issuer: &str, username: &str) -> Option<String> {
+    TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP, secret.to_vec(), Some(issuer.to_string()), username.to_string())
+        .ok()
+        .map(|totp| totp.get_url())
+}
+
+/// Checks the code at `now`. Returns the step it was for if it's right and
+/// newer than `last_step`, so a code can't be used twice.
+pub fn check(secret: &[u8], code: &str, now: u64, last_step: Option<u64>) -> Option<u64> {
+    let totp = TOTP::new_unchecked(Algorithm::SHA1, DIGITS, 0, STEP, secret.to_vec(), None, String::new());
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+        .find(|step| totp.check(code, step * STEP))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_check() {
+        // The SHA1 test vector from RFC 6238.
+        let secret = b"12345678901234567890";
+        assert_eq!(check(secret, "287082", 59, None), Some(1));
+        assert_eq!(check(secret, "287082", 89, None), Some(1));
+        assert_eq!(check(secret, "287082", 119, None), None);
+        assert_eq!(check(secret, "287082", 59, Some(1)), None);
+        assert_eq!(check(secret, "000000", 59, None), None);
+
+        let url = url(secret, "Sustenet", "admin").unwrap();
+        assert_eq!(url, "otpauth://totp/Sustenet:admin?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Sustenet");
+        assert!(super::url(secret, "Bad:Issuer", "admin").is_none());
+    }
+}
@@ -0,0 +1,247 @@
+use sustenet_shared as shared;
+
+use std::net::IpAddr;
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt, BufReader };
+use tokio::net::TcpStream;
+
+use shared::bans::read_ban;
+use shared::network::read_string;
+use shared::packets::Status;
+use shared::packets::auth::{ FromClient, ToClient };
+use shared::security::exchange::Exchange;
+use shared::security::session::SecureReader;
+
+use crate::{ Handshake, TOKEN, start_session };
+
+/// How long a refresh token is, in bytes.
+pub const REFRESH_TOKEN_LEN: usize = 32;
+
+const AUTH_HANDSHAKE: Handshake = Handshake {
+    start_session: FromClient::StartSession as u8,
+    session_started: ToClient::SessionStarted as u8,
+    request_key: FromClient::RequestKey as u8,
+    send_pub_key: ToClient::SendPubKey as u8,
+    version_of_key: None,
+    banned: ToClient::Banned as u8,
+    refused: None,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub id: u64,
+    pub username: String,
+    /// Signed by the auth server. Clusters check it to know who the player is.
+    pub token: Vec<u8>,
+    /// Gets a new token with `refresh` when this one is about to expire. It only works once.
+    pub refresh_token: [u8; REFRESH_TOKEN_LEN],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// Couldn't connect, or the auth server couldn't be trusted.
+    Connection,
+    /// The username, password, or JWT is too long to send.
+    TooLong,
+    /// The auth server refused. It's never `Status::Ok` or `Status::TotpRequired`.
+    Refused(Status),
+    /// The account has a role above player, so it needs a one-time code. Log in
+    /// again with `login_with_totp`. `enroll_url` is set if the account hasn't
+    /// set up an authenticator yet. Show it as a QR code, then log in with the
+    /// code the authenticator shows to finish setting it up.
+    TotpRequired {
+        enroll_url: Option<String>,
+    },
+    /// The account is banned. `expires_at` is `None` if it never expires.
+    Banned {
+        expires_at: Option<u64>,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Connection => write!(f, "Failed to start a session with the auth server."),
+            AuthError::TooLong => write!(f, "The username, password, or JWT is too long."),
+            AuthError::Refused(Status::BadRequest) => write!(f, "The username or password isn't allowed."),
+            AuthError::Refused(Status::Unauthorized) => write!(f, "The username, password, or refresh token is wrong."),
+            AuthError::Refused(Status::TooManyAttempts) => write!(f, "Too many failed attempts."),
+            AuthError::TotpRequired { enroll_url: Some(url) } => write!(f, "Set up an authenticator with {url} and log in with its code."),
+            AuthError::TotpRequired { enroll_url: None } => write!(f, "A one-time code is required."),
+            AuthError::Refused(Status::Conflict) => write!(f, "The username is taken."),
+            AuthError::Refused(Status::NotFound) => write!(f, "The auth server doesn't accept JWTs."),
+            AuthError::Refused(status) => write!(f, "The auth server failed with {status:?}."),
+            AuthError::Banned { expires_at: Some(expires_at), reason } => write!(f, "Banned until {expires_at}. {reason}"),
+            AuthError::Banned { expires_at: None, reason } => write!(f, "Banned. {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Creates an account on the auth server and keeps its token in `TOKEN`. The
+/// password is only sent once the server has proven it has its key.
+pub async fn register(ip: IpAddr, port: u16, username: &str, password: &str) -> Result<Account, AuthError> {
+    authenticate(ip, port, credentials(FromClient::Register, username, password)?).await
+}
+
+/// Logs in and keeps the account's token in `TOKEN` so it's sent to clusters.
+pub async fn login(ip: IpAddr, port: u16, username: &str, password: &str) -> Result<Account, AuthError> {
+    authenticate(ip, port, credentials(FromClient::Login, username, password)?).await
+}
+
+/// Logs in with the code from the account's authenticator app. Needed for
+/// accounts with a role above player. See `AuthError::TotpRequired`.
+pub async fn login_with_totp(ip: IpAddr, port: u16, username: &str, password: &str, code: &str) -> Result<Account, AuthError> {
+    authenticate_with(ip, port, credentials(FromClient::Login, username, password)?, Some(code)).await
+}
+
+/// Logs in with a JWT from the identity provider the auth server trusts, like
+/// Supabase. The account is made the first time.
+pub async fn login_external(ip: IpAddr, port: u16, jwt: &str) -> Result<Account, AuthError> {
+    authenticate(ip, port, external(jwt)?).await
+}
+
+/// Like `login_external`, with the code from the account's authenticator app.
+pub async fn login_external_with_totp(ip: IpAddr, port: u16, jwt: &str, code: &str) -> Result<Account, AuthError> {
+    authenticate_with(ip, port, external(jwt)?, Some(code)).await
+}
+
+fn external(jwt: &str) -> Result<Vec<u8>, AuthError> {
+    if jwt.len() > (u16::MAX as usize) {
+        return Err(AuthError::TooLong);
+    }
+    let mut data = vec![FromClient::LoginExternal as u8];
+    data.extend_from_slice(&(jwt.len() as u16).to_be_bytes());
+    data.extend_from_slice(jwt.as_bytes());
+    Ok(data)
+}
+
+/// Gets a new token with the refresh token from the last login or refresh, and
+/// keeps it in `TOKEN`. Send it to the cluster with `send_token`.
+pub async fn refresh(ip: IpAddr, port: u16, refresh_token: &[u8; REFRESH_TOKEN_LEN]) -> Result<Account, AuthError> {
+    let mut data = vec![FromClient::Refresh as u8];
+    data.extend_from_slice(refresh_token);
+    authenticate(ip, port, data).await
+}
+
+/// Like `refresh`, with the code from the account's authenticator app. Needed
+/// when the refresh token came from a login that didn't pass one. A refresh
+/// token is used up even when the answer is `AuthError::TotpRequired`.
+pub async fn refresh_with_totp(
+    ip: IpAddr,
+    port: u16,
+    refresh_token: &[u8; REFRESH_TOKEN_LEN],
+    code: &str
+) -> Result<Account, AuthError> {
+    let mut data = vec![FromClient::Refresh as u8];
+    data.extend_from_slice(refresh_token);
+    authenticate_with(ip, port, data, Some(code)).await
+}
+
+/// Changes the password and logs in with the new one. The account's other
+/// tokens are revoked, so its other sessions are disconnected. Accounts with a
+/// role above player need the code from their authenticator app.
+pub async fn change_password(
+    ip: IpAddr,
+    port: u16,
+    username: &str,
+    old: &str,
+    new: &str,
+    code: Option<&str>
+) -> Result<Account, AuthError> {
+    let mut data = credentials(FromClient::ChangePassword, username, old)?;
+    if new.len() > (u8::MAX as usize) {
+        return Err(AuthError::TooLong);
+    }
+    data.push(new.len() as u8);
+    data.extend_from_slice(new.as_bytes());
+    authenticate_with(ip, port, data, code).await
+}
+
+fn credentials(command: FromClient, username: &str, password: &str) -> Result<Vec<u8>, AuthError> {
+    if username.len() > (u8::MAX as usize) || password.len() > (u8::MAX as usize) {
+        return Err(AuthError::TooLong);
+    }
+    let mut data = vec![command as u8];
+    data.push(username.len() as u8);
+    data.extend_from_slice(username.as_bytes());
+    data.push(password.len() as u8);
+    data.extend_from_slice(password.as_bytes());
+    Ok(data)
+}
+
+async fn authenticate(ip: IpAddr, port: u16, data: Vec<u8>) -> Result<Account, AuthError> {
+    authenticate_with(ip, port, data, None).await
+}
+
+/// Sends the command, and the one-time code if the server asks for one.
+async fn authenticate_with(ip: IpAddr, port: u16, data: Vec<u8>, code: Option<&str>) -> Result<Account, AuthError> {
+    if code.is_some_and(|code| code.len() > (u8::MAX as usize)) {
+        return Err(AuthError::TooLong);
+    }
+    let mut stream = TcpStream::connect((ip, port)).await.map_err(|_| AuthError::Connection)?;
+    let (reader, mut writer) = stream.split();
+    let mut reader = SecureReader::new(BufReader::new(reader));
+    let mut sealer = start_session(&mut reader, &mut writer, ip, port, &AUTH_HANDSHAKE, Exchange::new()).await.ok_or(
+        AuthError::Connection
+    )?;
+
+    let frame = sealer.seal(&data).map_err(|_| AuthError::TooLong)?;
+    writer.write_all(&frame).await.map_err(|_| AuthError::Connection)?;
+    writer.flush().await.map_err(|_| AuthError::Connection)?;
+
+    let mut result = read_account(&mut reader).await;
+    if let (Err(AuthError::TotpRequired { .. }), Some(code)) = (&result, code) {
+        let mut data = vec![FromClient::SendTotp as u8, code.len() as u8];
+        data.extend_from_slice(code.as_bytes());
+        let frame = sealer.seal(&data).map_err(|_| AuthError::TooLong)?;
+        writer.write_all(&frame).await.map_err(|_| AuthError::Connection)?;
+        writer.flush().await.map_err(|_| AuthError::Connection)?;
+        result = read_account(&mut reader).await;
+    }
+    let _ = writer.shutdown().await;
+    if let Ok(account) = &result {
+        *TOKEN.write().await = Some(account.token.clone());
+    }
+    result
+}
+
+async fn read_account(reader: &mut shared::network::LinkReader<'_>) -> Result<Account, AuthError> {
+    match reader.read_u8().await {
+        Ok(command) if command == ToClient::Authenticate as u8 => (),
+        Ok(command) if command == ToClient::Banned as u8 => {
+            let (expires_at, reason) = read_ban(reader).await.map_err(|_| AuthError::Connection)?;
+            return Err(AuthError::Banned { expires_at, reason });
+        }
+        _ => {
+            return Err(AuthError::Connection);
+        }
+    }
+    let status = reader.read_u8().await.map_err(|_| AuthError::Connection)?;
+    match Status::from_u8(status) {
+        Some(Status::Ok) => (),
+        Some(Status::TotpRequired) => {
+            let len = reader.read_u16().await.map_err(|_| AuthError::Connection)? as usize;
+            let mut url = vec![0u8; len];
+            reader.read_exact(&mut url).await.map_err(|_| AuthError::Connection)?;
+            let url = String::from_utf8(url).map_err(|_| AuthError::Connection)?;
+            return Err(AuthError::TotpRequired { enroll_url: (!url.is_empty()).then_some(url) });
+        }
+        Some(status) => {
+            return Err(AuthError::Refused(status));
+        }
+        None => {
+            return Err(AuthError::Refused(Status::ServerError));
+        }
+    }
+    let id = reader.read_u64().await.map_err(|_| AuthError::Connection)?;
+    let username = read_string(reader).await.map_err(|_| AuthError::Connection)?;
+    let len = reader.read_u16().await.map_err(|_| AuthError::Connection)? as usize;
+    let mut token = vec![0u8; len];
+    reader.read_exact(&mut token).await.map_err(|_| AuthError::Connection)?;
+    let mut refresh_token = [0u8; REFRESH_TOKEN_LEN];
+    reader.read_exact(&mut refresh_token).await.map_err(|_| AuthError::Connection)?;
+    Ok(Account { id, username, token, refresh_token })
+}
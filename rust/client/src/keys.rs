@@ -0,0 +1,165 @@
+use sustenet_shared as shared;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ Read, Write };
+use std::net::IpAddr;
+use std::sync::{ LazyLock, RwLock };
+
+use shared::security::exchange::PUBLIC_KEY_LEN;
+
+use crate::LOGGER;
+
+/// Where the keys of clusters we've connected to are cached.
+const CACHE_DIR: &str = "keys/clusters";
+
+/// A cluster's public key and its version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClusterKey {
+    pub version: u32,
+    pub key: [u8; PUBLIC_KEY_LEN],
+}
+
+/// Pinned keys by the cluster's address.
+type Pins = HashMap<(IpAddr, u16), [u8; PUBLIC_KEY_LEN]>;
+
+/// Keys set with `pin`. A cluster with a pinned key is only trusted if it has that key.
+static PINS: LazyLock<RwLock<Pins>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key isn't the one that was pinned.
+    NotPinned,
+    /// The key isn't the cached one. A new version doesn't make it trusted,
+    /// since anyone in the middle could say they rotated.
+    Changed,
+    /// The version is older than the one that's cached.
+    Rollback,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyError::NotPinned => write!(f, "Its key isn't the one that was pinned."),
+            KeyError::Changed => write!(f, "Its key changed. Use `keys::forget` or `keys::pin` if it was replaced on purpose."),
+            KeyError::Rollback => write!(f, "Its key is older than the one we have."),
+        }
+    }
+}
+
+/// Only trusts the cluster at the address if it has this key, whatever its
+/// version. It's trusted even if a different key is cached.
+pub fn pin(ip: IpAddr, port: u16, key: [u8; PUBLIC_KEY_LEN]) {
+    PINS.write().expect("The pins lock was poisoned.").insert((ip, port), key);
+}
+
+pub fn unpin(ip: IpAddr, port: u16) {
+    PINS.write().expect("The pins lock was poisoned.").remove(&(ip, port));
+}
+
+fn cache_path(ip: IpAddr, port: u16) -> String {
+    // IPv6 addresses have colons, which can't be in file names on every platform.
+    format!("{CACHE_DIR}/{}_{port}", ip.to_string().replace(':', "-"))
+}
+
+/// The key that was cached for the cluster at the address. The file is a u32
+/// version followed by the key.
+pub fn cached(ip: IpAddr, port: u16) -> Option<ClusterKey> {
+    let mut data = [0u8; 4 + PUBLIC_KEY_LEN];
+    File::open(cache_path(ip, port)).ok()?.read_exact(&mut data).ok()?;
+    let mut key = [0u8; PUBLIC_KEY_LEN];
+    key.copy_from_slice(&data[4..]);
+    Some(ClusterKey { version: u32::from_be_bytes([data[0], data[1], data[2], data[3]]), key })
+}
+
+pub fn cache(ip: IpAddr, port: u16, key: &ClusterKey) -> std::io::Result<()> {
+    std::fs::DirBuilder::new().recursive(true).create(CACHE_DIR)?;
+    File::create(cache_path(ip, port))?.write_all(
+        &[key.version.to_be_bytes().as_slice(), key.key.as_slice()].concat()
+    )
+}
+
+/// Deletes the cached key so whatever key the cluster has next is trusted.
+/// Use this after a cluster replaced or rotated its key.
+pub fn forget(ip: IpAddr, port: u16) -> std::io::Result<()> {
+    match std::fs::remove_file(cache_path(ip, port)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a key the cluster sent against the pinned and cached ones. A
+/// pinned key is trusted whatever is cached. Otherwise the key has to be the
+/// cached one, even if its version is newer, until it's pinned or forgotten.
+pub fn check(ip: IpAddr, port: u16, key: &ClusterKey) -> Result<(), KeyError> {
+    match PINS.read().expect("The pins lock was poisoned.").get(&(ip, port)) {
+        Some(pinned) if *pinned != key.key => {
+            return Err(KeyError::NotPinned);
+        }
+        Some(_) => {
+            return Ok(());
+        }
+        None => (),
+    }
+
+    match cached(ip, port) {
+        Some(cached) if key.key != cached.key => Err(KeyError::Changed),
+        Some(cached) if key.version < cached.version => Err(KeyError::Rollback),
+        _ => Ok(()),
+    }
+}
+
+/// Fetches a key from the cluster's `domain_pub_key`. The URL has to serve the raw 32-byte key.
+pub async fn fetch(url: &str) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let response = match reqwest::get(url).await.and_then(|response| response.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => {
+            LOGGER.error(format!("Failed to fetch the cluster's key from {url}: {e}").as_str());
+            return None;
+        }
+    };
+    match response.bytes().await.map(|bytes| <[u8; PUBLIC_KEY_LEN]>::try_from(bytes.as_ref())) {
+        Ok(Ok(key)) => Some(key),
+        _ => {
+            LOGGER.error(format!("The key at {url} isn't a {PUBLIC_KEY_LEN}-byte public key.").as_str());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    pub fn test_check() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 39));
+        forget(ip, 39).unwrap();
+
+        let first = ClusterKey { version: 1, key: [1u8; PUBLIC_KEY_LEN] };
+        assert_eq!(check(ip, 39, &first), Ok(()));
+        cache(ip, 39, &first).unwrap();
+        assert_eq!(cached(ip, 39), Some(first));
+
+        let changed = ClusterKey { version: 1, key: [2u8; PUBLIC_KEY_LEN] };
+        assert_eq!(check(ip, 39, &changed), Err(KeyError::Changed));
+        // Someone in the middle can't take over by saying it's the next version.
+        let takeover = ClusterKey { version: 2, key: [2u8; PUBLIC_KEY_LEN] };
+        assert_eq!(check(ip, 39, &takeover), Err(KeyError::Changed));
+        let old = ClusterKey { version: 0, key: first.key };
+        assert_eq!(check(ip, 39, &old), Err(KeyError::Rollback));
+        assert_eq!(check(ip, 39, &ClusterKey { version: 2, ..first }), Ok(()));
+
+        pin(ip, 39, first.key);
+        assert_eq!(check(ip, 39, &takeover), Err(KeyError::NotPinned));
+        // A rotation is only trusted once it's pinned or the old key is forgotten.
+        pin(ip, 39, takeover.key);
+        assert_eq!(check(ip, 39, &takeover), Ok(()));
+        unpin(ip, 39);
+        forget(ip, 39).unwrap();
+        assert_eq!(cached(ip, 39), None);
+        assert_eq!(check(ip, 39, &takeover), Ok(()));
+    }
+}
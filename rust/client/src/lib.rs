@@ -6,13 +6,19 @@ use std::sync::{ Arc, LazyLock };
 
 use tokio::io::{ AsyncReadExt, AsyncWriteExt, BufReader };
 use tokio::net::TcpStream;
+use tokio::net::tcp::WriteHalf;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{ RwLock, mpsc };
 
 use sustenet_shared::ClientPlugin;
 use shared::logging::{ LogType, Logger };
-use shared::packets::cluster::ToClient;
-use shared::packets::master::ToUnknown;
+use shared::network::{ LinkReader, read_string };
+use shared::bans::read_ban;
+use shared::packets::Status;
+use shared::packets::cluster::{ FromClient, ToClient };
+use shared::packets::master::{ FromUnknown, ToUnknown };
+use shared::security::exchange::{ Exchange, PUBLIC_KEY_LEN, SIGNATURE_LEN, transcript, verify };
+use shared::security::session::{ SecureReader, Sealer };
 use shared::utils::constants::{ DEFAULT_IP, MASTER_PORT };
 use shared::{ lread_string, lselect };
 
@@ -29,11 +35,22 @@ lazy_static::lazy_static! {
             })
         )
     );
+    /// The ticket the Master Server gave us for the cluster we're joining.
+    pub static ref TICKET: Arc<RwLock<Option<Vec<u8>>>> = Arc::new(RwLock::new(None));
+    /// Our half of the exchange for the session with the cluster we're joining.
+    /// The ticket only works in a session started with its public key.
+    static ref EXCHANGE: Arc<RwLock<Option<Exchange>>> = Arc::new(RwLock::new(None));
+    /// The token from the last login. It's sent to every cluster after the ticket.
+    pub static ref TOKEN: Arc<RwLock<Option<Vec<u8>>>> = Arc::new(RwLock::new(None));
 }
+pub mod auth;
+pub mod keys;
+
 pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::Cluster));
 
 #[derive(Debug, Clone)]
 pub struct ClusterInfo {
+    pub id: u32,
     pub name: String,
     pub ip: String,
     pub port: u16,
@@ -102,13 +119,75 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
 
     let handler = tokio::spawn(async move {
         LOGGER.warning(format!("Connecting to the {connection_type}...").as_str());
-        let mut stream = TcpStream::connect(format!("{}:{}", ip, port)).await.expect(
-            format!("Failed to connect to the {connection_type} at {ip}:{port}.").as_str()
+        let mut stream = TcpStream::connect(format!("{}:{}", ip, port)).await.unwrap_or_else(|_|
+            panic!("Failed to connect to the {connection_type} at {ip}:{port}.")
         );
         LOGGER.success(format!("Connected to the {connection_type} at {ip}:{port}.").as_str());
 
         let (reader, mut writer) = stream.split();
-        let mut reader = BufReader::new(reader);
+        let mut reader = SecureReader::new(BufReader::new(reader));
+        // Set once the session starts. Everything after that is sealed.
+        let mut session: Option<Sealer> = None;
+
+        // The token is only sent to the Master Server once it proves it has its key.
+        if connection_type == ConnectionType::MasterServer {
+            match start_session(&mut reader, &mut writer, ip, port, &MASTER_HANDSHAKE, Exchange::new()).await {
+                Some(sealer) => {
+                    session = Some(sealer);
+                }
+                None => {
+                    LOGGER.error(format!("Failed to start a session with the {connection_type}.").as_str());
+                    let _ = writer.shutdown().await;
+                    return;
+                }
+            }
+        }
+
+        // Clusters only accept clients that start a session and then send a ticket from the Master Server.
+        if connection_type == ConnectionType::ClusterServer {
+            let exchange = EXCHANGE.write().await.take().unwrap_or_default();
+            let mut sealer = match start_session(&mut reader, &mut writer, ip, port, &CLUSTER_HANDSHAKE, exchange).await {
+                Some(sealer) => sealer,
+                None => {
+                    LOGGER.error(format!("Failed to start a session with the {connection_type}.").as_str());
+                    let _ = writer.shutdown().await;
+                    return;
+                }
+            };
+
+            match TICKET.write().await.take() {
+                Some(ticket) => {
+                    let mut data = vec![FromClient::SendTicket as u8];
+                    data.extend_from_slice(&(ticket.len() as u16).to_be_bytes());
+                    data.extend_from_slice(&ticket);
+                    let frame = match sealer.seal(&data) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            LOGGER.error(format!("Failed to seal the ticket: {e}").as_str());
+                            let _ = writer.shutdown().await;
+                            return;
+                        }
+                    };
+                    writer.write_all(&frame).await.expect("Failed to write to the Server.");
+                    writer.flush().await.expect("Failed to flush the writer.");
+                }
+                None => LOGGER.warning("Connecting to a cluster without a ticket."),
+            }
+
+            if let Some(token) = TOKEN.read().await.as_ref() {
+                let mut data = vec![FromClient::SendToken as u8];
+                data.extend_from_slice(&(token.len() as u16).to_be_bytes());
+                data.extend_from_slice(token);
+                match sealer.seal(&data) {
+                    Ok(frame) => {
+                        writer.write_all(&frame).await.expect("Failed to write to the Server.");
+                        writer.flush().await.expect("Failed to flush the writer.");
+                    }
+                    Err(e) => LOGGER.error(format!("Failed to seal the token: {e}").as_str()),
+                }
+            }
+            session = Some(sealer);
+        }
 
         lselect! {
             command = reader.read_u8() => {
@@ -131,6 +210,13 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
 
                             let mut cluster_servers_tmp = Vec::new();
                             for _ in 0..amount {
+                                let id = match reader.read_u32().await {
+                                    Ok(id) => id,
+                                    Err(_) => {
+                                        LOGGER.error("Failed to read the cluster ID.");
+                                        continue;
+                                    }
+                                };
                                 let name = lread_string!(reader, |msg| LOGGER.error(msg), "cluster name");
                                 let ip = lread_string!(reader, |msg| LOGGER.error(msg), "cluster IP");
                                 let port = match reader.read_u16().await {
@@ -149,6 +235,7 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
                                 };
 
                                 cluster_servers_tmp.push(ClusterInfo {
+                                    id,
                                     name,
                                     ip,
                                     port,
@@ -166,6 +253,74 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
                                 }
                             }
                         },
+                        x if x == ToUnknown::SendTicket as u8 => {
+                            let cluster_id = match reader.read_u32().await {
+                                Ok(cluster_id) => cluster_id,
+                                Err(_) => {
+                                    LOGGER.error("Failed to read the ticket's cluster ID.");
+                                    continue;
+                                }
+                            };
+                            let len = match reader.read_u16().await {
+                                Ok(len) => len,
+                                Err(_) => {
+                                    LOGGER.error("Failed to read the ticket length.");
+                                    continue;
+                                }
+                            } as usize;
+                            let mut ticket = vec![0u8; len];
+                            if reader.read_exact(&mut ticket).await.is_err() {
+                                LOGGER.error("Failed to read the ticket.");
+                                continue;
+                            }
+
+                            let cluster = CLUSTER_SERVERS.read().await
+                                .iter()
+                                .find(|cluster| cluster.id == cluster_id)
+                                .cloned();
+                            let cluster = match cluster {
+                                Some(cluster) => cluster,
+                                None => {
+                                    LOGGER.error(format!("Received a ticket for Cluster#{cluster_id}, which we don't know about.").as_str());
+                                    continue;
+                                }
+                            };
+
+                            let connection = match std::panic::catch_unwind(|| Connection::from(cluster)) {
+                                Ok(connection) => connection,
+                                Err(_) => {
+                                    LOGGER.error("Failed to create a connection with the Cluster Server.");
+                                    continue;
+                                }
+                            };
+
+                            // Overwrite the current connection with the cluster connection.
+                            *TICKET.write().await = Some(ticket);
+                            *CONNECTION.write().await = Some(connection);
+                            writer.shutdown().await.expect("Failed to shutdown the writer.");
+                            LOGGER.info("Closing connection...");
+                            break;
+                        },
+                        x if x == ToUnknown::ClusterFull as u8 => {
+                            match reader.read_u32().await {
+                                Ok(cluster_id) => LOGGER.error(format!("Failed to join Cluster#{cluster_id}. It's full.").as_str()),
+                                Err(_) => LOGGER.error("Failed to read the full cluster's ID."),
+                            }
+                        },
+                        x if x == ToUnknown::JoinRefused as u8 => {
+                            let (Ok(cluster_id), Ok(status)) = (reader.read_u32().await, reader.read_u8().await) else {
+                                LOGGER.error("Failed to read why the join was refused.");
+                                continue;
+                            };
+                            LOGGER.error(
+                                format!("Failed to join Cluster#{cluster_id}. The {connection_type} refused our token with {:?}.", Status::from_u8(status)).as_str()
+                            );
+                        },
+                        x if x == ToUnknown::Banned as u8 => {
+                            log_ban(&mut reader, connection_type).await;
+                            writer.shutdown().await.expect("Failed to shutdown the writer.");
+                            break;
+                        },
                         cmd => plugin.receive_master(tx.clone(), cmd, &mut reader).await,
                     }
                     ConnectionType::ClusterServer => match command.unwrap() {
@@ -180,6 +335,13 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
 
                             let mut cluster_servers_tmp = Vec::new();
                             for _ in 0..amount {
+                                let id = match reader.read_u32().await {
+                                    Ok(id) => id,
+                                    Err(_) => {
+                                        LOGGER.error("Failed to read the cluster ID.");
+                                        continue;
+                                    }
+                                };
                                 let name = lread_string!(reader, |msg| LOGGER.error(msg), "cluster name");
                                 let ip = lread_string!(reader, |msg| LOGGER.error(msg), "cluster IP");
                                 let port = match reader.read_u16().await {
@@ -198,6 +360,7 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
                                 };
 
                                 cluster_servers_tmp.push(ClusterInfo {
+                                    id,
                                     name,
                                     ip,
                                     port,
@@ -215,12 +378,46 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
                                 }
                             }
                         },
-                        x if x == ToClient::DisconnectCluster as u8 => todo!(),
+                        x if x == ToClient::DisconnectCluster as u8 => {
+                            LOGGER.warning(format!("The {connection_type} disconnected us.").as_str());
+                            writer.shutdown().await.expect("Failed to shutdown the writer.");
+                            break;
+                        },
                         x if x == ToClient::LeaveCluster as u8 => todo!(),
 
-                        x if x == ToClient::VersionOfKey as u8 => todo!(),
-                        x if x == ToClient::SendPubKey as u8 => todo!(),
-                        x if x == ToClient::Authenticate as u8 => todo!(),
+                        x if x == ToClient::Authenticate as u8 => {
+                            let status = match reader.read_u8().await {
+                                Ok(status) => Status::from_u8(status),
+                                Err(_) => {
+                                    LOGGER.error("Failed to read the token status.");
+                                    continue;
+                                }
+                            };
+                            match status {
+                                Some(Status::Ok) => match reader.read_u64().await {
+                                    Ok(account_id) => LOGGER.success(format!("The {connection_type} accepted our token for account #{account_id}.").as_str()),
+                                    Err(_) => LOGGER.error("Failed to read the account ID."),
+                                },
+                                status => LOGGER.warning(format!("The {connection_type} refused our token with {status:?}.").as_str()),
+                            }
+                        },
+
+                        x if x == ToClient::CommandStatus as u8 => {
+                            let (Ok(command), Ok(status)) = (reader.read_u8().await, reader.read_u8().await) else {
+                                LOGGER.error("Failed to read the command status.");
+                                continue;
+                            };
+                            match Status::from_u8(status) {
+                                Some(Status::Ok) => LOGGER.success(format!("The {connection_type} ran command {command}.").as_str()),
+                                status => LOGGER.warning(format!("The {connection_type} refused command {command} with {status:?}.").as_str()),
+                            }
+                        },
+
+                        x if x == ToClient::Banned as u8 => {
+                            log_ban(&mut reader, connection_type).await;
+                            writer.shutdown().await.expect("Failed to shutdown the writer.");
+                            break;
+                        },
 
                         x if x == ToClient::Move as u8 => todo!(),
                         cmd => plugin.receive_cluster(tx.clone(), cmd, &mut reader).await,
@@ -236,9 +433,17 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
                         break;
                     }
 
+                    LOGGER.info(format!("Sent {data:?} as data to the {connection_type}.").as_str());
+                    let data = match session.as_mut().map(|sealer| sealer.seal(&data)) {
+                        Some(Ok(frame)) => frame.into_boxed_slice(),
+                        Some(Err(e)) => {
+                            LOGGER.error(format!("Failed to seal data for the {connection_type}: {e}").as_str());
+                            continue;
+                        }
+                        None => data,
+                    };
                     writer.write_all(&data).await.expect("Failed to write to the Server.");
                     writer.flush().await.expect("Failed to flush the writer.");
-                    LOGGER.info(format!("Sent {data:?} as data to the {connection_type}.").as_str());
                 } else {
                     writer.shutdown().await.expect("Failed to shutdown the writer.");
                     LOGGER.info("Shutting down connection...");
@@ -251,16 +456,177 @@ pub async fn start<P>(plugin: P) where P: ClientPlugin + Send + Sync + 'static {
     let _ = handler.await;
 }
 
+/// The commands a server uses for the session handshake.
+pub(crate) struct Handshake {
+    pub start_session: u8,
+    pub session_started: u8,
+    pub request_key: u8,
+    pub send_pub_key: u8,
+    /// Only clusters can send their key's URL instead of the key.
+    pub version_of_key: Option<u8>,
+    /// Sent instead of `session_started` if our IP is banned.
+    pub banned: u8,
+    /// Only clusters refuse connections for other reasons, like when they're draining.
+    pub refused: Option<u8>,
+}
+
+const MASTER_HANDSHAKE: Handshake = Handshake {
+    start_session: FromUnknown::StartSession as u8,
+    session_started: ToUnknown::SessionStarted as u8,
+    request_key: FromUnknown::RequestKey as u8,
+    send_pub_key: ToUnknown::SendPubKey as u8,
+    version_of_key: None,
+    banned: ToUnknown::Banned as u8,
+    refused: None,
+};
+
+const CLUSTER_HANDSHAKE: Handshake = Handshake {
+    start_session: FromClient::StartSession as u8,
+    session_started: ToClient::SessionStarted as u8,
+    request_key: FromClient::RequestKey as u8,
+    send_pub_key: ToClient::SendPubKey as u8,
+    version_of_key: Some(ToClient::VersionOfKey as u8),
+    banned: ToClient::Banned as u8,
+    refused: Some(ToClient::Refused as u8),
+};
+
+/// Reads a `Banned` packet and says why we were banned.
+pub(crate) async fn log_ban(reader: &mut LinkReader<'_>, server: impl std::fmt::Display) {
+    match read_ban(reader).await {
+        Ok((Some(expires_at), reason)) =>
+            LOGGER.error(format!("The {server} banned us until {expires_at}. {reason}").as_str()),
+        Ok((None, reason)) => LOGGER.error(format!("The {server} banned us. {reason}").as_str()),
+        Err(_) => LOGGER.error(format!("The {server} banned us.").as_str()),
+    }
+}
+
+/// Runs the key exchange with a server. The server signs both ephemeral keys
+/// with its identity, so the session can't be hijacked by someone in the middle
+/// who doesn't have that key. Its public key comes from the cache, or is asked
+/// for if the server signed with a version we don't have.
+pub(crate) async fn start_session(
+    reader: &mut LinkReader<'_>,
+    writer: &mut WriteHalf<'_>,
+    ip: IpAddr,
+    port: u16,
+    handshake: &Handshake,
+    exchange: Exchange
+) -> Option<Sealer> {
+    let client_public = exchange.public_key();
+    let mut data = vec![handshake.start_session];
+    data.extend_from_slice(&client_public);
+    writer.write_all(&data).await.ok()?;
+    writer.flush().await.ok()?;
+
+    match reader.read_u8().await {
+        Ok(command) if command == handshake.session_started => (),
+        Ok(command) if command == handshake.banned => {
+            log_ban(reader, ip).await;
+            return None;
+        }
+        Ok(command) if Some(command) == handshake.refused => {
+            match (reader.read_u8().await, read_string(reader).await) {
+                (Ok(status), Ok(reason)) =>
+                    LOGGER.error(format!("The server at {ip}:{port} refused us with {:?}. {reason}", Status::from_u8(status)).as_str()),
+                _ => LOGGER.error(format!("The server at {ip}:{port} refused us.").as_str()),
+            }
+            return None;
+        }
+        _ => {
+            return None;
+        }
+    }
+    let version = reader.read_u32().await.ok()?;
+    let mut cluster_public = [0u8; PUBLIC_KEY_LEN];
+    reader.read_exact(&mut cluster_public).await.ok()?;
+    let mut signature = [0u8; SIGNATURE_LEN];
+    reader.read_exact(&mut signature).await.ok()?;
+
+    // The key is asked for over the session, but it isn't trusted until it checks out below.
+    let (mut sealer, opener) = exchange.finish(&cluster_public, true)?;
+    reader.set_opener(opener);
+
+    let cached = keys::cached(ip, port);
+    let key = match cached {
+        Some(cached) if cached.version == version => cached,
+        _ => request_key(reader, writer, &mut sealer, handshake).await?,
+    };
+    if key.version != version {
+        LOGGER.error(format!("The server signed with version {version} of its key but sent version {}.", key.version).as_str());
+        return None;
+    }
+    if let Err(e) = keys::check(ip, port, &key) {
+        LOGGER.error(format!("Refusing to trust the server at {ip}:{port}. {e}").as_str());
+        return None;
+    }
+    if !verify(&key.key, &transcript(version, &client_public, &cluster_public), &signature) {
+        LOGGER.error(format!("The signature from the server at {ip}:{port} doesn't match its key.").as_str());
+        return None;
+    }
+
+    if cached != Some(key) {
+        if cached.is_some_and(|cached| cached.key != key.key) {
+            LOGGER.warning(format!("The server at {ip}:{port} has the pinned version {version} of its key instead of the cached one.").as_str());
+        }
+        if let Err(e) = keys::cache(ip, port, &key) {
+            LOGGER.error(format!("Failed to cache the server's key: {e}").as_str());
+        }
+    }
+    Some(sealer)
+}
+
+/// Asks the server for its key. It's either sent directly or fetched from the
+/// URL the server gives us.
+async fn request_key(
+    reader: &mut LinkReader<'_>,
+    writer: &mut WriteHalf<'_>,
+    sealer: &mut Sealer,
+    handshake: &Handshake
+) -> Option<keys::ClusterKey> {
+    writer.write_all(&sealer.seal(&[handshake.request_key]).ok()?).await.ok()?;
+    writer.flush().await.ok()?;
+
+    let command = reader.read_u8().await.ok()?;
+    let version = reader.read_u32().await.ok()?;
+    let key = match command {
+        x if x == handshake.send_pub_key => {
+            let mut key = [0u8; PUBLIC_KEY_LEN];
+            reader.read_exact(&mut key).await.ok()?;
+            key
+        }
+        x if Some(x) == handshake.version_of_key => {
+            let url = read_string(reader).await.ok()?;
+            keys::fetch(&url).await?
+        }
+        _ => {
+            return None;
+        }
+    };
+    Some(keys::ClusterKey { version, key })
+}
+
 pub async fn send_data(tx: &Sender<Box<[u8]>>, data: Box<[u8]>) {
     tx.send(data).await.expect("Failed to send data to the Server.");
 }
 
-pub async fn join_cluster(tx: &Sender<Box<[u8]>>, id: usize) {
-    if id < (0 as usize) {
-        LOGGER.error("Failed to join a cluster. The cluster ID is invalid (less than 0).");
+/// Sends the token in `TOKEN` to the cluster, like after a refresh. The cluster
+/// answers with `Authenticate`.
+pub async fn send_token(tx: &Sender<Box<[u8]>>) {
+    let Some(token) = TOKEN.read().await.clone() else {
+        LOGGER.error("Failed to send the token. There isn't one.");
         return;
-    }
+    };
+    let mut data = vec![FromClient::SendToken as u8];
+    data.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    data.extend_from_slice(&token);
+    send_data(tx, data.into_boxed_slice()).await;
+}
 
+/// Asks the Master Server to join a cluster. `id` is the index of the cluster in
+/// `CLUSTER_SERVERS`. The token in `TOKEN` says who the player is, so log in
+/// first. Once the Master Server sends a ticket back, the client disconnects
+/// from it and connects to the cluster.
+pub async fn join_cluster(tx: &Sender<Box<[u8]>>, id: usize) {
     let cluster_servers = CLUSTER_SERVERS.read().await;
     if cluster_servers.is_empty() {
         LOGGER.error("Failed to join a cluster. No cluster servers are available.");
@@ -274,32 +640,28 @@ pub async fn join_cluster(tx: &Sender<Box<[u8]>>, id: usize) {
         return;
     }
 
-    let cluster = (
-        match cluster_servers.get(id) {
-            Some(cluster) => cluster,
-            None => {
-                LOGGER.error("Failed to join a cluster. The cluster ID is invalid.");
-                return;
-            }
-        }
-    ).clone();
-
-    LOGGER.success(format!("Client is joining cluster {}", cluster.name).as_str());
-
-    let connection = match std::panic::catch_unwind(|| Connection::from(cluster)) {
-        Ok(connection) => connection,
-        Err(_) => {
-            LOGGER.error("Failed to create a connection with the Cluster Server.");
+    let cluster = match cluster_servers.get(id) {
+        Some(cluster) => cluster,
+        None => {
+            LOGGER.error("Failed to join a cluster. The cluster ID is invalid.");
             return;
         }
     };
-    {
-        // Overwrite the current connection with the cluster connection.
-        *CONNECTION.write().await = Some(connection);
-        stop(tx).await;
-    }
-}
 
-async fn stop(tx: &Sender<Box<[u8]>>) {
-    tx.send(Box::new([])).await.expect("Failed to shutdown.");
+    let Some(token) = TOKEN.read().await.clone() else {
+        LOGGER.error("Failed to join a cluster. Log in first.");
+        return;
+    };
+
+    LOGGER.success(format!("Client is joining cluster {}", cluster.name).as_str());
+
+    // The ticket is tied to this key, so it's kept for the session with the cluster.
+    let exchange = Exchange::new();
+    let mut data = vec![FromUnknown::JoinCluster as u8];
+    data.extend_from_slice(&cluster.id.to_be_bytes());
+    data.extend_from_slice(&exchange.public_key());
+    data.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    data.extend_from_slice(&token);
+    *EXCHANGE.write().await = Some(exchange);
+    send_data(tx, data.into_boxed_slice()).await;
 }
@@ -16,11 +16,11 @@ impl shared::ClientPlugin for DefaultPlugin {
         }
     }
 
-    fn receive_master<'plug>(
+    fn receive_master(
         &self,
         _tx: Sender<Box<[u8]>>,
         command: u8,
-        _reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        _reader: &mut shared::network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
         Box::pin(async move {
             match command {
@@ -31,11 +31,11 @@ impl shared::ClientPlugin for DefaultPlugin {
         })
     }
 
-    fn receive_cluster<'plug>(
+    fn receive_cluster(
         &self,
         _tx: Sender<Box<[u8]>>,
         command: u8,
-        _reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        _reader: &mut shared::network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
         Box::pin(async move {
             match command {
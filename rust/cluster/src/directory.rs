@@ -0,0 +1,37 @@
+use sustenet_shared as shared;
+
+use tokio::sync::mpsc::Sender;
+
+use shared::packets::master::FromUnknown;
+
+use crate::{ LOGGER, send_data };
+
+/// Tells the Master Server that a player is now on this cluster.
+///
+/// `tx` is the sender given to the plugin through `set_sender`.
+pub async fn player_joined(tx: &Sender<Box<[u8]>>, player_id: &str) {
+    send_player(tx, FromUnknown::PlayerJoined, player_id).await;
+}
+
+/// Tells the Master Server that a player has left this cluster.
+pub async fn player_left(tx: &Sender<Box<[u8]>>, player_id: &str) {
+    send_player(tx, FromUnknown::PlayerLeft, player_id).await;
+}
+
+/// Asks the Master Server which cluster a player is on. The answer
+/// arrives through `ServerPlugin::player_location`.
+pub async fn locate_player(tx: &Sender<Box<[u8]>>, player_id: &str) {
+    send_player(tx, FromUnknown::LocatePlayer, player_id).await;
+}
+
+async fn send_player(tx: &Sender<Box<[u8]>>, command: FromUnknown, player_id: &str) {
+    if player_id.len() > (u8::MAX as usize) {
+        LOGGER.error("Failed to send the player ID. It's too long.");
+        return;
+    }
+
+    let mut data = vec![command as u8];
+    data.push(player_id.len() as u8);
+    data.extend_from_slice(player_id.as_bytes());
+    send_data(tx, data.into_boxed_slice()).await;
+}
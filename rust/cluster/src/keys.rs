@@ -0,0 +1,85 @@
+use sustenet_shared as shared;
+
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ LazyLock, OnceLock, RwLock };
+
+use tokio::sync::mpsc::Sender;
+
+use shared::packets::master::FromUnknown;
+use shared::security::aes::{ Aes256Gcm, Key, KeyRing, KeyVersion, load_key_ring, rotation_proof };
+
+use crate::{ LOGGER, cluster_id, send_data };
+
+/// The name of this cluster's key.
+static NAME: OnceLock<String> = OnceLock::new();
+/// Every version of this cluster's key.
+static KEY_RING: LazyLock<RwLock<KeyRing>> = LazyLock::new(|| RwLock::new(KeyRing::default()));
+/// The key version the Master Server knows this cluster by.
+static REGISTERED_VERSION: AtomicU32 = AtomicU32::new(0);
+
+/// The newest version of the key that's valid right now.
+pub fn current() -> Option<KeyVersion> {
+    KEY_RING.read().expect("The key ring lock was poisoned.").current().cloned()
+}
+
+/// Returns a version of the key if it's still accepted. See `KeyRing::accepts`.
+pub fn get(version: u32) -> Option<Key<Aes256Gcm>> {
+    KEY_RING.read()
+        .expect("The key ring lock was poisoned.")
+        .accepts(version)
+        .map(|key| key.key)
+}
+
+pub fn name() -> Option<&'static str> {
+    NAME.get().map(String::as_str)
+}
+
+pub(crate) fn set_name(name: String) {
+    let _ = NAME.set(name);
+}
+
+pub(crate) fn set(ring: KeyRing) {
+    *KEY_RING.write().expect("The key ring lock was poisoned.") = ring;
+}
+
+pub fn registered_version() -> u32 {
+    REGISTERED_VERSION.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_registered_version(version: u32) {
+    REGISTERED_VERSION.store(version, Ordering::Relaxed);
+}
+
+/// Reloads the key from the `keys` directory. If there's a newer version than
+/// the one the Master Server knows, the cluster switches to it without
+/// registering again.
+pub(crate) async fn reload(key_name: &str, master_tx: &Sender<Box<[u8]>>) {
+    match load_key_ring(key_name) {
+        Ok(ring) => set(ring),
+        Err(e) => {
+            LOGGER.error(format!("Failed to reload the key {key_name}. Keeping the old one: {:?}", e).as_str());
+            return;
+        }
+    }
+
+    let (Some(cluster_id), Some(key)) = (cluster_id(), current()) else {
+        return;
+    };
+    if key.version == registered_version() {
+        return;
+    }
+
+    LOGGER.info(format!("Rotating to key version {}...", key.version).as_str());
+    let proof = match rotation_proof(cluster_id, key_name, &key) {
+        Ok(proof) => proof,
+        Err(e) => {
+            LOGGER.error(format!("Failed to make the rotation proof: {e}").as_str());
+            return;
+        }
+    };
+    let mut data = vec![FromUnknown::RotateKey as u8];
+    data.extend_from_slice(&key.version.to_be_bytes());
+    data.extend_from_slice(&(proof.len() as u16).to_be_bytes());
+    data.extend_from_slice(&proof);
+    send_data(master_tx, data.into_boxed_slice()).await;
+}
@@ -1,27 +1,48 @@
 use sustenet_shared as shared;
 
 use std::collections::BTreeSet;
-use std::sync::{ Arc, LazyLock };
+use std::sync::{ Arc, LazyLock, OnceLock };
 use std::{ net::Ipv4Addr, str::FromStr };
 
 use tokio::io::{ AsyncReadExt, AsyncWriteExt, BufReader };
+use tokio::net::tcp::WriteHalf;
 use tokio::net::{ TcpListener, TcpStream };
 use tokio::select;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{ Mutex, RwLock, mpsc };
+use tokio::time::{ Duration, timeout };
 
 use dashmap::DashMap;
 
 use public_ip::addr;
 
+use shared::bans;
+use shared::revocations;
 use shared::config::cluster::{ Settings, read };
+use shared::ids::{ Handle, IdAllocator };
 use shared::logging::{ LogType, Logger };
-use shared::network::{ ClusterInfo, Event };
-use shared::packets::cluster::FromClient;
+use shared::network::{ ClusterInfo, Event, LinkReader, read_envelope };
+use shared::packets::Status;
+use shared::packets::cluster::{ FromClient, ToClient };
 use shared::packets::master::{ FromUnknown, ToUnknown };
-use shared::security::aes::{ create_keys_dir, decrypt, generate_key, load_key, save_key };
+use shared::roles::{ Permission, Role, Roles, SessionInfo };
+use shared::security::aes::{
+    KeyRing,
+    KeyStore,
+    KeyVersion,
+    create_keys_dir,
+    generate_key,
+    load_key_ring,
+    save_key_version,
+    set_key_store,
+};
+use shared::security::envelope::{ self, Context };
+use shared::security::exchange::{ Exchange, Identity, PUBLIC_KEY_LEN, transcript };
+use shared::security::session::{ Opener, SecureReader, Sealer, derive_keys, generate_nonce };
+use shared::security::ticket::{ JoinTicket, TicketError };
+use shared::security::token::{ Token, TokenError, TrustedKeys };
 use shared::utils::constants::{ self, DEFAULT_IP };
-use shared::{ ServerPlugin, lselect };
+use shared::{ ServerPlugin, lread_string, lselect };
 
 lazy_static::lazy_static! {
     static ref CLUSTER_IDS: Arc<RwLock<BTreeSet<ClusterInfo>>> = Arc::new(
@@ -29,6 +50,26 @@ lazy_static::lazy_static! {
     );
 }
 pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::Cluster));
+/// The ID the Master Server gave this cluster once it was verified.
+static CLUSTER_ID: OnceLock<u32> = OnceLock::new();
+/// The key this cluster signs client sessions with.
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+/// Where clients can fetch the public key of `IDENTITY`. Unset if it's sent to them directly.
+static DOMAIN_PUB_KEY: OnceLock<String> = OnceLock::new();
+/// The auth server's keys. Tokens signed by anything else are refused.
+static AUTH_KEYS: OnceLock<TrustedKeys> = OnceLock::new();
+/// How long a client has to start a session and send its ticket after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub mod directory;
+pub mod keys;
+pub mod relay;
+pub mod sessions;
+
+/// The ID the Master Server gave this cluster. `None` until it's verified.
+pub fn cluster_id() -> Option<u32> {
+    CLUSTER_ID.get().copied()
+}
 
 pub fn get_ip(ip: &str) -> Ipv4Addr {
     Ipv4Addr::from_str(ip).unwrap_or(Ipv4Addr::from_str(DEFAULT_IP).unwrap_or(Ipv4Addr::LOCALHOST))
@@ -48,7 +89,14 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
         key_name,
         master_ip,
         master_port,
-        domain_pub_key: _,
+        tags,
+        cluster_uuid,
+        key_reload_interval,
+        domain_pub_key,
+        auth_keys,
+        keys_dir,
+        key_passphrase,
+        bans_file,
     } = settings;
 
     let plugin = Arc::new(plugin);
@@ -58,33 +106,93 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
         move |msg| plugin.info(msg)
     });
 
-    let key = match load_key(key_name.as_str()) {
-        Ok(key) => key,
+    let mut store = KeyStore::new(keys_dir.as_str());
+    if let Some(passphrase) = key_passphrase {
+        store = match store.with_passphrase(&passphrase) {
+            Ok(store) => store,
+            Err(e) => {
+                LOGGER.error(format!("Failed to open the key store: {e}").as_str());
+                panic!("Failed to open the key store: {e:?}");
+            }
+        };
+    }
+    set_key_store(store);
+
+    if let Err(e) = bans::set_ban_file(bans_file.as_str()) {
+        LOGGER.error(format!("Failed to load the bans at '{bans_file}': {e}").as_str());
+        panic!("Failed to load the bans at '{bans_file}': {e}");
+    }
+
+    let ring = match load_key_ring(key_name.as_str()) {
+        Ok(ring) => ring,
+        // Only a key that isn't there is replaced. One that can't be read is left alone.
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            LOGGER.error(format!("Failed to load the key {key_name}: {e}").as_str());
+            panic!("Failed to load the key {key_name}: {e}");
+        }
         Err(_) => {
             if let Err(e) = create_keys_dir() {
                 LOGGER.error(e.to_string().as_str());
                 panic!("{e:?}");
             }
 
-            let key = generate_key();
-            if save_key(key_name.as_str(), key).is_err() {
-                LOGGER.error("Failed to save the generated key.");
-                panic!("Failed to save the generated key.");
+            let key = KeyVersion::new(0, generate_key());
+            if let Err(e) = save_key_version(key_name.as_str(), &key) {
+                LOGGER.error(format!("Failed to save the generated key: {e}").as_str());
+                panic!("Failed to save the generated key: {e}");
             }
 
             LOGGER.warning(
                 format!(
-                    "A new AES key at 'keys/{key_name}' has been generated and saved. Make sure the Master Server also has this key for authentication."
+                    "A new AES key at '{keys_dir}/{key_name}' has been generated and saved. Copy it to the Master Server with `sustenet-keys export {key_name}.0` and `sustenet-keys import`."
                 ).as_str()
             );
 
-            key
+            let mut ring = KeyRing::default();
+            ring.insert(key);
+            ring
+        }
+    };
+    keys::set(ring);
+    keys::set_name(key_name.clone());
+    // The version this cluster registers with. Newer ones are picked up by `keys::reload`.
+    let key = match keys::current() {
+        Some(key) => key,
+        None => {
+            LOGGER.error(format!("None of the versions of the key {key_name} are valid right now.").as_str());
+            panic!("None of the versions of the key {key_name} are valid right now.");
         }
     };
 
+    match Identity::load_or_generate(key_name.as_str()) {
+        Ok(identity) => {
+            let _ = IDENTITY.set(identity);
+        }
+        Err(e) => {
+            LOGGER.error(format!("Failed to load the identity at '{keys_dir}/identity/{key_name}': {e}").as_str());
+            panic!("Failed to load the identity at '{keys_dir}/identity/{key_name}': {e:?}");
+        }
+    }
+    if let Some(domain_pub_key) = domain_pub_key {
+        if domain_pub_key.len() > (u8::MAX as usize) {
+            LOGGER.error("The domain_pub_key URL is too long. It can't be more than 255 bytes.");
+            panic!("The domain_pub_key URL is too long. It can't be more than 255 bytes.");
+        }
+        LOGGER.info(
+            format!("Clients will fetch our public key from {domain_pub_key}. Host '{keys_dir}/identity/{key_name}.pub' there.").as_str()
+        );
+        let _ = DOMAIN_PUB_KEY.set(domain_pub_key);
+    }
+
+    let _ = AUTH_KEYS.set(TrustedKeys::load(keys_dir.as_str(), &auth_keys, &LOGGER));
+
+    // Sent with `BecomeCluster`. The Master Server has to send it back encrypted with our key.
+    let cluster_nonce = generate_nonce();
+
     let (tx, mut rx) = mpsc::channel::<Box<[u8]>>(10);
     plugin.set_sender(tx.clone());
     let tx_clone = tx.clone();
+    let client_plugin = Arc::clone(&plugin);
 
     // Cluster Server's connection to the Master Server.
     tokio::spawn(async move {
@@ -93,7 +201,9 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
         ).await.expect("Failed to connect to the Master Server.");
 
         let (reader, mut writer) = stream.split();
-        let mut reader = BufReader::new(reader);
+        let mut reader = SecureReader::new(BufReader::new(reader));
+        // Set once the Master Server proves it has our key. Everything after that is sealed with it.
+        let mut session: Option<Sealer> = None;
 
         loop {
             select! {
@@ -106,24 +216,31 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
 
                     match command.unwrap() {
                         x if x == ToUnknown::VerifyCluster as u8 => {
-                            let len = reader.read_u8().await.unwrap() as usize;
-                            let mut passphrase = vec![0u8; len];
-                            match reader.read_exact(&mut passphrase).await {
-                                Ok(_) => {},
+                            let passphrase = match read_envelope(&mut reader).await {
+                                Ok(passphrase) => passphrase,
                                 Err(e) => {
-                                    LOGGER.error(format!("Failed to read passphrase to String: {:?}", e).as_str());
-                                    continue;
+                                    LOGGER.error(format!("Failed to read the passphrase. Disconnecting: {:?}", e).as_str());
+                                    return;
                                 }
-                            }
+                            };
 
                             let mut data = vec![FromUnknown::AnswerCluster as u8];
 
-                            let decrypted_passphrase = decrypt(passphrase.as_slice(), &key);
+                            // The passphrase is sealed for our nonce. Anything that can't do that
+                            // doesn't have our key and isn't the real Master Server.
+                            let context = Context { packet: ToUnknown::VerifyCluster as u8, connection: &cluster_nonce };
+                            let decrypted_passphrase = match envelope::open(&passphrase, &key.key, &context) {
+                                Ok(passphrase) => passphrase,
+                                Err(_) => {
+                                    LOGGER.error("The Master Server couldn't prove it has our key. Disconnecting.");
+                                    return;
+                                }
+                            };
 
                             data.push(decrypted_passphrase.len() as u8);
                             data.extend_from_slice(&decrypted_passphrase);
                             data.push(server_name.len() as u8);
-                            data.extend_from_slice(&server_name.as_bytes());
+                            data.extend_from_slice(server_name.as_bytes());
 
                             if let Some(ip) = addr().await {
                                 let ip_string = ip.to_string();
@@ -137,18 +254,120 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
 
                             data.extend_from_slice(&port.to_be_bytes());
                             data.extend_from_slice(&max_connections.to_be_bytes());
+                            data.push(tags.len() as u8);
+                            for tag in tags.iter() {
+                                data.push(tag.len() as u8);
+                                data.extend_from_slice(tag.as_bytes());
+                            }
+                            let cluster_uuid = cluster_uuid.as_deref().unwrap_or_default();
+                            data.push(cluster_uuid.len() as u8);
+                            data.extend_from_slice(cluster_uuid.as_bytes());
 
-
-                            send_data(&tx, data.into_boxed_slice()).await;
+                            // Written here instead of through `tx` so it's the last thing sent in plaintext.
+                            if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                                LOGGER.error("Failed to answer the Master Server.");
+                                return;
+                            }
+                            let (sealer, opener) = derive_keys(
+                                &[key.key.as_slice(), decrypted_passphrase.as_slice()].concat(),
+                                &cluster_nonce,
+                                b"sustenet master link",
+                                true
+                            );
+                            reader.set_opener(opener);
+                            session = Some(sealer);
                         }
                         x if x == ToUnknown::CreateCluster as u8 => {
-                            LOGGER.success("We did it! We verified the cluster!");
+                            let id = match reader.read_u32().await {
+                                Ok(id) => id,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the cluster ID: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let _ = CLUSTER_ID.set(id);
+                            keys::set_registered_version(key.version);
+                            LOGGER.success(format!("We did it! We verified the cluster as Cluster#{id}!").as_str());
+                        }
+                        x if x == ToUnknown::KeyRotated as u8 => {
+                            let version = match reader.read_u32().await {
+                                Ok(version) => version,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the rotated key version: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            keys::set_registered_version(version);
+                            LOGGER.success(format!("Rotated to key version {version}.").as_str());
+                        }
+                        x if x == ToUnknown::TokensRevoked as u8 => {
+                            let revoked = match revocations::read_revocations(&mut reader).await {
+                                Ok(revoked) => revocations::add(&revoked),
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the revoked tokens: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let disconnected = sessions::revoke(&revoked);
+                            if disconnected > 0 {
+                                LOGGER.info(format!("Disconnecting {disconnected} clients whose tokens were revoked.").as_str());
+                            }
+                        }
+                        x if x == ToUnknown::ClusterMessage as u8 => {
+                            let from = match reader.read_u32().await {
+                                Ok(from) => from,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the sender's cluster ID: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let len = match reader.read_u16().await {
+                                Ok(len) => len,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the cluster message length: {:?}", e).as_str());
+                                    continue;
+                                }
+                            } as usize;
+                            let mut message = vec![0u8; len];
+                            if let Err(e) = reader.read_exact(&mut message).await {
+                                LOGGER.error(format!("Failed to read the cluster message: {:?}", e).as_str());
+                                continue;
+                            }
+
+                            plugin.cluster_message(tx.clone(), from, message).await;
+                        }
+                        x if x == ToUnknown::PlayerLocation as u8 => {
+                            let player_id = lread_string!(reader, |msg| LOGGER.error(msg), "player ID");
+                            let found = match reader.read_u8().await {
+                                Ok(found) => found == 1,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read if the player was found: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+                            let cluster = match reader.read_u32().await {
+                                Ok(cluster) => cluster,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to read the player's cluster ID: {:?}", e).as_str());
+                                    continue;
+                                }
+                            };
+
+                            plugin.player_location(tx.clone(), player_id, found.then_some(cluster)).await;
                         }
                         cmd => plugin.receive(tx.clone(), cmd, &mut reader).await,
                 }
             }
                 result = rx.recv() => {
                     if let Some(data) = result {
+                        let data = match session.as_mut().map(|sealer| sealer.seal(&data)) {
+                            Some(Ok(frame)) => frame.into_boxed_slice(),
+                            Some(Err(e)) => {
+                                LOGGER.error(format!("Failed to seal data for the Master Server: {e}").as_str());
+                                continue;
+                            }
+                            None => data,
+                        };
                         writer.write_all(&data).await.expect("Failed to write to the Master Server.");
                         writer.flush().await.expect("Failed to flush the writer.");
                     } else {
@@ -162,8 +381,8 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
 
         let (event_sender, mut event_receiver) = mpsc::channel::<Event>(100);
 
-        let clients: DashMap<u32, ServerClient> = DashMap::new();
-        let released_ids: Arc<Mutex<BTreeSet<u32>>> = Arc::new(Mutex::new(BTreeSet::new())); // In the future, think about reserving cluster ids. Sometimes a cluster can get a high ID, causing RAM to stay high during low loads.
+        let clients: DashMap<Handle, ServerClient> = DashMap::new();
+        let ids: Mutex<IdAllocator> = Mutex::new(IdAllocator::new(max_connections as usize));
 
         {
             let tcp_listener = TcpListener::bind(
@@ -178,18 +397,13 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
                                 Event::Connection(id) => on_connection(id),
                                 Event::Disconnection(id) => {
                                     LOGGER.debug(format!("Client#{id} disconnected.").as_str());
-                                    clients.remove(&id);
-
-                                    if id >= clients.len() as u32 {
-                                        LOGGER.info(format!("Client#{id} wasn't added to the released IDs list.").as_str());
-                                        continue;
+                                    if let Some((_, client)) = clients.remove(&id) {
+                                        client.leave(&tx).await;
                                     }
 
-                                    let mut ids = released_ids.lock().await;
-                                    if !(*ids).insert(id) {
-                                        LOGGER.error(format!("ID {} already exists in the released IDs.", id).as_str());
-                                        continue;
-                                    };
+                                    if !ids.lock().await.release(id) {
+                                        LOGGER.error(format!("Client#{id} was already released.").as_str());
+                                    }
                                 },
                                 Event::ReceivedData(id, data) => on_received_data(id, &data),
                             }
@@ -199,23 +413,30 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
                     res = tcp_listener.accept() => {
                         if let Ok((stream, addr)) = res {
                             LOGGER.debug(format!("Accepted connection from {:?}", addr).as_str());
-
-                            // If the max_connections is reached, return an error.
-                            if max_connections != 0 && clients.len() >= (max_connections as usize) {
-                                LOGGER.error("Max connections reached.");
+                            if sessions::is_draining() {
+                                LOGGER.debug(format!("Refusing {addr}. The cluster is draining.").as_str());
+                                tokio::spawn(refuse(stream, Status::Unavailable, "The cluster is draining."));
+                                continue;
+                            }
+                            if let Some(ban) = bans::check_ip(addr.ip()) {
+                                LOGGER.warning(format!("Refusing {addr}. It's banned. {}", ban.reason).as_str());
+                                tokio::spawn(bans::refuse(stream, ban, ToClient::Banned as u8));
                                 continue;
                             }
 
-                            // Get the next available ID and insert it.
-                            let released_id: u32 = released_ids
-                                .lock().await
-                                .pop_first()
-                                .unwrap_or(clients.len() as u32);
-                            let mut client = ServerClient::new(released_id);
-                            client.handle_data(event_sender.clone(), stream).await;
-                            clients.insert(released_id, client);
+                            // Get the next available ID. There isn't one if max_connections is reached.
+                            let id = match ids.lock().await.allocate() {
+                                Some(id) => id,
+                                None => {
+                                    LOGGER.error("Max connections reached.");
+                                    continue;
+                                }
+                            };
+                            let mut client = ServerClient::new(id);
+                            client.handle_data(event_sender.clone(), stream, tx.clone(), Arc::clone(&plugin)).await;
+                            clients.insert(id, client);
 
-                            event_sender.send(Event::Connection(released_id)).await.unwrap();
+                            event_sender.send(Event::Connection(id)).await.unwrap();
                         }
                     }
                 }
@@ -230,17 +451,33 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
         let mut data = [command].to_vec();
         data.push(key_name.len() as u8);
         data.extend_from_slice(key_name.as_bytes());
+        data.extend_from_slice(&key.version.to_be_bytes());
+        data.push(cluster_nonce.len() as u8);
+        data.extend_from_slice(&cluster_nonce);
 
         let data = data.into_boxed_slice();
         send_data(&tx_clone, data).await;
     }
 
+    if key_reload_interval > 0 {
+        let tx = tx_clone.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(key_reload_interval));
+            // The first tick is immediate and the key was just loaded.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                keys::reload(&key_name, &tx).await;
+            }
+        });
+    }
+
     // Cluster Server Listener
     {
         let (event_sender, mut event_receiver) = mpsc::channel::<Event>(100);
 
-        let clients: DashMap<u32, ServerClient> = DashMap::new();
-        let released_ids: Arc<Mutex<BTreeSet<u32>>> = Arc::new(Mutex::new(BTreeSet::new()));
+        let clients: DashMap<Handle, ServerClient> = DashMap::new();
+        let ids: Mutex<IdAllocator> = Mutex::new(IdAllocator::new(max_connections as usize));
 
         {
             let max_connections_str = match max_connections {
@@ -267,18 +504,13 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
                             Event::Connection(id) => on_connection(id),
                             Event::Disconnection(id) => {
                                 LOGGER.debug(format!("Client#{id} disconnected.").as_str());
-                                clients.remove(&id);
-
-                                if id >= clients.len() as u32 {
-                                    LOGGER.info(format!("Client#{id} wasn't added to the released IDs list.").as_str());
-                                    continue;
+                                if let Some((_, client)) = clients.remove(&id) {
+                                    client.leave(&tx_clone).await;
                                 }
 
-                                let mut ids = released_ids.lock().await;
-                                if !(*ids).insert(id) {
-                                    LOGGER.error(format!("ID {} already exists in the released IDs.", id).as_str());
-                                    continue;
-                                };
+                                if !ids.lock().await.release(id) {
+                                    LOGGER.error(format!("Client#{id} was already released.").as_str());
+                                }
                             },
                             Event::ReceivedData(id, data) => on_received_data(id, &data),
                         }
@@ -288,23 +520,30 @@ pub async fn start<P>(plugin: P, settings: Settings) where P: ServerPlugin + Sen
                 res = tcp_listener.accept() => {
                     if let Ok((stream, addr)) = res {
                         LOGGER.debug(format!("Accepted connection from {:?}", addr).as_str());
-
-                        // If the max_connections is reached, return an error.
-                        if max_connections != 0 && clients.len() >= (max_connections as usize) {
-                            LOGGER.error("Max connections reached.");
+                        if sessions::is_draining() {
+                            LOGGER.debug(format!("Refusing {addr}. The cluster is draining.").as_str());
+                            tokio::spawn(refuse(stream, Status::Unavailable, "The cluster is draining."));
+                            continue;
+                        }
+                        if let Some(ban) = bans::check_ip(addr.ip()) {
+                            LOGGER.warning(format!("Refusing {addr}. It's banned. {}", ban.reason).as_str());
+                            tokio::spawn(bans::refuse(stream, ban, ToClient::Banned as u8));
                             continue;
                         }
 
-                        // Get the next available ID and insert it.
-                        let released_id: u32 = released_ids
-                            .lock().await
-                            .pop_first()
-                            .unwrap_or(clients.len() as u32);
-                        let mut client = ServerClient::new(released_id);
-                        client.handle_data(event_sender.clone(), stream).await;
-                        clients.insert(released_id, client);
+                        // Get the next available ID. There isn't one if max_connections is reached.
+                        let id = match ids.lock().await.allocate() {
+                            Some(id) => id,
+                            None => {
+                                LOGGER.error("Max connections reached.");
+                                continue;
+                            }
+                        };
+                        let mut client = ServerClient::new(id);
+                        client.handle_data(event_sender.clone(), stream, tx_clone.clone(), Arc::clone(&client_plugin)).await;
+                        clients.insert(id, client);
 
-                        event_sender.send(Event::Connection(released_id)).await.unwrap();
+                        event_sender.send(Event::Connection(id)).await.unwrap();
                     }
                 }
             }
@@ -316,12 +555,22 @@ async fn send_data(tx: &mpsc::Sender<Box<[u8]>>, data: Box<[u8]>) {
     tx.send(data).await.expect("Failed to send data to the Server.");
 }
 
+/// Tells a connection that was just accepted why it isn't taken, then closes it.
+/// It's sent unsealed since there isn't a session yet.
+async fn refuse(mut stream: TcpStream, status: Status, reason: &str) {
+    let mut data = vec![ToClient::Refused as u8, status as u8];
+    data.push(reason.len() as u8);
+    data.extend_from_slice(reason.as_bytes());
+    let _ = stream.write_all(&data).await;
+    let _ = stream.shutdown().await;
+}
+
 // region: Events
-fn on_connection(id: u32) {
+fn on_connection(id: Handle) {
     LOGGER.debug(format!("Client#{id} connected").as_str());
 }
 
-fn on_received_data(id: u32, data: &[u8]) {
+fn on_received_data(id: Handle, data: &[u8]) {
     LOGGER.debug(format!("Received data from Client#{id}: {:?}", data).as_str());
     todo!()
 }
@@ -343,31 +592,95 @@ fn on_received_data(id: u32, data: &[u8]) {
 // endregion
 
 pub struct ServerClient {
-    pub id: u32,
+    pub id: Handle,
     pub name: Arc<RwLock<Option<String>>>,
+    /// Set once the client's token is verified and the Master Server is told the player joined.
+    pub player_id: Arc<RwLock<Option<String>>>,
+    /// Set once the client sends a valid token from the auth server.
+    pub token: Arc<RwLock<Option<Token>>>,
     pub sender: Option<Sender<Box<[u8]>>>,
 }
 
 impl ServerClient {
-    pub fn new(id: u32) -> Self {
+    pub fn new(id: Handle) -> Self {
         ServerClient {
             id,
             name: Arc::new(RwLock::new(None)),
+            player_id: Arc::new(RwLock::new(None)),
+            token: Arc::new(RwLock::new(None)),
             sender: None,
         }
     }
 
     /// Handle the data from the client.
-    pub async fn handle_data(&mut self, event_sender: Sender<Event>, mut stream: TcpStream) {
+    ///
+    /// `master_tx` is the connection to the Master Server. It's used to
+    /// report the player joining. Commands the cluster doesn't handle go to `plugin`.
+    pub async fn handle_data<P>(
+        &mut self,
+        event_sender: Sender<Event>,
+        mut stream: TcpStream,
+        master_tx: Sender<Box<[u8]>>,
+        plugin: Arc<P>
+    )
+        where P: ServerPlugin + 'static
+    {
         let id = self.id;
         let _name = self.name.clone(); // TODO: Implement name handling.
+        let player_id = self.player_id.clone();
+        let token = self.token.clone();
         let (tx, mut rx) = tokio::sync::mpsc::channel(10);
         self.sender = Some(tx.clone());
 
         tokio::spawn(async move {
             let (reader, mut writer) = stream.split();
 
-            let mut reader = BufReader::new(reader);
+            let mut reader = SecureReader::new(BufReader::new(reader));
+            let mut sealer = None;
+
+            // The client has to start a session first. The ticket from the Master
+            // Server is the first thing it sends after that.
+            let handshake = timeout(HANDSHAKE_TIMEOUT, async {
+                let (session, opener, client_public) = Self::start_session(&mut reader, &mut writer).await.ok_or(
+                    "It didn't start a session.".to_string()
+                )?;
+                reader.set_opener(opener);
+                let sealer = sealer.insert(session);
+
+                let mut command = reader.read_u8().await.map_err(|_| TicketError::Invalid.to_string())?;
+                // The client only asks for our key if it doesn't have this version of it.
+                if command == FromClient::RequestKey as u8 {
+                    Self::send_key(&mut writer, sealer).await.map_err(|e| format!("Failed to send our key. {e}"))?;
+                    command = reader.read_u8().await.map_err(|_| TicketError::Invalid.to_string())?;
+                }
+                Self::read_ticket(command, &mut reader, &client_public).await.map_err(|e| e.to_string())
+            }).await;
+            let ticket = match handshake {
+                Ok(Ok(ticket)) => ticket,
+                Ok(Err(e)) => {
+                    LOGGER.warning(format!("Client#{id} was rejected. {e}").as_str());
+                    Self::reject(&mut writer, sealer.as_mut()).await;
+                    event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                    return;
+                }
+                Err(_) => {
+                    LOGGER.warning(format!("Client#{id} was rejected. It didn't finish the handshake in time.").as_str());
+                    Self::reject(&mut writer, sealer.as_mut()).await;
+                    event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                    return;
+                }
+            };
+            let mut sealer = sealer.expect("The session starts before the ticket is read.");
+            LOGGER.debug(format!("Client#{id} is player {}.", ticket.player_id).as_str());
+            let kicked = sessions::add(id);
+            // Who the player is. The roles only go past `Player` with a token.
+            let mut session = SessionInfo {
+                client: id,
+                player_id: ticket.player_id.clone(),
+                account_id: None,
+                username: None,
+                roles: Roles::of(&[Role::Player]),
+            };
 
             loop {
                 select! {
@@ -386,6 +699,7 @@ impl ServerClient {
                                 let cluster_ids = CLUSTER_IDS.read().await;
                                 data.push(cluster_ids.len() as u8);
                                 for cluster in (*cluster_ids).iter() {
+                                    data.extend_from_slice(&cluster.id.to_be_bytes());
                                     data.push(cluster.name.len() as u8);
                                     data.extend_from_slice(cluster.name.as_bytes());
                                     data.push(cluster.ip.len() as u8);
@@ -395,13 +709,108 @@ impl ServerClient {
                                 }
                                 Self::send_data(&tx, data.into_boxed_slice()).await;
                             },
+                            x if x == FromClient::SendToken as u8 => {
+                                let Ok(data) = Self::read_token(&mut reader).await else {
+                                    event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                                    break;
+                                };
+                                let result = Self::verify_token(&data, Some(session.player_id.as_str()));
+                                let mut reply = vec![ToClient::Authenticate as u8];
+                                if let Ok(verified) = &result && let Some(ban) = bans::check_account(verified.account_id) {
+                                    LOGGER.warning(format!("Client#{id} is banned account #{}. {}", verified.account_id, ban.reason).as_str());
+                                    if let Ok(frame) = sealer.seal(&ban.to_packet(ToClient::Banned as u8)) {
+                                        let _ = writer.write_all(&frame).await;
+                                    }
+                                    let _ = writer.shutdown().await;
+                                    event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                                    break;
+                                }
+                                match result {
+                                    Ok(verified) => {
+                                        session.account_id = Some(verified.account_id);
+                                        session.username = Some(verified.username.clone());
+                                        session.roles = Roles::from_token(&verified);
+                                        LOGGER.debug(
+                                            format!("Client#{id} is {}#{} with the roles {}.", verified.username, verified.account_id, session.roles).as_str()
+                                        );
+                                        reply.push(Status::Ok as u8);
+                                        reply.extend_from_slice(&verified.account_id.to_be_bytes());
+                                        // The Master Server only hears about players whose token checks out.
+                                        // A refreshed token doesn't report them again.
+                                        if player_id.read().await.is_none() {
+                                            directory::player_joined(&master_tx, &session.player_id).await;
+                                            *player_id.write().await = Some(session.player_id.clone());
+                                        }
+                                        sessions::authenticate(id, verified.clone());
+                                        *token.write().await = Some(verified);
+                                    }
+                                    Err(status) => {
+                                        LOGGER.warning(format!("Client#{id} sent a token that was refused with {status:?}.").as_str());
+                                        reply.push(status as u8);
+                                    }
+                                }
+                                Self::send_data(&tx, reply.into_boxed_slice()).await;
+                            },
+                            x if x == FromClient::Kick as u8 => {
+                                let Ok(target) = reader.read_u64().await else {
+                                    event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                                    break;
+                                };
+                                let status = match session.can(Permission::Kick) {
+                                    false => Status::Forbidden,
+                                    true if sessions::kick(target) => {
+                                        LOGGER.info(format!("Client#{id} kicked account #{target}.").as_str());
+                                        Status::Ok
+                                    }
+                                    true => Status::NotFound,
+                                };
+                                Self::send_data(&tx, Box::new([ToClient::CommandStatus as u8, x, status as u8])).await;
+                            },
+                            x if x == FromClient::Drain as u8 => {
+                                let status = match session.can(Permission::Drain) {
+                                    true => {
+                                        LOGGER.warning(format!("Client#{id} is draining the cluster. New players are refused.").as_str());
+                                        sessions::drain();
+                                        Status::Ok
+                                    }
+                                    false => Status::Forbidden,
+                                };
+                                Self::send_data(&tx, Box::new([ToClient::CommandStatus as u8, x, status as u8])).await;
+                            },
+                            x if x == FromClient::Undrain as u8 => {
+                                let status = match session.can(Permission::Drain) {
+                                    true => {
+                                        LOGGER.warning(format!("Client#{id} stopped draining the cluster. New players are taken again.").as_str());
+                                        sessions::undrain();
+                                        Status::Ok
+                                    }
+                                    false => Status::Forbidden,
+                                };
+                                Self::send_data(&tx, Box::new([ToClient::CommandStatus as u8, x, status as u8])).await;
+                            },
+                            cmd if cmd > FromClient::Undrain as u8 => {
+                                plugin.receive_client(tx.clone(), session.clone(), cmd, &mut reader).await;
+                            },
                             _ => (),
                         }
                     }
+                    _ = kicked.notified() => {
+                        LOGGER.info(format!("Client#{id} was kicked.").as_str());
+                        Self::reject(&mut writer, Some(&mut sealer)).await;
+                        event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
+                        break;
+                    }
                     // Outgoing data to the client.
                     result = rx.recv() => {
                         if let Some(data) = result {
-                            writer.write_all(&data).await.expect("Failed to write to the Master Server.");
+                            let frame = match sealer.seal(&data) {
+                                Ok(frame) => frame,
+                                Err(e) => {
+                                    LOGGER.error(format!("Failed to seal data for Client#{id}: {e}").as_str());
+                                    continue;
+                                }
+                            };
+                            writer.write_all(&frame).await.expect("Failed to write to the client.");
                             writer.flush().await.expect("Failed to flush the writer.");
                         } else {
                             writer.shutdown().await.expect("Failed to shutdown the writer.");
@@ -418,4 +827,120 @@ impl ServerClient {
     async fn send_data(tx: &mpsc::Sender<Box<[u8]>>, data: Box<[u8]>) {
         tx.send(data).await.expect("Failed to send data out.");
     }
+
+    /// Answers the client's `StartSession` with our half of the exchange, signed
+    /// by our identity. Returns `None` if the client sent anything else. The
+    /// client's public key is returned too, since its ticket is tied to it.
+    async fn start_session(
+        reader: &mut LinkReader<'_>,
+        writer: &mut WriteHalf<'_>
+    ) -> Option<(Sealer, Opener, [u8; PUBLIC_KEY_LEN])> {
+        match reader.read_u8().await {
+            Ok(command) if command == FromClient::StartSession as u8 => (),
+            _ => {
+                return None;
+            }
+        }
+        let mut client_public = [0u8; PUBLIC_KEY_LEN];
+        reader.read_exact(&mut client_public).await.ok()?;
+
+        let identity = IDENTITY.get()?;
+        let exchange = Exchange::new();
+        let cluster_public = exchange.public_key();
+        let mut data = vec![ToClient::SessionStarted as u8];
+        data.extend_from_slice(&identity.version().to_be_bytes());
+        data.extend_from_slice(&cluster_public);
+        data.extend_from_slice(&identity.sign(&transcript(identity.version(), &client_public, &cluster_public)));
+        writer.write_all(&data).await.ok()?;
+        writer.flush().await.ok()?;
+
+        let (sealer, opener) = exchange.finish(&client_public, false)?;
+        Some((sealer, opener, client_public))
+    }
+
+    /// Answers `RequestKey`. The key itself is only sent if it isn't hosted at `domain_pub_key`.
+    async fn send_key(writer: &mut WriteHalf<'_>, sealer: &mut Sealer) -> std::io::Result<()> {
+        let identity = IDENTITY.get().ok_or(std::io::Error::other("The identity isn't loaded."))?;
+        let data = match DOMAIN_PUB_KEY.get() {
+            Some(url) => {
+                let mut data = vec![ToClient::VersionOfKey as u8];
+                data.extend_from_slice(&identity.version().to_be_bytes());
+                data.push(url.len() as u8);
+                data.extend_from_slice(url.as_bytes());
+                data
+            }
+            None => {
+                let mut data = vec![ToClient::SendPubKey as u8];
+                data.extend_from_slice(&identity.version().to_be_bytes());
+                data.extend_from_slice(&identity.public_key());
+                data
+            }
+        };
+        writer.write_all(&sealer.seal(&data).map_err(std::io::Error::other)?).await?;
+        writer.flush().await
+    }
+
+    async fn read_ticket(
+        command: u8,
+        reader: &mut LinkReader<'_>,
+        client_public: &[u8]
+    ) -> Result<JoinTicket, TicketError> {
+        if command != (FromClient::SendTicket as u8) {
+            return Err(TicketError::Invalid);
+        }
+        let len = reader.read_u16().await.map_err(|_| TicketError::Invalid)? as usize;
+        let mut ticket = vec![0u8; len];
+        reader.read_exact(&mut ticket).await.map_err(|_| TicketError::Invalid)?;
+
+        let cluster_id = cluster_id().ok_or(TicketError::WrongCluster)?;
+        let header = JoinTicket::header(&ticket)?;
+        if keys::name() != Some(header.key_id.as_str()) {
+            return Err(TicketError::UnknownKey);
+        }
+        let key = keys::get(header.key_version).ok_or(TicketError::UnknownKey)?;
+        JoinTicket::verify(&ticket, &key, cluster_id, client_public)
+    }
+
+    async fn read_token(reader: &mut LinkReader<'_>) -> std::io::Result<Vec<u8>> {
+        let len = reader.read_u16().await? as usize;
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Checks a token from the auth server. It has to be for the player the ticket was for.
+    fn verify_token(data: &[u8], player_id: Option<&str>) -> Result<Token, Status> {
+        let keys = match AUTH_KEYS.get() {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => {
+                return Err(Status::ServerError);
+            }
+        };
+        match Token::verify(data, keys) {
+            Ok(token) if player_id != Some(token.player_id().as_str()) => Err(Status::BadRequest),
+            Ok(token) if revocations::is_revoked(&token) => Err(Status::Unauthorized),
+            Ok(token) => Ok(token),
+            Err(TokenError::UnknownKey) => Err(Status::NotFound),
+            Err(TokenError::Malformed | TokenError::BadSignature | TokenError::Expired) => Err(Status::BadRequest),
+        }
+    }
+
+    /// Disconnects the client. It's sealed if the session already started.
+    async fn reject(writer: &mut WriteHalf<'_>, sealer: Option<&mut Sealer>) {
+        let data = [ToClient::DisconnectCluster as u8];
+        let _ = match sealer.map(|sealer| sealer.seal(&data)) {
+            Some(Ok(frame)) => writer.write_all(&frame).await,
+            Some(Err(_)) => Ok(()),
+            None => writer.write_all(&data).await,
+        };
+        let _ = writer.shutdown().await;
+    }
+
+    /// Tells the Master Server the player left, if it was ever told they joined.
+    pub async fn leave(&self, master_tx: &Sender<Box<[u8]>>) {
+        sessions::remove(self.id);
+        if let Some(player_id) = self.player_id.read().await.as_ref() {
+            directory::player_left(master_tx, player_id).await;
+        }
+    }
 }
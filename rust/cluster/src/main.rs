@@ -16,11 +16,11 @@ impl shared::ServerPlugin for DefaultPlugin {
         }
     }
 
-    fn receive<'plug>(
+    fn receive(
         &self,
         _tx: Sender<Box<[u8]>>,
         command: u8,
-        _reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        _reader: &mut shared::network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
         Box::pin(async move {
             match command {
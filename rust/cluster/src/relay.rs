@@ -0,0 +1,60 @@
+use sustenet_shared as shared;
+
+use tokio::sync::mpsc::Sender;
+
+use shared::packets::master::FromUnknown;
+
+use crate::{ LOGGER, send_data };
+
+/// The largest message that can be relayed between clusters.
+pub const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+
+/// Sends a message to a single cluster through the Master Server.
+///
+/// `tx` is the sender given to the plugin through `set_sender`.
+pub async fn send_to_cluster(tx: &Sender<Box<[u8]>>, id: u32, message: &[u8]) {
+    let mut data = vec![FromUnknown::SendToCluster as u8];
+    data.extend_from_slice(&id.to_be_bytes());
+    if !push_message(&mut data, message) {
+        return;
+    }
+    send_data(tx, data.into_boxed_slice()).await;
+}
+
+/// Sends a message to every cluster that registered with `tag`.
+pub async fn send_to_tag(tx: &Sender<Box<[u8]>>, tag: &str, message: &[u8]) {
+    if tag.len() > (u8::MAX as usize) {
+        LOGGER.error("Failed to send the cluster message. The tag is too long.");
+        return;
+    }
+
+    let mut data = vec![FromUnknown::SendToTag as u8];
+    data.push(tag.len() as u8);
+    data.extend_from_slice(tag.as_bytes());
+    if !push_message(&mut data, message) {
+        return;
+    }
+    send_data(tx, data.into_boxed_slice()).await;
+}
+
+/// Sends a message to every other cluster.
+pub async fn broadcast_to_clusters(tx: &Sender<Box<[u8]>>, message: &[u8]) {
+    let mut data = vec![FromUnknown::BroadcastClusters as u8];
+    if !push_message(&mut data, message) {
+        return;
+    }
+    send_data(tx, data.into_boxed_slice()).await;
+}
+
+fn push_message(data: &mut Vec<u8>, message: &[u8]) -> bool {
+    if message.len() > MAX_MESSAGE_LEN {
+        LOGGER.error(
+            format!("Failed to send the cluster message. It's larger than {MAX_MESSAGE_LEN} bytes.").as_str()
+        );
+        return false;
+    }
+
+    data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    data.extend_from_slice(message);
+    true
+}
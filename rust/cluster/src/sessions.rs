@@ -0,0 +1,109 @@
+use sustenet_shared as shared;
+
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, LazyLock };
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use shared::ids::Handle;
+use shared::revocations::Revocation;
+use shared::security::token::Token;
+
+/// A client that sent a valid ticket.
+struct Player {
+    /// Set once it sends a valid token. Kicks and revocations go by its account.
+    token: Option<Token>,
+    /// Notified when it's kicked.
+    kicked: Arc<Notify>,
+}
+
+static PLAYERS: LazyLock<DashMap<Handle, Player>> = LazyLock::new(DashMap::new);
+/// Set once the cluster is draining. New connections are refused.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Adds a client once its ticket checks out. The client disconnects itself
+/// when the returned `Notify` is notified.
+pub fn add(client: Handle) -> Arc<Notify> {
+    let kicked = Arc::new(Notify::new());
+    PLAYERS.insert(client, Player { token: None, kicked: Arc::clone(&kicked) });
+    kicked
+}
+
+/// Remembers the token the client sent, so it's disconnected if the token is revoked.
+pub fn authenticate(client: Handle, token: Token) {
+    if let Some(mut player) = PLAYERS.get_mut(&client) {
+        player.token = Some(token);
+    }
+}
+
+pub fn remove(client: Handle) {
+    PLAYERS.remove(&client);
+}
+
+/// Disconnects every client authenticated as the account. Clients that haven't
+/// sent a token can't be kicked by account. Returns `false` if the account isn't on this cluster.
+pub fn kick(account_id: u64) -> bool {
+    let mut found = false;
+    let authenticated = |player: &Player| player.token.as_ref().is_some_and(|token| token.account_id == account_id);
+    for player in PLAYERS.iter().filter(|player| authenticated(player)) {
+        player.kicked.notify_one();
+        found = true;
+    }
+    found
+}
+
+/// Disconnects every client whose token is covered by one of the revocations. Returns how many there were.
+pub fn revoke(revocations: &[Revocation]) -> usize {
+    let mut revoked = 0;
+    for player in PLAYERS.iter() {
+        let covered = player.token
+            .as_ref()
+            .is_some_and(|token| revocations.iter().any(|revocation| revocation.covers(token)));
+        if covered {
+            player.kicked.notify_one();
+            revoked += 1;
+        }
+    }
+    revoked
+}
+
+/// Stops the cluster from taking new players until `undrain` is called.
+pub fn drain() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+/// Takes new players again.
+pub fn undrain() {
+    DRAINING.store(false, Ordering::Relaxed);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_kick_by_account() {
+        let unauthenticated = Handle::from_u64(9000);
+        let authenticated = Handle::from_u64(9001);
+        add(unauthenticated);
+        add(authenticated);
+        authenticate(authenticated, Token::new(424242, "sessions_testrunner".to_string(), Vec::new(), 60));
+
+        // Only clients with a token for the account are kicked.
+        assert!(kick(424242));
+        assert!(!kick(424243));
+        remove(authenticated);
+        assert!(!kick(424242));
+        remove(unauthenticated);
+
+        drain();
+        assert!(is_draining());
+        undrain();
+        assert!(!is_draining());
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::LazyLock;
+
+use dashmap::DashMap;
+
+/// Which cluster each authenticated player is on, keyed by player ID.
+static PLAYERS: LazyLock<DashMap<String, u32>> = LazyLock::new(DashMap::new);
+
+/// Returns the ID of the cluster the player is on. There's no admin API yet,
+/// so admin tools call this through a plugin.
+pub fn locate_player(player_id: &str) -> Option<u32> {
+    PLAYERS.get(player_id).map(|cluster| *cluster)
+}
+
+/// Returns every player that's on the cluster.
+pub fn players_on(cluster_id: u32) -> Vec<String> {
+    PLAYERS.iter()
+        .filter(|entry| *entry.value() == cluster_id)
+        .map(|entry| entry.key().clone())
+        .collect()
+}
+
+/// How many players are on the cluster.
+pub fn player_count(cluster_id: u32) -> usize {
+    PLAYERS.iter()
+        .filter(|entry| *entry.value() == cluster_id)
+        .count()
+}
+
+/// Moves the player to the cluster. A player can only be on one cluster at a time.
+pub(crate) fn player_joined(cluster_id: u32, player_id: String) {
+    PLAYERS.insert(player_id, cluster_id);
+}
+
+/// Removes the player, but only if the cluster reporting it is the one they're on.
+/// This keeps a late leave from an old cluster from removing them from their new one.
+pub(crate) fn player_left(cluster_id: u32, player_id: &str) {
+    PLAYERS.remove_if(player_id, |_, cluster| *cluster == cluster_id);
+}
+
+/// Removes every player on a cluster that disconnected.
+pub(crate) fn remove_cluster(cluster_id: u32) {
+    PLAYERS.retain(|_, cluster| *cluster != cluster_id);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_late_leave_keeps_new_cluster() {
+        player_joined(1, "directory_testrunner".to_string());
+        player_joined(2, "directory_testrunner".to_string());
+        player_left(1, "directory_testrunner");
+        assert_eq!(locate_player("directory_testrunner"), Some(2));
+
+        remove_cluster(2);
+        assert_eq!(locate_player("directory_testrunner"), None);
+    }
+}
@@ -0,0 +1,169 @@
+use sustenet_shared as shared;
+
+use std::time::{ Duration, Instant };
+
+use shared::packets::master::FromUnknown;
+use shared::roles::{ Role, Roles };
+
+use crate::security::PASSWORD_LEN;
+
+/// What a connection to the Master Server has proven itself to be.
+///
+/// Every connection starts as `Unknown`. It becomes a `Client` as soon as it
+/// asks for clusters, or a `SecureClient` once it starts a session. Only a
+/// `SecureClient` can join a cluster, so its token is never sent in the clear.
+/// If it asks to become a cluster instead, it's sent a challenge and only
+/// becomes a `VerifiedCluster` by answering it in time. Anything else closes
+/// the connection.
+pub enum ConnectionState {
+    Unknown,
+    ChallengeIssued {
+        key_name: String,
+        key_version: u32,
+        /// Only valid for a single answer.
+        passphrase: [u8; PASSWORD_LEN],
+        /// The nonce the cluster sent. It goes into the session key.
+        cluster_nonce: Vec<u8>,
+        expires_at: Instant,
+    },
+    VerifiedCluster {
+        cluster_id: u32,
+    },
+    Client,
+    SecureClient,
+}
+
+impl ConnectionState {
+    pub fn challenge(
+        key_name: String,
+        key_version: u32,
+        passphrase: [u8; PASSWORD_LEN],
+        cluster_nonce: Vec<u8>,
+        timeout: Duration
+    ) -> Self {
+        ConnectionState::ChallengeIssued {
+            key_name,
+            key_version,
+            passphrase,
+            cluster_nonce,
+            expires_at: Instant::now() + timeout,
+        }
+    }
+
+    /// Whether the connection can send the command in this state. Commands the
+    /// Master Server doesn't handle go to the plugin unless a challenge is pending.
+    pub fn allows(&self, command: u8) -> bool {
+        let is = |packet: FromUnknown| command == (packet as u8);
+
+        if is_custom(command) {
+            return !matches!(self, ConnectionState::ChallengeIssued { .. });
+        }
+
+        match self {
+            ConnectionState::Unknown =>
+                is(FromUnknown::RequestClusters) ||
+                    is(FromUnknown::StartSession) ||
+                    is(FromUnknown::BecomeCluster),
+            ConnectionState::ChallengeIssued { .. } => is(FromUnknown::AnswerCluster),
+            ConnectionState::VerifiedCluster { .. } =>
+                is(FromUnknown::RequestClusters) ||
+                    is(FromUnknown::SendToCluster) ||
+                    is(FromUnknown::SendToTag) ||
+                    is(FromUnknown::BroadcastClusters) ||
+                    is(FromUnknown::PlayerJoined) ||
+                    is(FromUnknown::PlayerLeft) ||
+                    is(FromUnknown::LocatePlayer) ||
+                    is(FromUnknown::RotateKey),
+            ConnectionState::Client => is(FromUnknown::RequestClusters),
+            ConnectionState::SecureClient =>
+                is(FromUnknown::RequestClusters) ||
+                    is(FromUnknown::RequestKey) ||
+                    is(FromUnknown::JoinCluster),
+        }
+    }
+
+    /// When the challenge has to be answered by, if one was issued.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            ConnectionState::ChallengeIssued { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+
+    /// Resolves once the challenge expires. Never resolves if there isn't one.
+    pub async fn expired(&self) {
+        match self.deadline() {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Verified clusters are trusted like `Role::Server`. Nothing else has a role on the Master Server.
+    pub fn roles(&self) -> Roles {
+        match self {
+            ConnectionState::VerifiedCluster { .. } => Roles::of(&[Role::Server]),
+            _ => Roles::NONE,
+        }
+    }
+
+    pub fn cluster_id(&self) -> Option<u32> {
+        match self {
+            ConnectionState::VerifiedCluster { cluster_id } => Some(*cluster_id),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the command is one the Master Server leaves to its plugin.
+/// `RequestKey` has to stay the last command in `FromUnknown`.
+pub fn is_custom(command: u8) -> bool {
+    command > (FromUnknown::RequestKey as u8)
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectionState::Unknown => write!(f, "Unknown"),
+            ConnectionState::ChallengeIssued { .. } => write!(f, "Challenge Issued"),
+            ConnectionState::VerifiedCluster { .. } => write!(f, "Verified Cluster"),
+            ConnectionState::Client => write!(f, "Client"),
+            ConnectionState::SecureClient => write!(f, "Secure Client"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_allowed_commands() {
+        let challenge = ConnectionState::challenge(
+            "cluster_key".to_string(),
+            0,
+            [0u8; PASSWORD_LEN],
+            vec![0u8; 32],
+            Duration::from_secs(10)
+        );
+        assert!(challenge.allows(FromUnknown::AnswerCluster as u8));
+        assert!(!challenge.allows(FromUnknown::BecomeCluster as u8));
+        assert!(!challenge.allows(FromUnknown::SendToCluster as u8));
+
+        assert!(!ConnectionState::Unknown.allows(FromUnknown::AnswerCluster as u8));
+        assert!(!ConnectionState::Unknown.allows(FromUnknown::PlayerJoined as u8));
+        assert!(!ConnectionState::Client.allows(FromUnknown::BecomeCluster as u8));
+        // Joining needs a session.
+        assert!(!ConnectionState::Unknown.allows(FromUnknown::JoinCluster as u8));
+        assert!(!ConnectionState::Client.allows(FromUnknown::JoinCluster as u8));
+        assert!(ConnectionState::SecureClient.allows(FromUnknown::JoinCluster as u8));
+        assert!(!ConnectionState::SecureClient.allows(FromUnknown::StartSession as u8));
+        let cluster = ConnectionState::VerifiedCluster { cluster_id: 0 };
+        assert!(cluster.allows(FromUnknown::BroadcastClusters as u8));
+        assert!(!cluster.allows(FromUnknown::AnswerCluster as u8));
+
+        let custom = (FromUnknown::RequestKey as u8) + 1;
+        assert!(ConnectionState::Unknown.allows(custom));
+        assert!(cluster.allows(custom));
+        assert!(!challenge.allows(custom));
+    }
+}
@@ -0,0 +1,121 @@
+use sustenet_shared as shared;
+
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::sync::{ LazyLock, Mutex, OnceLock };
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+
+use shared::utils::write_atomic;
+
+use crate::LOGGER;
+
+/// Cluster IDs keyed by the cluster's identity. A cluster that reconnects
+/// gets the same ID back, no matter which connection it came from.
+static CLUSTER_IDENTITIES: LazyLock<DashMap<String, u32>> = LazyLock::new(DashMap::new);
+static NEXT_CLUSTER_ID: AtomicU32 = AtomicU32::new(0);
+/// Where the cluster IDs are saved so they survive a restart.
+static CLUSTER_IDS_FILE: OnceLock<PathBuf> = OnceLock::new();
+/// Keeps two new clusters from saving the file at once.
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// Loads the cluster IDs from the file and saves new ones to it. Only the first
+/// call does anything. A file that doesn't exist is made when the first cluster registers.
+pub fn set_cluster_ids_file(path: impl Into<PathBuf>) -> Result<(), String> {
+    let path = path.into();
+    let ids = match std::fs::read_to_string(&path) {
+        Ok(text) => parse(&text)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            return Err(e.to_string());
+        }
+    };
+    if CLUSTER_IDS_FILE.set(path).is_ok() {
+        for (id, identity) in ids {
+            NEXT_CLUSTER_ID.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+            CLUSTER_IDENTITIES.insert(identity, id);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the cluster ID for an identity, giving it a new one if it's never been seen.
+pub fn cluster_id(identity: &str) -> u32 {
+    if let Some(id) = CLUSTER_IDENTITIES.get(identity) {
+        return *id;
+    }
+    let id = match CLUSTER_IDENTITIES.entry(identity.to_string()) {
+        Entry::Occupied(entry) => {
+            return *entry.get();
+        }
+        Entry::Vacant(entry) => *entry.insert(NEXT_CLUSTER_ID.fetch_add(1, Ordering::Relaxed)),
+    };
+    if let Some(path) = CLUSTER_IDS_FILE.get() && let Err(e) = save(path) {
+        LOGGER.error(format!("Failed to save the cluster IDs to '{}': {e}", path.display()).as_str());
+    }
+    id
+}
+
+/// A cluster is identified by the name of the key it was verified with, which
+/// stays the same when the key is rotated. A configured cluster UUID tells
+/// clusters with the same key apart. It's tied to the key, so a cluster can't
+/// take the ID of a cluster with another key by sending its UUID.
+pub fn identity(cluster_uuid: Option<&str>, key_name: &str) -> String {
+    match cluster_uuid {
+        Some(uuid) if !uuid.is_empty() => format!("key:{key_name}/uuid:{uuid}"),
+        _ => format!("key:{key_name}"),
+    }
+}
+
+/// Each line is a cluster ID and the identity it belongs to.
+fn parse(text: &str) -> Result<Vec<(u32, String)>, String> {
+    let mut ids = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(' ').map(|(id, identity)| (id.parse::<u32>(), identity.trim())) {
+            Some((Ok(id), identity)) if !identity.is_empty() => ids.push((id, identity.to_string())),
+            _ => {
+                return Err(format!("Line {}: A cluster ID is a number and the identity it belongs to.", number + 1));
+            }
+        }
+    }
+    Ok(ids)
+}
+
+fn save(path: &Path) -> std::io::Result<()> {
+    let _saving = SAVING.lock().expect("The cluster ID file lock was poisoned.");
+    let mut ids = CLUSTER_IDENTITIES.iter()
+        .map(|entry| (*entry.value(), entry.key().clone()))
+        .collect::<Vec<_>>();
+    ids.sort();
+
+    let mut text = "# cluster_id identity\n".to_string();
+    for (id, identity) in ids {
+        text.push_str(&format!("{id} {identity}\n"));
+    }
+    write_atomic(path, text.as_bytes())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_cluster_id_is_stable() {
+        let first = cluster_id(&identity(None, "identity_testrunner"));
+        let other = cluster_id(&identity(Some("identity_testrunner"), "identity_testrunner"));
+        assert_ne!(first, other);
+        assert_eq!(first, cluster_id(&identity(None, "identity_testrunner")));
+        assert_eq!(other, cluster_id(&identity(Some("identity_testrunner"), "identity_testrunner")));
+        // Another key can't claim the UUID.
+        assert_ne!(other, cluster_id(&identity(Some("identity_testrunner"), "other")));
+
+        let ids = parse("# cluster_id identity\n3 key:cluster_key/uuid:a b\n0 key:cluster_key").unwrap();
+        assert_eq!(ids, vec![(3, "key:cluster_key/uuid:a b".to_string()), (0, "key:cluster_key".to_string())]);
+        assert!(parse("three key:cluster_key").is_err());
+    }
+}
@@ -1,23 +1,47 @@
 use sustenet_shared as shared;
 
 use std::collections::BTreeSet;
-use std::sync::{ Arc, LazyLock };
+use std::net::IpAddr;
+use std::sync::{ Arc, LazyLock, OnceLock };
+use std::time::{ Duration, Instant };
 
 use dashmap::DashMap;
+use subtle::ConstantTimeEq;
 
 use tokio::io::{ AsyncReadExt, AsyncWriteExt, BufReader };
 use tokio::net::{ TcpListener, TcpStream };
 use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::{ self, Sender };
 use tokio::sync::{ Mutex, RwLock };
 
-use shared::config::master::{ Settings, read };
+use shared::bans;
+use shared::revocations::{ self, Revocation };
+use shared::config::master::{ DuplicateClusters, Settings, read };
+use shared::ids::{ Handle, IdAllocator };
 use shared::logging::{ LogType, Logger };
 use shared::network::*;
+use shared::packets::Status;
 use shared::packets::master::*;
 use shared::security::aes::*;
+use shared::security::envelope::{ self, Context, Header };
+use shared::security::exchange::{ Exchange, Identity, PUBLIC_KEY_LEN, transcript };
+use shared::security::session::{ NONCE_LEN, SecureReader, Sealer, derive_keys };
+use shared::security::ticket::JoinTicket;
+use shared::security::token::{ Token, TokenError, TrustedKeys };
 use shared::utils::constants;
-
+use shared::{ MasterPlugin, lread_string };
+
+use handshake::ConnectionState;
+use limits::Limits;
+use reservation::{ Caps, ReserveError };
+
+pub mod directory;
+pub mod handshake;
+pub mod identity;
+pub mod limits;
+pub mod relay;
+pub mod reservation;
 pub mod security;
 
 lazy_static::lazy_static! {
@@ -26,16 +50,32 @@ lazy_static::lazy_static! {
     );
 }
 pub static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::Cluster));
+/// Signs client sessions so clients know they're sending their token to us.
+static IDENTITY: OnceLock<Identity> = OnceLock::new();
+/// The auth server's keys. Clients need a token signed with one to join a cluster.
+static AUTH_KEYS: OnceLock<TrustedKeys> = OnceLock::new();
 
-#[derive(Eq)]
+#[derive(Clone)]
 struct ClusterInfo {
+    /// Stays the same when the cluster reconnects. See `identity`.
     id: u32,
+    /// The ID of the connection the cluster is on.
+    connection: Handle,
+    /// The name and version of the key the cluster was verified with.
+    key_name: String,
+    key_version: u32,
+    /// So the cluster can be disconnected if the version's key is replaced.
+    key_fingerprint: String,
     name: String,
     ip: String,
     port: u16,
     max_connections: u32,
+    tags: Vec<String>,
+    sender: Sender<Box<[u8]>>,
 }
 
+impl Eq for ClusterInfo {}
+
 impl Ord for ClusterInfo {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         // Define how to compare two ClusterInfo instances
@@ -58,18 +98,66 @@ impl PartialEq for ClusterInfo {
     }
 }
 
-pub async fn start_with_config() {
-    start(read()).await;
+pub async fn start_with_config<P>(plugin: P) where P: MasterPlugin + 'static {
+    start(plugin, read()).await;
 }
 
 /// This function starts the master server.
 /// It listens for an event
-pub async fn start(settings: Settings) {
-    let Settings { server_name: _, max_connections, port } = settings;
+pub async fn start<P>(plugin: P, settings: Settings) where P: MasterPlugin + 'static {
+    let Settings { max_connections, port, .. } = settings;
+    let settings = Arc::new(settings);
+    let plugin = Arc::new(plugin);
+
+    LOGGER.set_plugin({
+        let plugin = Arc::clone(&plugin);
+        move |msg| plugin.info(msg)
+    });
+
+    let mut store = KeyStore::new(settings.keys_dir.as_str());
+    if let Some(passphrase) = &settings.key_passphrase {
+        store = match store.with_passphrase(passphrase) {
+            Ok(store) => store,
+            Err(e) => {
+                LOGGER.error(format!("Failed to open the key store: {e}").as_str());
+                panic!("Failed to open the key store: {e:?}");
+            }
+        };
+    }
+    set_key_store(store);
+
+    if let Err(e) = bans::set_ban_file(settings.bans_file.as_str()) {
+        LOGGER.error(format!("Failed to load the bans at '{}': {e}", settings.bans_file).as_str());
+        panic!("Failed to load the bans at '{}': {e}", settings.bans_file);
+    }
+
+    if let Err(e) = revocations::set_revocation_file(settings.revocations_file.as_str()) {
+        LOGGER.error(format!("Failed to load the revocations at '{}': {e}", settings.revocations_file).as_str());
+        panic!("Failed to load the revocations at '{}': {e}", settings.revocations_file);
+    }
+
+    if let Err(e) = identity::set_cluster_ids_file(settings.cluster_ids_file.as_str()) {
+        LOGGER.error(format!("Failed to load the cluster IDs at '{}': {e}", settings.cluster_ids_file).as_str());
+        panic!("Failed to load the cluster IDs at '{}': {e}", settings.cluster_ids_file);
+    }
+
+    match Identity::load_or_generate(settings.key_name.as_str()) {
+        Ok(identity) => {
+            let _ = IDENTITY.set(identity);
+        }
+        Err(e) => {
+            LOGGER.error(format!("Failed to load the identity at '{}/identity/{}': {e}", settings.keys_dir, settings.key_name).as_str());
+            panic!("Failed to load the identity at '{}/identity/{}': {e:?}", settings.keys_dir, settings.key_name);
+        }
+    }
+    let _ = AUTH_KEYS.set(TrustedKeys::load(settings.keys_dir.as_str(), &settings.auth_keys, &LOGGER));
+
+    watch_keys(settings.key_reload_interval);
+    watch_revocations();
     let (event_sender, mut event_receiver) = mpsc::channel::<Event>(100);
 
-    let clients: DashMap<u32, ServerClient> = DashMap::new();
-    let released_ids: Arc<Mutex<BTreeSet<u32>>> = Arc::new(Mutex::new(BTreeSet::new())); // In the future, think about reserving cluster ids. Sometimes a cluster can get a high ID, causing RAM to stay high during low loads.
+    let clients: DashMap<Handle, ServerClient> = DashMap::new();
+    let ids: Mutex<IdAllocator> = Mutex::new(IdAllocator::new(max_connections as usize));
 
     {
         let max_connections_str = match max_connections {
@@ -94,21 +182,31 @@ pub async fn start(settings: Settings) {
                 event = event_receiver.recv() => {
                     if let Some(event) = event {
                         match event {
-                            Event::Connection(id) => on_connection(id),
+                            Event::Connection(id) => {
+                                on_connection(id);
+                                plugin.client_connected(id);
+                            },
                             Event::Disconnection(id) => {
                                 LOGGER.debug(format!("Client#{id} disconnected.").as_str());
                                 clients.remove(&id);
-
-                                if id >= clients.len() as u32 {
-                                    LOGGER.info(format!("Client#{id} wasn't added to the released IDs list.").as_str());
-                                    continue;
+                                let cluster_id = {
+                                    let mut cluster_ids = CLUSTER_IDS.write().await;
+                                    let cluster_id = cluster_ids.iter()
+                                        .find(|cluster| cluster.connection == id)
+                                        .map(|cluster| cluster.id);
+                                    cluster_ids.retain(|cluster| cluster.connection != id);
+                                    cluster_id
+                                };
+                                if let Some(cluster_id) = cluster_id {
+                                    directory::remove_cluster(cluster_id);
+                                    reservation::remove_cluster(cluster_id);
+                                    plugin.cluster_unregistered(cluster_id);
                                 }
+                                plugin.client_disconnected(id);
 
-                                let mut ids = released_ids.lock().await;
-                                if !(*ids).insert(id) {
-                                    LOGGER.error(format!("ID {} already exists in the released IDs.", id).as_str());
-                                    continue;
-                                };
+                                if !ids.lock().await.release(id) {
+                                    LOGGER.error(format!("Client#{id} was already released.").as_str());
+                                }
                             },
                             Event::ReceivedData(id, data) => on_received_data(id, &data),
                         }
@@ -118,23 +216,25 @@ pub async fn start(settings: Settings) {
                 res = tcp_listener.accept() => {
                     if let Ok((stream, addr)) = res {
                         LOGGER.debug(format!("Accepted connection from {:?}", addr).as_str());
-
-                        // If the max_connections is reached, return an error.
-                        if max_connections != 0 && clients.len() >= (max_connections as usize) {
-                            LOGGER.error("Max connections reached.");
+                        if let Some(ban) = bans::check_ip(addr.ip()) {
+                            LOGGER.warning(format!("Refusing {addr}. It's banned. {}", ban.reason).as_str());
+                            tokio::spawn(bans::refuse(stream, ban, ToUnknown::Banned as u8));
                             continue;
                         }
 
-                        // Get the next available ID and insert it.
-                        let released_id: u32 = released_ids
-                            .lock().await
-                            .pop_first()
-                            .unwrap_or(clients.len() as u32);
-                        let mut client = ServerClient::new(released_id);
-                        client.handle_data(event_sender.clone(), stream).await;
-                        clients.insert(released_id, client);
-
-                        event_sender.send(Event::Connection(released_id)).await.unwrap();
+                        // Get the next available ID. There isn't one if max_connections is reached.
+                        let id = match ids.lock().await.allocate() {
+                            Some(id) => id,
+                            None => {
+                                LOGGER.error("Max connections reached.");
+                                continue;
+                            }
+                        };
+                        let mut client = ServerClient::new(id);
+                        client.handle_data(event_sender.clone(), stream, settings.clone(), plugin.clone()).await;
+                        clients.insert(id, client);
+
+                        event_sender.send(Event::Connection(id)).await.unwrap();
                     }
                 }
             }
@@ -142,12 +242,123 @@ pub async fn start(settings: Settings) {
     }
 }
 
+/// Reloads the cluster keys from the `keys` directory. Clusters whose key was
+/// removed or replaced are disconnected.
+pub async fn reload_keys() {
+    let added = match security::reload_keys() {
+        Ok(added) => added,
+        Err(e) => {
+            LOGGER.error(format!("Failed to reload the keys. Keeping the old ones: {:?}", e).as_str());
+            return;
+        }
+    };
+    for name in added.iter() {
+        LOGGER.info(format!("Loaded the key {name}.").as_str());
+    }
+
+    // A key version is revoked if it was removed, replaced, expired, or is
+    // more than one version behind.
+    let senders = CLUSTER_IDS.read().await
+        .iter()
+        .filter(|cluster| {
+            security::get_key(&cluster.key_name, cluster.key_version)
+                .is_none_or(|key| fingerprint(&key) != cluster.key_fingerprint)
+        })
+        .map(|cluster| (cluster.id, format!("{}.{}", cluster.key_name, cluster.key_version), cluster.sender.clone()))
+        .collect::<Vec<_>>();
+    for (cluster_id, key_name, sender) in senders {
+        LOGGER.warning(format!("Disconnecting Cluster#{cluster_id}. Its key {key_name} was revoked.").as_str());
+        // An empty message tells the connection to close.
+        let _ = sender.send(Box::new([])).await;
+    }
+}
+
+/// Reloads the keys every `interval` seconds and whenever the process gets a SIGHUP.
+fn watch_keys(interval: u64) {
+    if interval > 0 {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval));
+            // The first tick is immediate and the keys were just loaded.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                reload_keys().await;
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{ SignalKind, signal };
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                LOGGER.error(format!("Failed to listen for SIGHUP: {:?}", e).as_str());
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            LOGGER.info("Reloading the keys...");
+            reload_keys().await;
+        }
+    });
+}
+
+/// Checks the revocation file every second and pushes new revocations to every cluster.
+/// Revocations made with `revocations::revoke` are pushed right away.
+fn watch_revocations() {
+    let mut updates = revocations::subscribe();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            select! {
+                _ = interval.tick() => revocations::reload(),
+                update = updates.recv() => {
+                    let revoked = match update {
+                        Ok(revocation) => {
+                            let mut revoked = vec![revocation];
+                            while let Ok(revocation) = updates.try_recv() {
+                                revoked.push(revocation);
+                            }
+                            revoked
+                        }
+                        // Some were missed, so send all of them. Clusters ignore the ones they have.
+                        Err(RecvError::Lagged(_)) => revocations::active(),
+                        Err(RecvError::Closed) => break,
+                    };
+                    push_revocations(&revoked).await;
+                }
+            }
+        }
+    });
+}
+
+async fn push_revocations(revoked: &[Revocation]) {
+    for revocation in revoked.iter() {
+        LOGGER.info(format!("Revoking account #{}'s tokens issued by {}.", revocation.account_id, revocation.revoked_at).as_str());
+    }
+    // Clone the senders so the lock isn't held while waiting on a full channel.
+    let senders = CLUSTER_IDS.read().await
+        .iter()
+        .map(|cluster| (cluster.id, cluster.sender.clone()))
+        .collect::<Vec<_>>();
+    for data in revocations::to_packets(ToUnknown::TokensRevoked as u8, revoked) {
+        let data = data.into_boxed_slice();
+        for (id, sender) in senders.iter() {
+            if let Err(e) = sender.send(data.clone()).await {
+                LOGGER.error(format!("Failed to send revocations to Cluster#{id}: {:?}", e).as_str());
+            }
+        }
+    }
+}
+
 // region: Events
-fn on_connection(id: u32) {
+fn on_connection(id: Handle) {
     LOGGER.debug(format!("Client#{id} connected").as_str());
 }
 
-fn on_received_data(id: u32, data: &[u8]) {
+fn on_received_data(id: Handle, data: &[u8]) {
     LOGGER.debug(format!("Received data from Client#{id}: {:?}", data).as_str());
     todo!()
 }
@@ -169,13 +380,13 @@ fn on_received_data(id: u32, data: &[u8]) {
 // endregion
 
 pub struct ServerClient {
-    pub id: u32,
+    pub id: Handle,
     pub name: Arc<RwLock<Option<String>>>,
     pub sender: Option<Sender<Box<[u8]>>>,
 }
 
 impl ServerClient {
-    pub fn new(id: u32) -> Self {
+    pub fn new(id: Handle) -> Self {
         ServerClient {
             id,
             name: Arc::new(RwLock::new(None)),
@@ -184,34 +395,56 @@ impl ServerClient {
     }
 
     /// Handle the data from the client.
-    pub async fn handle_data(&mut self, event_sender: Sender<Event>, mut stream: TcpStream) {
+    pub async fn handle_data<P>(
+        &mut self,
+        event_sender: Sender<Event>,
+        mut stream: TcpStream,
+        settings: Arc<Settings>,
+        plugin: Arc<P>
+    ) where P: MasterPlugin + 'static {
         let id = self.id;
         let name = self.name.clone();
         let (tx, mut rx) = tokio::sync::mpsc::channel(10);
         self.sender = Some(tx.clone());
+        let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        let limits = Limits::from(settings.as_ref());
+        let caps = Caps::from(settings.as_ref());
 
         tokio::spawn(async move {
             let (reader, mut writer) = stream.split();
 
-            let mut reader = BufReader::new(reader);
+            let mut reader = SecureReader::new(BufReader::new(reader));
+            let mut state = ConnectionState::Unknown;
+            // Set once the cluster is verified or the client starts a session. Everything after that is sealed with it.
+            let mut session: Option<Sealer> = None;
 
             loop {
                 select! {
                     // Incoming data from the client.
                     command = reader.read_u8() => {
-                        if command.is_err() {
-                            event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
-                            break;
-                        }
+                        let command = match command {
+                            Ok(command) => command,
+                            Err(_) => break,
+                        };
 
                         LOGGER.debug(format!("Received data from Client#{id}: {:?}", command).as_str());
 
-                        match command.unwrap() {
+                        if !state.allows(command) {
+                            LOGGER.warning(format!("Client#{id} sent command {command}, which isn't allowed while it's {state}.").as_str());
+                            break;
+                        }
+
+                        match command {
                             x if x == FromUnknown::RequestClusters as u8 => {
+                                if let ConnectionState::Unknown = state {
+                                    state = ConnectionState::Client;
+                                }
+
                                 let mut data = vec![ToUnknown::SendClusters as u8];
                                 let cluster_ids = CLUSTER_IDS.read().await;
                                 data.push(cluster_ids.len() as u8);
                                 for cluster in (*cluster_ids).iter() {
+                                    data.extend_from_slice(&cluster.id.to_be_bytes());
                                     data.push(cluster.name.len() as u8);
                                     data.extend_from_slice(cluster.name.as_bytes());
                                     data.push(cluster.ip.len() as u8);
@@ -221,181 +454,545 @@ impl ServerClient {
                                 }
                                 Self::send_data(&tx, data.into_boxed_slice()).await;
                             },
-                            x if x == FromUnknown::JoinCluster as u8 => {
-                                event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
-                                break;
+                            x if x == FromUnknown::StartSession as u8 => {
+                                let mut client_public = [0u8; PUBLIC_KEY_LEN];
+                                if reader.read_exact(&mut client_public).await.is_err() {
+                                    LOGGER.error(format!("Failed to read Client#{id}'s public key.").as_str());
+                                    break;
+                                }
+                                let Some(identity) = IDENTITY.get() else {
+                                    break;
+                                };
+                                let exchange = Exchange::new();
+                                let server_public = exchange.public_key();
+                                let mut data = vec![ToUnknown::SessionStarted as u8];
+                                data.extend_from_slice(&identity.version().to_be_bytes());
+                                data.extend_from_slice(&server_public);
+                                data.extend_from_slice(&identity.sign(&transcript(identity.version(), &client_public, &server_public)));
+                                // Written directly since it's the last thing that isn't sealed.
+                                if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                                    break;
+                                }
+                                let Some((sealer, opener)) = exchange.finish(&client_public, false) else {
+                                    LOGGER.warning(format!("Client#{id} sent a public key that can't be used.").as_str());
+                                    break;
+                                };
+                                reader.set_opener(opener);
+                                session = Some(sealer);
+                                state = ConnectionState::SecureClient;
                             },
-                            x if x == FromUnknown::BecomeCluster as u8 => {
-                                let len = match reader.read_u8().await {
-                                    Ok(len) => len,
+                            x if x == FromUnknown::RequestKey as u8 => {
+                                let Some(identity) = IDENTITY.get() else {
+                                    break;
+                                };
+                                let mut data = vec![ToUnknown::SendPubKey as u8];
+                                data.extend_from_slice(&identity.version().to_be_bytes());
+                                data.extend_from_slice(&identity.public_key());
+                                Self::send_data(&tx, data.into_boxed_slice()).await;
+                            },
+                            x if x == FromUnknown::JoinCluster as u8 => {
+                                let request = async {
+                                    let cluster_id = reader.read_u32().await?;
+                                    let mut client_public = [0u8; PUBLIC_KEY_LEN];
+                                    reader.read_exact(&mut client_public).await?;
+                                    let mut token = vec![0u8; reader.read_u16().await? as usize];
+                                    reader.read_exact(&mut token).await?;
+                                    Ok::<_, std::io::Error>((cluster_id, client_public, token))
+                                }.await;
+                                let (cluster_id, client_public, token) = match request {
+                                    Ok(request) => request,
                                     Err(e) => {
-                                        LOGGER.error(format!("Failed to read cluster name length: {:?}", e).as_str());
-                                        continue;
+                                        LOGGER.error(format!("Failed to read Client#{id}'s request to join a cluster: {:?}", e).as_str());
+                                        break;
                                     }
-                                } as usize;
-                                let mut key_name = vec![0u8; len];
-                                match reader.read_exact(&mut key_name).await {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        LOGGER.error(format!("Failed to read cluster name to String: {:?}", e).as_str());
+                                };
+
+                                if let Some(ip) = peer_ip && !limits::allow_join(ip, limits) {
+                                    LOGGER.warning(format!("Client#{id} ({ip}) is asking to join clusters too often.").as_str());
+                                    let mut data = vec![ToUnknown::JoinRefused as u8];
+                                    data.extend_from_slice(&cluster_id.to_be_bytes());
+                                    data.push(Status::TooManyAttempts as u8);
+                                    Self::send_data(&tx, data.into_boxed_slice()).await;
+                                    continue;
+                                }
+
+                                // The player ID comes from the token, so a client can only be routed as itself.
+                                let token = match Self::verify_token(&token) {
+                                    Ok(token) => token,
+                                    Err(status) => {
+                                        LOGGER.warning(format!("Client#{id} sent a token that was refused with {status:?}.").as_str());
+                                        let mut data = vec![ToUnknown::JoinRefused as u8];
+                                        data.extend_from_slice(&cluster_id.to_be_bytes());
+                                        data.push(status as u8);
+                                        Self::send_data(&tx, data.into_boxed_slice()).await;
                                         continue;
                                     }
+                                };
+                                if let Some(ban) = bans::check_account(token.account_id) {
+                                    LOGGER.warning(format!("Client#{id} is banned account #{}. {}", token.account_id, ban.reason).as_str());
+                                    Self::send_data(&tx, ban.to_packet(ToUnknown::Banned as u8).into_boxed_slice()).await;
+                                    Self::send_data(&tx, Box::new([])).await;
+                                    continue;
                                 }
-                                let key_name = String::from_utf8(key_name).unwrap();
-                                let key = match security::AES_KEYS.get(&key_name) {
-                                    Some(key) => key,
+                                let player_id = token.player_id();
+
+                                let cluster_id = match plugin.route(&player_id, cluster_id) {
+                                    Some(routed) => {
+                                        if routed != cluster_id {
+                                            LOGGER.debug(format!("Routed Client#{id} from Cluster#{cluster_id} to Cluster#{routed}.").as_str());
+                                        }
+                                        routed
+                                    }
+                                    None => {
+                                        LOGGER.warning(format!("Client#{id} was turned away from Cluster#{cluster_id}.").as_str());
+                                        let mut data = vec![ToUnknown::ClusterFull as u8];
+                                        data.extend_from_slice(&cluster_id.to_be_bytes());
+                                        Self::send_data(&tx, data.into_boxed_slice()).await;
+                                        continue;
+                                    }
+                                };
+
+                                let cluster = CLUSTER_IDS.read().await
+                                    .iter()
+                                    .find(|cluster| cluster.id == cluster_id)
+                                    .map(|cluster| (cluster.key_name.clone(), cluster.key_version, cluster.max_connections));
+                                let (key_name, key, key_version, max_connections) = match cluster.and_then(|(key_name, key_version, max_connections)|
+                                    security::get_key(&key_name, key_version).map(|key| (key_name, key, key_version, max_connections))
+                                ) {
+                                    Some(cluster) => cluster,
                                     None => {
-                                        LOGGER.error(format!("Key {} doesn't exist.", key_name).as_str());
+                                        LOGGER.warning(format!("Client#{id} tried to join Cluster#{cluster_id}, which doesn't exist.").as_str());
                                         continue;
                                     }
                                 };
 
+                                // Sealed before the slot is reserved so a failure doesn't hold one.
+                                let ticket = JoinTicket::new(player_id.clone(), cluster_id, settings.ticket_ttl);
+                                let ticket = match ticket.seal(&key_name, key_version, &key, &client_public) {
+                                    Ok(ticket) => ticket,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to seal a ticket for Client#{id}: {e}").as_str());
+                                        continue;
+                                    }
+                                };
+
+                                let timeout = Duration::from_secs(settings.reservation_timeout);
+                                match reservation::reserve(cluster_id, max_connections, &player_id, id, peer_ip, caps, timeout) {
+                                    Ok(()) => (),
+                                    Err(ReserveError::Full) => {
+                                        LOGGER.warning(format!("Client#{id} tried to join Cluster#{cluster_id}, which is full.").as_str());
+                                        let mut data = vec![ToUnknown::ClusterFull as u8];
+                                        data.extend_from_slice(&cluster_id.to_be_bytes());
+                                        Self::send_data(&tx, data.into_boxed_slice()).await;
+                                        continue;
+                                    }
+                                    Err(ReserveError::TooMany) => {
+                                        LOGGER.warning(format!("Client#{id} is holding too many slots to join Cluster#{cluster_id}.").as_str());
+                                        let mut data = vec![ToUnknown::JoinRefused as u8];
+                                        data.extend_from_slice(&cluster_id.to_be_bytes());
+                                        data.push(Status::TooManyAttempts as u8);
+                                        Self::send_data(&tx, data.into_boxed_slice()).await;
+                                        continue;
+                                    }
+                                }
+
+                                let mut data = vec![ToUnknown::SendTicket as u8];
+                                data.extend_from_slice(&cluster_id.to_be_bytes());
+                                data.extend_from_slice(&(ticket.len() as u16).to_be_bytes());
+                                data.extend_from_slice(&ticket);
+                                Self::send_data(&tx, data.into_boxed_slice()).await;
+                            },
+                            x if x == FromUnknown::BecomeCluster as u8 => {
+                                let key_name = match read_string(&mut reader).await {
+                                    Ok(key_name) => key_name,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the key name: {:?}", e).as_str());
+                                        break;
+                                    }
+                                };
+                                let key_version = match reader.read_u32().await {
+                                    Ok(key_version) => key_version,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the key version: {:?}", e).as_str());
+                                        break;
+                                    }
+                                };
+                                let cluster_nonce = match read_bytes(&mut reader).await {
+                                    Ok(cluster_nonce) if cluster_nonce.len() == NONCE_LEN => cluster_nonce,
+                                    Ok(_) => {
+                                        LOGGER.error(format!("Client#{id} sent a nonce that isn't {NONCE_LEN} bytes.").as_str());
+                                        break;
+                                    }
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the cluster's nonce: {:?}", e).as_str());
+                                        break;
+                                    }
+                                };
+                                LOGGER.debug(format!("Client#{id} asked to become a cluster with key {key_name}.{key_version}.").as_str());
+
+                                let Some(ip) = peer_ip else {
+                                    break;
+                                };
+                                if !limits::allow_attempt(ip, &key_name, limits) {
+                                    LOGGER.warning(format!("Client#{id} ({ip}) is asking to become a cluster too often.").as_str());
+                                    break;
+                                }
+
                                 let mut data = vec![ToUnknown::VerifyCluster as u8];
 
-                                let passphrase = &security::generate_passphrase();
-                                let encrypted_passphrase = encrypt(passphrase, key);
+                                // The passphrase is sealed for the cluster's nonce. Only something with the
+                                // key can do that, so it proves to the cluster that we're the real Master Server.
+                                // Unknown keys and versions that aren't accepted get a challenge sealed with a
+                                // throwaway key. It looks the same as a real one, but it can never be answered.
+                                let passphrase = security::generate_passphrase();
+                                let header = Header { key_id: key_name.clone(), key_version };
+                                let context = Context { packet: ToUnknown::VerifyCluster as u8, connection: &cluster_nonce };
+                                let encrypted_passphrase = match security::get_key(&key_name, key_version) {
+                                    Some(key) => envelope::seal(&passphrase, &header, &key, &context),
+                                    None => envelope::seal(&passphrase, &header, &generate_key(), &context),
+                                };
+                                let encrypted_passphrase = match encrypted_passphrase {
+                                    Ok(encrypted_passphrase) => encrypted_passphrase,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to encrypt the challenge for Client#{id}: {e}").as_str());
+                                        break;
+                                    }
+                                };
 
-                                data.push(encrypted_passphrase.len() as u8);
+                                data.extend_from_slice(&(encrypted_passphrase.len() as u16).to_be_bytes());
                                 data.extend_from_slice(&encrypted_passphrase);
 
-                                {
-                                    let mut name = name.write().await;
-                                    *name = Some(String::from_utf8(passphrase.to_vec()).unwrap());
-                                }
+                                state = ConnectionState::challenge(
+                                    key_name,
+                                    key_version,
+                                    passphrase,
+                                    cluster_nonce,
+                                    Duration::from_secs(settings.challenge_timeout)
+                                );
                                 Self::send_data(&tx, data.into_boxed_slice()).await;
                             },
                             x if x == FromUnknown::AnswerCluster as u8 => {
-                                let len = reader.read_u8().await.unwrap() as usize;
-                                let mut passphrase = vec![0u8; len];
-                                match reader.read_exact(&mut passphrase).await {
-                                    Ok(_) => {},
+                                // The challenge can only be answered once, right or wrong.
+                                let ConnectionState::ChallengeIssued { key_name, key_version, passphrase: expected, cluster_nonce, expires_at } =
+                                    std::mem::replace(&mut state, ConnectionState::Unknown)
+                                else {
+                                    break;
+                                };
+                                if Instant::now() > expires_at {
+                                    LOGGER.warning(format!("Client#{id} answered the challenge too late.").as_str());
+                                    Self::failed_challenge(peer_ip, limits);
+                                    break;
+                                }
+
+                                let passphrase = match read_bytes(&mut reader).await {
+                                    Ok(passphrase) => passphrase,
                                     Err(e) => {
-                                        LOGGER.error(format!("Failed to read the passphrase to String: {:?}", e).as_str());
-                                        continue;
+                                        LOGGER.error(format!("Failed to read the passphrase: {:?}", e).as_str());
+                                        break;
                                     }
+                                };
+                                // Compared in constant time so the answer can't be guessed a byte at a time.
+                                if !bool::from(passphrase.ct_eq(&expected)) {
+                                    LOGGER.error(format!("Client#{id} answered the challenge with the wrong passphrase.").as_str());
+                                    Self::failed_challenge(peer_ip, limits);
+                                    break;
                                 }
 
-                                {
-                                    let passphrase = match String::from_utf8(passphrase) {
-                                        Ok(passphrase) => passphrase,
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to convert passphrase to String: {:?}", e).as_str());
-                                            continue;
+                                // Read their name, IP, port, max connections, tags, and UUID.
+                                let cluster = async {
+                                    let server_name = read_string(&mut reader).await?;
+                                    let ip = read_string(&mut reader).await?;
+                                    let port = reader.read_u16().await?;
+                                    let max_connections = reader.read_u32().await?;
+                                    let tags = relay::read_tags(&mut reader).await?;
+                                    let cluster_uuid = read_string(&mut reader).await?;
+                                    Ok::<_, std::io::Error>((server_name, ip, port, max_connections, tags, cluster_uuid))
+                                }.await;
+                                let (server_name, ip, port, max_connections, tags, cluster_uuid) = match cluster {
+                                    Ok(cluster) => cluster,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the cluster's details: {:?}", e).as_str());
+                                        break;
+                                    }
+                                };
+
+                                let key = match security::get_key(&key_name, key_version) {
+                                    Some(key) => key,
+                                    None => {
+                                        LOGGER.error(format!("Key {key_name}.{key_version} was revoked during the challenge.").as_str());
+                                        break;
+                                    }
+                                };
+                                let identity = identity::identity(Some(cluster_uuid.as_str()), &key_name);
+                                let cluster_id = identity::cluster_id(&identity);
+
+                                let replaced = {
+                                    let mut cluster_ids = CLUSTER_IDS.write().await;
+                                    let duplicate = cluster_ids.iter()
+                                        .find(|cluster| cluster.id == cluster_id)
+                                        .map(|cluster| (cluster.connection, cluster.sender.clone()));
+                                    let replaced = match duplicate {
+                                        Some((connection, sender)) => match settings.duplicate_clusters {
+                                            DuplicateClusters::Replace => {
+                                                LOGGER.warning(format!("Client#{id} is replacing Client#{connection} as Cluster#{cluster_id}.").as_str());
+                                                // The old connection won't find itself when it disconnects, so it's cleaned up here.
+                                                cluster_ids.retain(|cluster| cluster.id != cluster_id);
+                                                directory::remove_cluster(cluster_id);
+                                                reservation::remove_cluster(cluster_id);
+                                                // An empty message tells the connection to close.
+                                                let _ = sender.try_send(Box::new([]));
+                                                true
+                                            }
+                                            DuplicateClusters::Reject => {
+                                                LOGGER.warning(format!("Client#{id} was rejected. Cluster#{cluster_id} is already connected as Client#{connection}.").as_str());
+                                                break;
+                                            }
                                         }
+                                        None => false,
                                     };
 
-                                    let name = name.read().await;
-                                    if (*name).is_none() || passphrase != *name.as_ref().expect("Failed to get saved passphrase.") {
-                                        LOGGER.error("The passphrase doesn't match the name.");
+                                    *name.write().await = Some(server_name.clone());
+                                    cluster_ids.insert(ClusterInfo {
+                                        id: cluster_id,
+                                        connection: id,
+                                        key_name,
+                                        key_version,
+                                        key_fingerprint: fingerprint(&key),
+                                        name: server_name.clone(),
+                                        ip,
+                                        port,
+                                        max_connections,
+                                        tags,
+                                        sender: tx.clone(),
+                                    });
+                                    replaced
+                                };
+                                // The plugin is called without the lock so it can't hold up every cluster lookup.
+                                if replaced {
+                                    plugin.cluster_unregistered(cluster_id);
+                                }
+                                plugin.cluster_registered(cluster_id, &server_name);
+                                LOGGER.success(format!("Client#{id} has become Cluster#{cluster_id} ({identity}).").as_str());
+                                state = ConnectionState::VerifiedCluster { cluster_id };
+                                let (sealer, opener) = derive_keys(
+                                    &[key.as_slice(), expected.as_slice()].concat(),
+                                    &cluster_nonce,
+                                    b"sustenet master link",
+                                    false
+                                );
+                                reader.set_opener(opener);
+                                session = Some(sealer);
+                                if let Some(peer_ip) = peer_ip {
+                                    limits::record_success(peer_ip);
+                                }
+
+                                let mut data = vec![ToUnknown::CreateCluster as u8];
+                                data.extend_from_slice(&cluster_id.to_be_bytes());
+                                Self::send_data(&tx, data.into_boxed_slice()).await;
+                                for data in revocations::to_packets(ToUnknown::TokensRevoked as u8, &revocations::active()) {
+                                    Self::send_data(&tx, data.into_boxed_slice()).await;
+                                }
+                            },
+
+                            // Cluster Section
+
+                            x if x == FromUnknown::SendToCluster as u8 => {
+                                let target = match reader.read_u32().await {
+                                    Ok(target) => target,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the target cluster: {:?}", e).as_str());
                                         continue;
-                                    } else {
-                                        LOGGER.success(format!("The passphrase matches the name: {:?} is {}", *name, passphrase).as_str());
                                     }
-                                }
+                                };
+                                let message = match relay::read_message(&mut reader).await {
+                                    Ok(message) => message,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the cluster message: {:?}", e).as_str());
+                                        continue;
+                                    }
+                                };
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
 
-                                // Read their new name they sent.
-                                let len = reader.read_u8().await.unwrap() as usize;
-                                let mut server_name = vec![0u8; len];
-                                match reader.read_exact(&mut server_name).await {
-                                    Ok(_) => {},
+                                if relay::relay(cluster_id, &message, |cluster| cluster.id == target).await == 0 {
+                                    LOGGER.warning(format!("Cluster#{target} doesn't exist. Message from Cluster#{cluster_id} was dropped.").as_str());
+                                }
+                            },
+                            x if x == FromUnknown::SendToTag as u8 => {
+                                let tag = lread_string!(reader, |msg| LOGGER.error(msg), "tag");
+                                let message = match relay::read_message(&mut reader).await {
+                                    Ok(message) => message,
                                     Err(e) => {
-                                        LOGGER.error(format!("Failed to read the server name to String: {:?}", e).as_str());
+                                        LOGGER.error(format!("Failed to read the cluster message: {:?}", e).as_str());
                                         continue;
                                     }
                                 };
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
 
-                                let server_name = match String::from_utf8(server_name) {
-                                    Ok(server_name) => server_name,
+                                let sent = relay::relay(cluster_id, &message, |cluster| cluster.tags.contains(&tag)).await;
+                                LOGGER.debug(format!("Cluster#{cluster_id} messaged {sent} clusters tagged '{tag}'.").as_str());
+                            },
+                            x if x == FromUnknown::BroadcastClusters as u8 => {
+                                let message = match relay::read_message(&mut reader).await {
+                                    Ok(message) => message,
                                     Err(e) => {
-                                        LOGGER.error(format!("Failed to convert server name to String: {:?}", e).as_str());
+                                        LOGGER.error(format!("Failed to read the cluster message: {:?}", e).as_str());
                                         continue;
                                     }
                                 };
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
 
-                                {
-                                    let mut name = name.write().await;
-                                    *name = Some(server_name.clone());
-                                }
+                                let sent = relay::relay(cluster_id, &message, |_| true).await;
+                                LOGGER.debug(format!("Cluster#{cluster_id} broadcasted to {sent} clusters.").as_str());
+                            },
+                            x if x == FromUnknown::PlayerJoined as u8 => {
+                                let player_id = lread_string!(reader, |msg| LOGGER.error(msg), "player ID");
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
 
-                                {
-                                    // Read IP to len. Read port u16. Read max connections u32.
-                                    let len = match reader.read_u8().await {
-                                        Ok(len) => len,
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to read the IP length: {:?}", e).as_str());
-                                            continue;
-                                        }
-                                    } as usize;
-                                    let mut ip = vec![0u8; len];
-                                    match reader.read_exact(&mut ip).await {
-                                        Ok(_) => {},
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to read the IP to String: {:?}", e).as_str());
-                                            continue;
-                                        }
+                                LOGGER.debug(format!("Player {player_id} joined Cluster#{cluster_id}.").as_str());
+                                reservation::confirm(cluster_id, &player_id);
+                                directory::player_joined(cluster_id, player_id);
+                            },
+                            x if x == FromUnknown::PlayerLeft as u8 => {
+                                let player_id = lread_string!(reader, |msg| LOGGER.error(msg), "player ID");
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
+
+                                LOGGER.debug(format!("Player {player_id} left Cluster#{cluster_id}.").as_str());
+                                directory::player_left(cluster_id, &player_id);
+                            },
+                            x if x == FromUnknown::LocatePlayer as u8 => {
+                                let player_id = lread_string!(reader, |msg| LOGGER.error(msg), "player ID");
+
+                                let mut data = vec![ToUnknown::PlayerLocation as u8];
+                                data.push(player_id.len() as u8);
+                                data.extend_from_slice(player_id.as_bytes());
+                                match directory::locate_player(&player_id) {
+                                    Some(cluster_id) => {
+                                        data.push(1);
+                                        data.extend_from_slice(&cluster_id.to_be_bytes());
                                     }
-                                    let ip = match String::from_utf8(ip) {
-                                        Ok(ip) => ip,
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to convert IP to String: {:?}", e).as_str());
-                                            continue;
-                                        }
-                                    };
-                                    let port = match reader.read_u16().await {
-                                        Ok(port) => port,
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to read the port: {:?}", e).as_str());
-                                            continue;
-                                        }
-                                    };
-                                    let max_connections = match reader.read_u32().await {
-                                        Ok(max_connections) => max_connections,
-                                        Err(e) => {
-                                            LOGGER.error(format!("Failed to read the max connections: {:?}", e).as_str());
-                                            continue;
-                                        }
-                                    };
+                                    None => {
+                                        data.push(0);
+                                        data.extend_from_slice(&0u32.to_be_bytes());
+                                    }
+                                }
+                                Self::send_data(&tx, data.into_boxed_slice()).await;
+                            },
+                            x if x == FromUnknown::RotateKey as u8 => {
+                                let Some(cluster_id) = state.cluster_id() else {
+                                    break;
+                                };
+                                let key_version = match reader.read_u32().await {
+                                    Ok(key_version) => key_version,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the key version: {:?}", e).as_str());
+                                        continue;
+                                    }
+                                };
+                                let proof = match read_envelope(&mut reader).await {
+                                    Ok(proof) => proof,
+                                    Err(e) => {
+                                        LOGGER.error(format!("Failed to read the rotation proof: {:?}", e).as_str());
+                                        continue;
+                                    }
+                                };
 
+                                let rotated = {
                                     let mut cluster_ids = CLUSTER_IDS.write().await;
-                                    if (*cluster_ids).insert(ClusterInfo {
-                                        id,
-                                        name: server_name,
-                                        ip,
-                                        port,
-                                        max_connections,
-                                    }) {
-                                        LOGGER.success(format!("Client#{id} has become a cluster.").as_str());
-                                    } else {
-                                        LOGGER.error(format!("Client#{id} failed to become a cluster.").as_str());
-                                        continue;
+                                    let cluster = cluster_ids.iter().find(|cluster| cluster.id == cluster_id).cloned();
+                                    match cluster {
+                                        Some(mut cluster) => match security::get_key(&cluster.key_name, key_version) {
+                                            Some(key) if check_rotation_proof(&proof, &cluster.key_name, &KeyVersion::new(key_version, key), cluster_id) => {
+                                                cluster.key_version = key_version;
+                                                cluster.key_fingerprint = fingerprint(&key);
+                                                cluster_ids.replace(cluster);
+                                                true
+                                            }
+                                            _ => false,
+                                        },
+                                        None => false,
                                     }
+                                };
+                                // The cluster tries again later if the Master Server hasn't loaded the version yet.
+                                if !rotated {
+                                    LOGGER.warning(format!("Cluster#{cluster_id} failed to rotate to key version {key_version}.").as_str());
+                                    continue;
                                 }
+                                LOGGER.success(format!("Cluster#{cluster_id} rotated to key version {key_version}.").as_str());
 
-                                Self::send_data(&tx, Box::new([ToUnknown::CreateCluster as u8])).await;
+                                let mut data = vec![ToUnknown::KeyRotated as u8];
+                                data.extend_from_slice(&key_version.to_be_bytes());
+                                Self::send_data(&tx, data.into_boxed_slice()).await;
                             },
 
-                            // Cluster Section
-
-                            _ => (),
+                            cmd => plugin.receive(tx.clone(), id, state.cluster_id(), cmd, &mut reader).await,
                         }
                     }
-                    // Outgoing data to the client.
+                    _ = state.expired() => {
+                        LOGGER.warning(format!("Client#{id} didn't answer the challenge in time.").as_str());
+                        Self::failed_challenge(peer_ip, limits);
+                        break;
+                    }
+                    // Outgoing data to the client. An empty message closes the connection.
                     result = rx.recv() => {
-                        if let Some(data) = result {
-                            writer.write_all(&data).await.expect("Failed to write to the Master Server.");
-                            writer.flush().await.expect("Failed to flush the writer.");
-                        } else {
-                            writer.shutdown().await.expect("Failed to shutdown the writer.");
-                            LOGGER.info("Cluster Server is shutting down its client writer.");
-                            event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
-                            break;
+                        match result {
+                            Some(data) if !data.is_empty() => {
+                                let data = match session.as_mut().map(|sealer| sealer.seal(&data)) {
+                                    Some(Ok(frame)) => frame.into_boxed_slice(),
+                                    Some(Err(e)) => {
+                                        LOGGER.error(format!("Failed to seal data for Client#{id}: {e}").as_str());
+                                        continue;
+                                    }
+                                    None => data,
+                                };
+                                if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                                    LOGGER.error(format!("Failed to write to Client#{id}.").as_str());
+                                    break;
+                                }
+                            }
+                            _ => break,
                         }
                     }
                 }
             }
+
+            LOGGER.info(format!("Closing the connection to Client#{id}.").as_str());
+            let _ = writer.shutdown().await;
+            event_sender.send(Event::Disconnection(id)).await.expect("Failed to send disconnection event.");
         });
     }
 
+    /// Checks a token from the auth server before a client is given a ticket.
+    fn verify_token(data: &[u8]) -> Result<Token, Status> {
+        let keys = match AUTH_KEYS.get() {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => {
+                return Err(Status::ServerError);
+            }
+        };
+        match Token::verify(data, keys) {
+            Ok(token) if revocations::is_revoked(&token) => Err(Status::Unauthorized),
+            Ok(token) => Ok(token),
+            Err(TokenError::UnknownKey) => Err(Status::NotFound),
+            Err(TokenError::Malformed | TokenError::BadSignature | TokenError::Expired) => Err(Status::BadRequest),
+        }
+    }
+
+    fn failed_challenge(ip: Option<IpAddr>, limits: Limits) {
+        if let Some(ip) = ip {
+            limits::record_failure(ip, limits);
+        }
+    }
+
     async fn send_data(tx: &mpsc::Sender<Box<[u8]>>, data: Box<[u8]>) {
         tx.send(data).await.expect("Failed to send data out.");
     }
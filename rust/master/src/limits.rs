@@ -0,0 +1,168 @@
+use sustenet_shared as shared;
+
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{ Duration, Instant };
+
+use dashmap::DashMap;
+
+use shared::config::master::Settings;
+
+/// How often connections can ask to become a cluster or join one. See the `[master]` config.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub attempts_per_ip: u32,
+    pub attempts_per_key: u32,
+    pub window: Duration,
+    pub failures_before_lockout: u32,
+    pub lockout: Duration,
+    pub joins_per_ip: u32,
+    pub join_window: Duration,
+}
+
+impl From<&Settings> for Limits {
+    fn from(settings: &Settings) -> Self {
+        Limits {
+            attempts_per_ip: settings.cluster_attempts_per_ip,
+            attempts_per_key: settings.cluster_attempts_per_key,
+            window: Duration::from_secs(settings.cluster_attempt_window),
+            failures_before_lockout: settings.cluster_failures_before_lockout,
+            lockout: Duration::from_secs(settings.cluster_lockout),
+            joins_per_ip: settings.join_attempts_per_ip,
+            join_window: Duration::from_secs(settings.join_attempt_window),
+        }
+    }
+}
+
+/// How often something asked to become a cluster in the current window.
+struct Attempts {
+    window_start: Instant,
+    count: u32,
+}
+
+/// Failed challenges from an IP and when its lockout ends, if it has one.
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+static IP_ATTEMPTS: LazyLock<DashMap<IpAddr, Attempts>> = LazyLock::new(DashMap::new);
+/// Keyed by the key name that was asked for, whether or not the key exists.
+static KEY_ATTEMPTS: LazyLock<DashMap<String, Attempts>> = LazyLock::new(DashMap::new);
+static FAILURES: LazyLock<DashMap<IpAddr, Failures>> = LazyLock::new(DashMap::new);
+/// Requests to join a cluster. Each one checks a token and can reserve a slot.
+static JOIN_ATTEMPTS: LazyLock<DashMap<IpAddr, Attempts>> = LazyLock::new(DashMap::new);
+
+/// Records an attempt to become a cluster and returns whether it's allowed.
+/// It isn't if the IP is locked out or the IP or key name is over its limit.
+pub(crate) fn allow_attempt(ip: IpAddr, key_name: &str, limits: Limits) -> bool {
+    prune(limits.window);
+
+    if is_locked_out(ip) {
+        return false;
+    }
+
+    // Both are counted even if one is over, so probing can't tell them apart.
+    let ip_allowed = count(
+        &mut IP_ATTEMPTS.entry(ip).or_insert_with(Attempts::new),
+        limits.attempts_per_ip,
+        limits.window
+    );
+    let key_allowed = count(
+        &mut KEY_ATTEMPTS.entry(key_name.to_string()).or_insert_with(Attempts::new),
+        limits.attempts_per_key,
+        limits.window
+    );
+    ip_allowed && key_allowed
+}
+
+/// Records a request to join a cluster and returns whether the IP is still under its limit.
+pub(crate) fn allow_join(ip: IpAddr, limits: Limits) -> bool {
+    JOIN_ATTEMPTS.retain(|_, attempts| attempts.window_start.elapsed() < limits.join_window);
+    count(&mut JOIN_ATTEMPTS.entry(ip).or_insert_with(Attempts::new), limits.joins_per_ip, limits.join_window)
+}
+
+/// Records a failed challenge and locks the IP out once it has too many.
+pub(crate) fn record_failure(ip: IpAddr, limits: Limits) {
+    if limits.failures_before_lockout == 0 {
+        return;
+    }
+
+    let mut failures = FAILURES.entry(ip).or_insert(Failures { count: 0, locked_until: None });
+    failures.count += 1;
+    if failures.count >= limits.failures_before_lockout {
+        failures.count = 0;
+        failures.locked_until = Some(Instant::now() + limits.lockout);
+    }
+}
+
+/// Forgets the failures of an IP that answered its challenge.
+pub(crate) fn record_success(ip: IpAddr) {
+    FAILURES.remove(&ip);
+}
+
+pub fn is_locked_out(ip: IpAddr) -> bool {
+    FAILURES.get(&ip)
+        .and_then(|failures| failures.locked_until)
+        .is_some_and(|locked_until| locked_until > Instant::now())
+}
+
+impl Attempts {
+    fn new() -> Self {
+        Attempts { window_start: Instant::now(), count: 0 }
+    }
+}
+
+/// A `limit` of 0 means there's no limit.
+fn count(attempts: &mut Attempts, limit: u32, window: Duration) -> bool {
+    if attempts.window_start.elapsed() >= window {
+        attempts.window_start = Instant::now();
+        attempts.count = 0;
+    }
+    attempts.count = attempts.count.saturating_add(1);
+    limit == 0 || attempts.count <= limit
+}
+
+/// Drops windows and lockouts that are over so made-up key names don't pile up.
+fn prune(window: Duration) {
+    let now = Instant::now();
+    IP_ATTEMPTS.retain(|_, attempts| attempts.window_start.elapsed() < window);
+    KEY_ATTEMPTS.retain(|_, attempts| attempts.window_start.elapsed() < window);
+    FAILURES.retain(|_, failures| failures.count > 0 || failures.locked_until.is_some_and(|until| until > now));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    pub fn test_limits_and_lockout() {
+        let limits = Limits {
+            attempts_per_ip: 2,
+            attempts_per_key: 0,
+            window: Duration::from_secs(60),
+            failures_before_lockout: 2,
+            lockout: Duration::from_secs(60),
+            joins_per_ip: 1,
+            join_window: Duration::from_secs(60),
+        };
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 33));
+        assert!(allow_attempt(ip, "limits_testrunner", limits));
+        assert!(allow_attempt(ip, "limits_testrunner", limits));
+        assert!(!allow_attempt(ip, "limits_testrunner", limits));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 34));
+        record_failure(ip, limits);
+        assert!(!is_locked_out(ip));
+        record_failure(ip, limits);
+        assert!(is_locked_out(ip));
+        assert!(!allow_attempt(ip, "limits_testrunner", limits));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 35));
+        assert!(allow_join(ip, limits));
+        assert!(!allow_join(ip, limits));
+    }
+}
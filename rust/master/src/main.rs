@@ -3,11 +3,31 @@ use sustenet_shared as shared;
 use sustenet_master::{ LOGGER, start_with_config };
 
 use tokio::select;
+use tokio::sync::mpsc::Sender;
 
+use shared::ids::Handle;
 use shared::utils;
 
 pub mod security;
 
+struct DefaultPlugin {}
+impl shared::MasterPlugin for DefaultPlugin {
+    fn receive(
+        &self,
+        _tx: Sender<Box<[u8]>>,
+        connection: Handle,
+        _cluster: Option<u32>,
+        command: u8,
+        _reader: &mut shared::network::LinkReader<'_>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        Box::pin(async move {
+            LOGGER.warning(format!("Client#{connection} sent an unknown command: {command}").as_str());
+        })
+    }
+
+    fn info(&self, _: &str) {}
+}
+
 #[tokio::main]
 async fn main() {
     let mut shutdown_rx = utils::shutdown_channel().expect("Error creating shutdown channel.");
@@ -16,7 +36,7 @@ async fn main() {
         _ = shutdown_rx.recv() => {
             LOGGER.warning("Shutting down...");
         }
-        _ = start_with_config() => {}
+        _ = start_with_config(DefaultPlugin {}) => {}
     }
 
     LOGGER.success("The Master Server has been shut down.");
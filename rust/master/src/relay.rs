@@ -0,0 +1,62 @@
+use sustenet_shared as shared;
+
+use tokio::io::{ AsyncRead, AsyncReadExt };
+
+use shared::network::read_string;
+use shared::packets::master::ToUnknown;
+
+use crate::{ CLUSTER_IDS, ClusterInfo, LOGGER };
+
+/// Reads a relayed message. It's prefixed by its length as a u16.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut message = vec![0u8; len];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Reads the tags a cluster registered with. It's a u8 count followed by
+/// u8 length-prefixed strings.
+pub async fn read_tags<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<String>> {
+    let count = reader.read_u8().await?;
+    let mut tags = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        tags.push(read_string(reader).await?);
+    }
+    Ok(tags)
+}
+
+/// Sends a message from one cluster to every other cluster that matches the filter.
+/// Returns how many clusters it was sent to.
+pub(crate) async fn relay<F>(from: u32, message: &[u8], filter: F) -> usize
+    where F: Fn(&ClusterInfo) -> bool
+{
+    let mut data = vec![ToUnknown::ClusterMessage as u8];
+    data.extend_from_slice(&from.to_be_bytes());
+    data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    data.extend_from_slice(message);
+    let data = data.into_boxed_slice();
+
+    // Clone the senders so the lock isn't held while waiting on a full channel.
+    let senders = {
+        let cluster_ids = CLUSTER_IDS.read().await;
+        (*cluster_ids)
+            .iter()
+            .filter(|cluster| cluster.id != from && filter(cluster))
+            .map(|cluster| (cluster.id, cluster.sender.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut sent = 0;
+    for (id, sender) in senders {
+        match sender.send(data.clone()).await {
+            Ok(_) => {
+                sent += 1;
+            }
+            Err(e) => {
+                LOGGER.error(format!("Failed to relay a message to Cluster#{id}: {:?}", e).as_str());
+            }
+        }
+    }
+    sent
+}
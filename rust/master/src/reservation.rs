@@ -0,0 +1,164 @@
+use sustenet_shared as shared;
+
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{ Duration, Instant };
+
+use dashmap::DashMap;
+
+use shared::config::master::Settings;
+use shared::ids::Handle;
+
+use crate::directory;
+
+/// A slot held on a cluster for a player that was routed to it but hasn't arrived yet.
+struct Reservation {
+    cluster_id: u32,
+    /// The connection that asked for it and its IP, so one of them can't hold every slot.
+    connection: Handle,
+    ip: Option<IpAddr>,
+    expires_at: Instant,
+}
+
+/// How many reservations a connection and an IP can hold at once. 0 means no limit.
+#[derive(Clone, Copy)]
+pub struct Caps {
+    pub per_connection: u32,
+    pub per_ip: u32,
+}
+
+impl From<&Settings> for Caps {
+    fn from(settings: &Settings) -> Self {
+        Caps {
+            per_connection: settings.reservations_per_connection,
+            per_ip: settings.reservations_per_ip,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReserveError {
+    /// The cluster doesn't have room.
+    Full,
+    /// The connection or its IP already holds as many reservations as it can.
+    TooMany,
+}
+
+/// Slots held on clusters, keyed by the player ID from the player's token.
+/// A player can only hold one at a time.
+static RESERVATIONS: LazyLock<DashMap<String, Reservation>> = LazyLock::new(DashMap::new);
+
+/// Holds a slot on the cluster for the player if it has room. Players on the
+/// cluster and other reservations count against its `max_connections`.
+/// A `max_connections` of 0 means the cluster has no limit.
+pub fn reserve(
+    cluster_id: u32,
+    max_connections: u32,
+    player_id: &str,
+    connection: Handle,
+    ip: Option<IpAddr>,
+    caps: Caps,
+    timeout: Duration
+) -> Result<(), ReserveError> {
+    release_expired();
+
+    // The player's old reservation is replaced, so it shouldn't take up a slot.
+    RESERVATIONS.remove(player_id);
+
+    let over = |held: usize, cap: u32| cap != 0 && held >= (cap as usize);
+    let by_connection = RESERVATIONS.iter()
+        .filter(|reservation| reservation.connection == connection)
+        .count();
+    let by_ip = match ip {
+        Some(ip) => RESERVATIONS.iter()
+            .filter(|reservation| reservation.ip == Some(ip))
+            .count(),
+        None => 0,
+    };
+    if over(by_connection, caps.per_connection) || over(by_ip, caps.per_ip) {
+        return Err(ReserveError::TooMany);
+    }
+
+    if max_connections != 0 && used_slots(cluster_id) >= (max_connections as usize) {
+        return Err(ReserveError::Full);
+    }
+
+    RESERVATIONS.insert(player_id.to_string(), Reservation {
+        cluster_id,
+        connection,
+        ip,
+        expires_at: Instant::now() + timeout,
+    });
+    Ok(())
+}
+
+/// Called when the player arrives on the cluster. Their slot is now counted
+/// by the player directory instead.
+pub fn confirm(cluster_id: u32, player_id: &str) {
+    RESERVATIONS.remove_if(player_id, |_, reservation| reservation.cluster_id == cluster_id);
+}
+
+/// How many slots are held on the cluster for players that haven't arrived yet.
+pub fn reserved_count(cluster_id: u32) -> usize {
+    release_expired();
+    RESERVATIONS.iter()
+        .filter(|reservation| reservation.cluster_id == cluster_id)
+        .count()
+}
+
+/// How many slots are taken on the cluster, including reservations.
+pub fn used_slots(cluster_id: u32) -> usize {
+    directory::player_count(cluster_id) + reserved_count(cluster_id)
+}
+
+/// Drops every reservation for a cluster that disconnected.
+pub(crate) fn remove_cluster(cluster_id: u32) {
+    RESERVATIONS.retain(|_, reservation| reservation.cluster_id != cluster_id);
+}
+
+/// Releases the slots of players that never arrived.
+pub fn release_expired() {
+    let now = Instant::now();
+    RESERVATIONS.retain(|_, reservation| reservation.expires_at > now);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    pub fn test_reservations_count_against_capacity() {
+        let timeout = Duration::from_secs(30);
+        let caps = Caps { per_connection: 0, per_ip: 0 };
+        assert_eq!(reserve(100, 2, "reservation_testrunner1", Handle::from_u64(1000), None, caps, timeout), Ok(()));
+        assert_eq!(reserve(100, 2, "reservation_testrunner2", Handle::from_u64(1001), None, caps, timeout), Ok(()));
+        assert_eq!(reserve(100, 2, "reservation_testrunner3", Handle::from_u64(1002), None, caps, timeout), Err(ReserveError::Full));
+
+        // Reserving again replaces the old reservation instead of taking another slot.
+        assert_eq!(reserve(100, 2, "reservation_testrunner2", Handle::from_u64(1001), None, caps, timeout), Ok(()));
+
+        confirm(100, "reservation_testrunner1");
+        assert_eq!(reserved_count(100), 1);
+
+        assert_eq!(reserve(101, 1, "reservation_testrunner4", Handle::from_u64(1003), None, caps, Duration::ZERO), Ok(()));
+        assert_eq!(reserved_count(101), 0);
+    }
+
+    #[test]
+    pub fn test_reservations_are_capped() {
+        let timeout = Duration::from_secs(30);
+        let caps = Caps { per_connection: 1, per_ip: 2 };
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 40)));
+
+        assert_eq!(reserve(102, 0, "reservation_testrunner5", Handle::from_u64(1004), ip, caps, timeout), Ok(()));
+        // The same connection can't hold a second one, but can replace its own.
+        assert_eq!(reserve(102, 0, "reservation_testrunner6", Handle::from_u64(1004), ip, caps, timeout), Err(ReserveError::TooMany));
+        assert_eq!(reserve(102, 0, "reservation_testrunner5", Handle::from_u64(1004), ip, caps, timeout), Ok(()));
+
+        assert_eq!(reserve(102, 0, "reservation_testrunner6", Handle::from_u64(1005), ip, caps, timeout), Ok(()));
+        assert_eq!(reserve(102, 0, "reservation_testrunner7", Handle::from_u64(1006), ip, caps, timeout), Err(ReserveError::TooMany));
+        assert_eq!(reserved_count(102), 2);
+    }
+}
@@ -1,18 +1,64 @@
 use sustenet_shared as shared;
 
-use shared::security::aes::{ load_all_keys, KeyMap };
+use std::sync::RwLock;
+
+use shared::security::aes::{ Aes256Gcm, Key, KeyMap, load_readable_keys };
+
+use crate::LOGGER;
 
 lazy_static::lazy_static! {
-    pub static ref AES_KEYS: KeyMap = match load_all_keys() {
+    static ref AES_KEYS: RwLock<KeyMap> = RwLock::new(match load_keys() {
         Ok(keys) => keys,
         Err(e) => {
             println!("Failed to load keys: {:?}", e);
             KeyMap::new()
         }
-    };
+    });
+}
+
+/// Returns a version of the key if it's loaded and still accepted. See `KeyRing::accepts`.
+pub fn get_key(name: &str, version: u32) -> Option<Key<Aes256Gcm>> {
+    AES_KEYS.read()
+        .expect("The keys lock was poisoned.")
+        .get(name)
+        .and_then(|ring| ring.accepts(version))
+        .map(|key| key.key)
+}
+
+/// Reloads the keys from the `keys` directory. Returns the key versions that
+/// weren't loaded before, as `name.version`. If the directory can't be read,
+/// the loaded keys are kept.
+pub fn reload_keys() -> std::io::Result<Vec<String>> {
+    let keys = load_keys()?;
+    Ok(replace_keys(keys))
+}
+
+/// Loads the keys that can be read. The files that can't are logged, so one
+/// bad file doesn't stop every cluster from connecting.
+fn load_keys() -> std::io::Result<KeyMap> {
+    let (keys, skipped) = load_readable_keys()?;
+    for (file_name, e) in skipped {
+        LOGGER.warning(format!("Skipped the key file '{file_name}': {e}").as_str());
+    }
+    Ok(keys)
+}
+
+fn replace_keys(keys: KeyMap) -> Vec<String> {
+    let mut current = AES_KEYS.write().expect("The keys lock was poisoned.");
+
+    let mut added = keys
+        .iter()
+        .flat_map(|(name, ring)| ring.versions().iter().map(move |key| (name, key)))
+        .filter(|(name, key)| current.get(*name).and_then(|ring| ring.get(key.version)) != Some(*key))
+        .map(|(name, key)| format!("{name}.{}", key.version))
+        .collect::<Vec<_>>();
+    added.sort();
+
+    *current = keys;
+    added
 }
 
-const PASSWORD_LEN: usize = 20;
+pub const PASSWORD_LEN: usize = 20;
 pub fn generate_passphrase() -> [u8; PASSWORD_LEN] {
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                             abcdefghijklmnopqrstuvwxyz\
@@ -27,3 +73,33 @@ pub fn generate_passphrase() -> [u8; PASSWORD_LEN] {
 
     password
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use shared::security::aes::{ KeyRing, KeyVersion, generate_key };
+
+    #[test]
+    pub fn test_replace_keys() {
+        let mut ring = KeyRing::default();
+        ring.insert(KeyVersion::new(0, generate_key()));
+        let mut keys = KeyMap::new();
+        keys.insert("security_testrunner".to_string(), ring.clone());
+        assert_eq!(replace_keys(keys), vec!["security_testrunner.0".to_string()]);
+
+        ring.insert(KeyVersion::new(1, generate_key()));
+        let mut keys = KeyMap::new();
+        keys.insert("security_testrunner".to_string(), ring.clone());
+        assert_eq!(replace_keys(keys), vec!["security_testrunner.1".to_string()]);
+        assert!(get_key("security_testrunner", 0).is_some());
+
+        // Once there's a newer version, the oldest one is no longer accepted.
+        ring.insert(KeyVersion::new(2, generate_key()));
+        let mut keys = KeyMap::new();
+        keys.insert("security_testrunner".to_string(), ring);
+        replace_keys(keys);
+        assert!(get_key("security_testrunner", 0).is_none());
+        assert!(get_key("security_testrunner", 1).is_some());
+    }
+}
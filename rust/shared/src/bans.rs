@@ -0,0 +1,389 @@
+//! Bans by account ID and by IP or CIDR range.
+//!
+//! Bans are kept in a text file with one ban per line, so they can be edited by
+//! hand or with `sustenet-bans`. Each server checks the file again whenever it
+//! changes.
+//!
+//! ```text
+//! # target expires_at reason
+//! ip 203.0.113.7 - Spamming the lobby
+//! ip 198.51.100.0/24 1767225600 Botnet
+//! account 42 1767225600 Cheating
+//! ```
+//!
+//! `expires_at` is Unix time in seconds, or `-` for a ban that never expires.
+
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::{ LazyLock, Mutex, OnceLock, RwLock };
+use std::time::SystemTime;
+
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWriteExt };
+use tokio::net::TcpStream;
+
+use crate::logging::{ LogType, Logger };
+use crate::network::read_string;
+use crate::utils::{ lock_file, unix_time, write_atomic };
+
+/// An IP address or a range of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let addr = addr.to_canonical();
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (prefix <= bits).then_some(IpNet { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - (self.prefix as u32)).unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(ip) & mask)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - (self.prefix as u32)).unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(ip) & mask)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = ();
+
+    /// Parses `203.0.113.7` or `198.51.100.0/24`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().map_err(|_| ())?, Some(prefix.parse::<u8>().map_err(|_| ())?)),
+            None => (s.parse::<IpAddr>().map_err(|_| ())?, None),
+        };
+        let bits = match addr.to_canonical() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpNet::new(addr, prefix.unwrap_or(bits)).ok_or(())
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.addr, self.prefix) {
+            (IpAddr::V4(addr), 32) => write!(f, "{addr}"),
+            (IpAddr::V6(addr), 128) => write!(f, "{addr}"),
+            (addr, prefix) => write!(f, "{addr}/{prefix}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Account(u64),
+    Ip(IpNet),
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BanTarget::Account(id) => write!(f, "account {id}"),
+            BanTarget::Ip(net) => write!(f, "ip {net}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Unix time in seconds. `None` means it never expires.
+    pub expires_at: Option<u64>,
+    /// Shown to whoever's banned.
+    pub reason: String,
+}
+
+impl Ban {
+    pub fn is_active_at(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    /// The packet sent before a banned connection is closed. It's the command,
+    /// the expiry as a u64 that's 0 if it never expires, and the reason as a
+    /// u8 length-prefixed string.
+    pub fn to_packet(&self, command: u8) -> Vec<u8> {
+        let mut reason = self.reason.as_str();
+        while reason.len() > (u8::MAX as usize) {
+            let mut end = u8::MAX as usize;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason = &reason[..end];
+        }
+
+        let mut data = vec![command];
+        data.extend_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        data.push(reason.len() as u8);
+        data.extend_from_slice(reason.as_bytes());
+        data
+    }
+
+    fn parse_line(line: &str) -> Result<Self, String> {
+        let mut parts = line.splitn(4, char::is_whitespace);
+        let (Some(kind), Some(target), Some(expires_at)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("A ban needs a type, a target, and an expiry.".to_string());
+        };
+        let target = match kind {
+            "account" => BanTarget::Account(target.parse().map_err(|_| format!("{target} isn't an account ID."))?),
+            "ip" => BanTarget::Ip(target.parse().map_err(|_| format!("{target} isn't an IP or CIDR range."))?),
+            _ => {
+                return Err(format!("{kind} isn't a ban type. It has to be account or ip."));
+            }
+        };
+        let expires_at = match expires_at {
+            "-" => None,
+            time => Some(time.parse().map_err(|_| format!("{time} isn't a Unix time or -."))?),
+        };
+        Ok(Ban { target, expires_at, reason: parts.next().unwrap_or_default().trim().to_string() })
+    }
+}
+
+impl std::fmt::Display for Ban {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Each ban has to stay on one line.
+        let reason = self.reason.replace(['\r', '\n'], " ");
+        match self.expires_at {
+            Some(expires_at) => write!(f, "{} {expires_at} {reason}", self.target),
+            None => write!(f, "{} - {reason}", self.target),
+        }
+    }
+}
+
+/// Reads the packet made by `Ban::to_packet`, after the command. Returns the expiry and the reason.
+pub async fn read_ban<R>(reader: &mut R) -> std::io::Result<(Option<u64>, String)> where R: AsyncRead + Unpin {
+    let expires_at = reader.read_u64().await?;
+    let reason = read_string(reader).await?;
+    Ok(((expires_at != 0).then_some(expires_at), reason))
+}
+
+/// Tells a connection that was just accepted why it's banned, then closes it.
+/// It's sent unsealed since there isn't a session yet.
+pub async fn refuse(mut stream: TcpStream, ban: Ban, command: u8) {
+    let _ = stream.write_all(&ban.to_packet(command)).await;
+    let _ = stream.shutdown().await;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BanList {
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Parses a ban file. Blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut bans = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            bans.push(Ban::parse_line(line).map_err(|e| format!("Line {}: {e}", number + 1))?);
+        }
+        Ok(BanList { bans })
+    }
+
+    /// A file that doesn't exist is an empty list.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => BanList::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BanList::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut text = "# target expires_at reason\n".to_string();
+        for ban in self.bans.iter() {
+            text.push_str(&format!("{ban}\n"));
+        }
+        write_atomic(path, text.as_bytes())
+    }
+
+    /// Loads the list, changes it, and saves it while holding a lock on `<path>.lock`.
+    /// Servers and `sustenet-bans` both write the file, so neither can
+    /// overwrite a ban the other just added.
+    pub fn update<T>(path: &Path, change: impl FnOnce(&mut BanList) -> T) -> Result<T, String> {
+        let lock = lock_file(path).map_err(|e| format!("Failed to lock the bans: {e}"))?;
+        let mut list = BanList::load(path)?;
+        let result = change(&mut list);
+        list.save(path).map_err(|e| e.to_string())?;
+        drop(lock);
+        Ok(result)
+    }
+
+    pub fn bans(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    /// Adds the ban, replacing any ban on the same target.
+    pub fn add(&mut self, ban: Ban) {
+        self.remove(&ban.target);
+        self.bans.push(ban);
+    }
+
+    /// Returns `false` if the target wasn't banned.
+    pub fn remove(&mut self, target: &BanTarget) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        self.bans.len() != len
+    }
+
+    /// Drops the bans that have expired.
+    pub fn prune(&mut self, now: u64) {
+        self.bans.retain(|ban| ban.is_active_at(now));
+    }
+
+    pub fn ip_at(&self, ip: IpAddr, now: u64) -> Option<&Ban> {
+        self.bans.iter().find(|ban| {
+            matches!(ban.target, BanTarget::Ip(net) if net.contains(ip)) && ban.is_active_at(now)
+        })
+    }
+
+    pub fn account_at(&self, id: u64, now: u64) -> Option<&Ban> {
+        self.bans.iter().find(|ban| ban.target == BanTarget::Account(id) && ban.is_active_at(now))
+    }
+}
+
+/// The ban file a server checks. It's loaded again when its modified time changes.
+struct BanFile {
+    path: PathBuf,
+    list: RwLock<BanList>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+static BAN_FILE: OnceLock<BanFile> = OnceLock::new();
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::System));
+
+/// Sets the file the server's bans are read from. Only the first call does anything.
+pub fn set_ban_file(path: impl Into<PathBuf>) -> Result<(), String> {
+    let path = path.into();
+    let list = BanList::load(&path)?;
+    let modified = modified(&path);
+    let _ = BAN_FILE.set(BanFile { path, list: RwLock::new(list), modified: Mutex::new(modified) });
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads the ban file if it changed. A file that fails to parse keeps the old bans.
+fn bans() -> Option<&'static BanFile> {
+    let file = BAN_FILE.get()?;
+    let modified = modified(&file.path);
+    let mut last = file.modified.lock().expect("The ban file lock was poisoned.");
+    if *last != modified {
+        *last = modified;
+        match BanList::load(&file.path) {
+            Ok(list) => {
+                *file.list.write().expect("The ban list lock was poisoned.") = list;
+            }
+            Err(e) => LOGGER.error(format!("Failed to reload the bans at '{}': {e}", file.path.display()).as_str()),
+        }
+    }
+    Some(file)
+}
+
+/// The ban on the IP, if there is one.
+pub fn check_ip(ip: IpAddr) -> Option<Ban> {
+    bans()?.list.read().expect("The ban list lock was poisoned.").ip_at(ip, unix_time()).cloned()
+}
+
+/// The ban on the account, if there is one.
+pub fn check_account(id: u64) -> Option<Ban> {
+    bans()?.list.read().expect("The ban list lock was poisoned.").account_at(id, unix_time()).cloned()
+}
+
+/// Adds a ban and saves it to the ban file.
+pub fn ban(ban: Ban) -> Result<(), String> {
+    edit(|list| list.add(ban))
+}
+
+/// Removes the ban on the target from the ban file. Returns `false` if it wasn't banned.
+pub fn unban(target: &BanTarget) -> Result<bool, String> {
+    let mut removed = false;
+    edit(|list| {
+        removed = list.remove(target);
+    })?;
+    Ok(removed)
+}
+
+/// Changes the file with `BanList::update`, so bans added elsewhere since it was
+/// last loaded are kept, and then uses what was saved.
+fn edit(change: impl FnOnce(&mut BanList)) -> Result<(), String> {
+    let file = BAN_FILE.get().ok_or("The ban file isn't set.".to_string())?;
+    let mut last = file.modified.lock().expect("The ban file lock was poisoned.");
+    let list = BanList::update(&file.path, |list| {
+        change(list);
+        list.clone()
+    })?;
+    *file.list.write().expect("The ban list lock was poisoned.") = list;
+    *last = modified(&file.path);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_ban_list() {
+        let mut list = BanList::parse(
+            "# target expires_at reason
+            ip 203.0.113.7 - Spamming the lobby
+            ip 198.51.100.0/24 2000 Botnet
+            ip 2001:db8::/32 - Bad range
+            account 42 2000 Cheating"
+        ).unwrap();
+
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(list.ip_at(ip("203.0.113.7"), 1000).unwrap().reason, "Spamming the lobby");
+        assert!(list.ip_at(ip("203.0.113.8"), 1000).is_none());
+        assert!(list.ip_at(ip("198.51.100.200"), 1000).is_some());
+        // IPv4 clients on a dual-stack socket show up as mapped IPv6 addresses.
+        assert!(list.ip_at(ip("::ffff:198.51.100.9"), 1000).is_some());
+        assert!(list.ip_at(ip("198.51.100.200"), 2000).is_none());
+        assert!(list.ip_at(ip("2001:db8:1::1"), 1000).is_some());
+        assert_eq!(list.account_at(42, 1999).unwrap().reason, "Cheating");
+        assert!(list.account_at(42, 2000).is_none());
+
+        assert!(list.remove(&BanTarget::Account(42)));
+        list.prune(2000);
+        assert_eq!(BanList::parse(&list.bans().iter().map(|ban| ban.to_string()).collect::<Vec<_>>().join("\n")).unwrap(), list);
+
+        assert!(BanList::parse("ip 203.0.113.0/33 - Too wide").is_err());
+        assert!(BanList::parse("player 42 - Not a type").is_err());
+    }
+
+    #[test]
+    pub fn test_update_keeps_other_bans() {
+        let path = std::env::temp_dir().join(format!("bans_testrunner_{}.txt", std::process::id()));
+        let first = Ban { target: BanTarget::Account(1), expires_at: None, reason: "Cheating".to_string() };
+        let second = Ban { target: BanTarget::Account(2), expires_at: None, reason: "Spamming".to_string() };
+        BanList::update(&path, |list| list.add(first.clone())).unwrap();
+        // Another writer's ban is read back before this one is added.
+        BanList::update(&path, |list| list.add(second.clone())).unwrap();
+        assert_eq!(BanList::load(&path).unwrap().bans(), &[first, second]);
+        assert!(!std::fs::exists(path.with_extension("txt.tmp")).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("txt.lock"));
+    }
+}
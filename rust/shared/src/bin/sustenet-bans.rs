@@ -0,0 +1,122 @@
+//! Manages the ban list and revokes tokens.
+//!
+//! Reads `bans_file` and `revocations_file` from *Config.toml* like the servers
+//! do. The servers reload the files when they change, so bans made here apply
+//! without a restart. Banning an account also revokes its tokens, so it's
+//! disconnected from every cluster.
+
+use std::path::{ Path, PathBuf };
+
+use sustenet_shared as shared;
+
+use shared::bans::{ Ban, BanList, BanTarget };
+use shared::config::keys::read;
+use shared::revocations::{ Revocation, RevocationList };
+use shared::utils::unix_time;
+
+const USAGE: &str = "Usage: sustenet-bans [--file <bans_file>] <command>
+
+Commands:
+    add account|ip <target> [--for <seconds>] [reason...]
+                                        Bans an account ID or an IP/CIDR range. Without --for, it never expires.
+    remove account|ip <target>          Lifts a ban.
+    revoke <account>                    Revokes the account's tokens without banning it.
+    list                                Lists the bans that are still active.
+    prune                               Removes the bans that expired.";
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let settings = read();
+    let mut path = PathBuf::from(settings.bans_file);
+    if args.first().is_some_and(|arg| arg == "--file") {
+        if args.len() < 2 {
+            fail(USAGE);
+        }
+        path = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+
+    let now = unix_time();
+
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["add", kind, target, rest @ ..] => {
+            let target = parse_target(kind, target);
+            let (expires_at, reason) = match rest {
+                ["--for", seconds, reason @ ..] => {
+                    let seconds = seconds
+                        .parse::<u64>()
+                        .unwrap_or_else(|_| fail("--for needs a number of seconds."));
+                    (Some(now + seconds), reason)
+                }
+                reason => (None, reason),
+            };
+            let ban = Ban { target, expires_at, reason: reason.join(" ") };
+            // The ban is saved before the tokens are revoked, so it isn't lost if revoking fails.
+            edit(&path, |list| list.add(ban.clone()));
+            println!("added {ban}");
+            if let BanTarget::Account(id) = target {
+                revoke(&settings.revocations_file, id, settings.token_ttl, settings.refresh_ttl);
+            }
+        }
+        ["remove", kind, target] => {
+            let target = parse_target(kind, target);
+            if !edit(&path, |list| list.remove(&target)) {
+                fail(&format!("{target} isn't banned."));
+            }
+            println!("removed {target}");
+        }
+        ["revoke", id] => {
+            let id = id.parse().unwrap_or_else(|_| fail(&format!("{id} isn't an account ID.")));
+            revoke(&settings.revocations_file, id, settings.token_ttl, settings.refresh_ttl);
+        }
+        ["list"] => {
+            let list = BanList::load(&path).unwrap_or_else(|e| fail(&format!("Failed to load the bans: {e}")));
+            for ban in list.bans().iter().filter(|ban| ban.is_active_at(now)) {
+                println!("{ban}");
+            }
+        }
+        ["prune"] => {
+            let pruned = edit(&path, |list| {
+                let before = list.bans().len();
+                list.prune(now);
+                before - list.bans().len()
+            });
+            println!("pruned {pruned}");
+        }
+        _ => fail(USAGE),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+/// Changes the ban file under its lock, so a server banning someone at the same time isn't overwritten.
+fn edit<T>(path: &Path, change: impl FnOnce(&mut BanList) -> T) -> T {
+    BanList::update(path, change).unwrap_or_else(|e| fail(&format!("Failed to save the bans to {}: {e}", path.display())))
+}
+
+/// Revokes every token the account has now.
+fn revoke(path: &str, id: u64, token_ttl: u64, refresh_ttl: u64) {
+    let path = PathBuf::from(path);
+    let revocation = Revocation::now(id, token_ttl, refresh_ttl);
+    let saved = RevocationList::update(&path, |list| {
+        list.prune(revocation.revoked_at);
+        list.add(revocation);
+    });
+    if let Err(e) = saved {
+        fail(&format!("Failed to save the revocations to {}: {e}", path.display()));
+    }
+    println!("revoked {revocation}");
+}
+
+fn parse_target(kind: &str, target: &str) -> BanTarget {
+    match kind {
+        "account" => BanTarget::Account(target.parse().unwrap_or_else(|_| fail(&format!("{target} isn't an account ID.")))),
+        "ip" => BanTarget::Ip(target.parse().unwrap_or_else(|_| fail(&format!("{target} isn't an IP or CIDR range.")))),
+        _ => fail(USAGE),
+    }
+}
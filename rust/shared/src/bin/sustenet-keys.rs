@@ -0,0 +1,368 @@
+//! Manages the cluster keys in the key store.
+//!
+//! Reads `keys_dir` and the key passphrase from *Config.toml* like the servers
+//! do, so it works on the same files. Every change is printed on one line so
+//! provisioning scripts can log what they did.
+
+use sustenet_shared as shared;
+
+use shared::config::keys::read;
+use shared::security::aes::{
+    KeyRing,
+    KeyStore,
+    KeyVersion,
+    fingerprint,
+    generate_key,
+    key_store,
+    load_all_keys,
+    load_key_ring,
+    parse_key_file_name,
+    save_key_version,
+    set_key_store,
+    update_key_version,
+};
+use shared::security::base64engine::{ base64_decode, base64_encode };
+use shared::utils::unix_time;
+
+const USAGE: &str = "Usage: sustenet-keys [--dir <keys_dir>] <command>
+
+Commands:
+    generate <name>                     Makes version 0 of a new key.
+    list                                Lists every version of every key.
+    fingerprint <name>[.version]        Prints a version's fingerprint. Defaults to the current version.
+    export <name>[.version]             Prints a version as URL-safe base64. Defaults to the current version.
+    import <name>.<version> <base64>    Saves a version printed by `export`.
+    rotate <name> [--delay <seconds>] [--overlap <seconds>]
+                                        Adds a version that's valid after the delay. The current
+                                        version stays valid for the overlap after that. (0, 3600)
+    revoke <name>[.version]             Expires the version now. Without a version, expires all of them.";
+
+/// How long the old version is still accepted after a rotation by default, in seconds.
+const DEFAULT_OVERLAP: u64 = 3600;
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let settings = read();
+    let mut keys_dir = settings.keys_dir;
+    if args.first().is_some_and(|arg| arg == "--dir") {
+        if args.len() < 2 {
+            fail(USAGE);
+        }
+        keys_dir = args.remove(1);
+        args.remove(0);
+    }
+
+    let mut store = KeyStore::new(keys_dir.as_str());
+    if let Some(passphrase) = settings.key_passphrase {
+        store = store
+            .with_passphrase(&passphrase)
+            .unwrap_or_else(|e| fail(&format!("Failed to open the key store: {e}")));
+    }
+    set_key_store(store);
+
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{line}");
+            }
+        }
+        Err(e) => fail(&e),
+    }
+}
+
+/// Runs a command and returns the lines it prints.
+fn run(args: &[&str]) -> Result<Vec<String>, String> {
+    match args {
+        ["generate", name] => generate(name),
+        ["list"] => list(),
+        ["fingerprint", spec] => {
+            let (name, key) = find(spec)?;
+            Ok(vec![format!("{name}.{} {}", key.version, fingerprint(&key.key))])
+        }
+        ["export", spec] => {
+            let (_, key) = find(spec)?;
+            Ok(vec![base64_encode(&key.to_bytes())])
+        }
+        ["import", spec, data] => import(spec, data),
+        ["rotate", name, options @ ..] => {
+            let delay = option(options, "--delay")?.unwrap_or(0);
+            let overlap = option(options, "--overlap")?.unwrap_or(DEFAULT_OVERLAP);
+            rotate(name, delay, overlap)
+        }
+        ["revoke", spec] => revoke(spec),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+/// Splits `name.version` into the name and the version, if there is one.
+fn parse_spec(spec: &str) -> (String, Option<u32>) {
+    match parse_key_file_name(spec) {
+        (name, _) if name == spec => (name, None),
+        (name, version) => (name, Some(version)),
+    }
+}
+
+/// Reads `--option <seconds>` from the options.
+fn option(options: &[&str], name: &str) -> Result<Option<u64>, String> {
+    let Some(index) = options.iter().position(|option| *option == name) else {
+        return Ok(None);
+    };
+    match options.get(index + 1).map(|value| value.parse::<u64>()) {
+        Some(Ok(value)) => Ok(Some(value)),
+        _ => Err(format!("{name} needs a number of seconds.")),
+    }
+}
+
+fn ring(name: &str) -> Result<KeyRing, String> {
+    load_key_ring(name).map_err(|e| format!("Failed to load the key {name}: {e}"))
+}
+
+/// The version in the spec, or the current one if it doesn't have one.
+fn find(spec: &str) -> Result<(String, KeyVersion), String> {
+    let (name, version) = parse_spec(spec);
+    let ring = ring(&name)?;
+    let key = match version {
+        Some(version) => ring.get(version),
+        None => ring.current(),
+    };
+    match key {
+        Some(key) => Ok((name, key.clone())),
+        None => Err(format!("{spec} doesn't have that version, or none of its versions are valid.")),
+    }
+}
+
+fn save(name: &str, key: &KeyVersion) -> Result<(), String> {
+    save_key_version(name, key).map_err(|e| format!("Failed to save {name}.{}: {e}", key.version))
+}
+
+fn update(name: &str, key: &KeyVersion) -> Result<(), String> {
+    update_key_version(name, key).map_err(|e| format!("Failed to update {name}.{}: {e}", key.version))
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    let valid =
+        !name.is_empty() &&
+        name.len() <= (u8::MAX as usize) &&
+        !name.starts_with('.') &&
+        !name.contains(['/', '\\']) &&
+        parse_spec(name).1.is_none();
+    match valid {
+        true => Ok(()),
+        false =>
+            Err(
+                format!(
+                    "{name} can't be used as a key name. It has to be 1 to 255 bytes, can't start with a dot or have slashes, and can't end with a version."
+                )
+            ),
+    }
+}
+
+/// Whether any version of the key has a file, even one that can't be read.
+fn has_files(name: &str) -> Result<bool, String> {
+    let dir = key_store().dir().to_path_buf();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(false);
+        }
+        Err(e) => {
+            return Err(format!("Failed to read '{}': {e}", dir.display()));
+        }
+    };
+    Ok(
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().map(|file_name| parse_key_file_name(file_name).0))
+            .any(|file_name| file_name == name)
+    )
+}
+
+fn generate(name: &str) -> Result<Vec<String>, String> {
+    check_name(name)?;
+    if has_files(name)? {
+        return Err(format!("{name} already exists. Use `rotate` to replace it."));
+    }
+
+    let key = KeyVersion::new(0, generate_key());
+    save(name, &key)?;
+    Ok(vec![format!("Generated {name}.0 {}", fingerprint(&key.key))])
+}
+
+fn list() -> Result<Vec<String>, String> {
+    let keys = load_all_keys().map_err(|e| format!("Failed to load the keys: {e}"))?;
+    let mut names = keys.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let now = unix_time();
+    let limit = |time: Option<u64>| time.map_or("-".to_string(), |time| time.to_string());
+    let mut lines = Vec::new();
+    for name in names {
+        let ring = &keys[name];
+        let current = ring.current_at(now).map(|key| key.version);
+        for key in ring.versions() {
+            let status = match key {
+                _ if Some(key.version) == current => "current",
+                _ if ring.accepts_at(key.version, now).is_some() => "accepted",
+                KeyVersion { not_after: Some(not_after), .. } if *not_after < now => "expired",
+                KeyVersion { not_before: Some(not_before), .. } if *not_before > now => "pending",
+                // Valid, but more than one version behind.
+                _ => "retired",
+            };
+            lines.push(
+                format!(
+                    "{name}.{} {} {status} not_before={} not_after={}",
+                    key.version,
+                    fingerprint(&key.key),
+                    limit(key.not_before),
+                    limit(key.not_after)
+                )
+            );
+        }
+    }
+    Ok(lines)
+}
+
+fn import(spec: &str, data: &str) -> Result<Vec<String>, String> {
+    let (name, Some(version)) = parse_spec(spec) else {
+        return Err("Importing needs the version, like cluster_key.0.".to_string());
+    };
+    check_name(&name)?;
+    let key = base64_decode(data)
+        .map_err(|e| e.to_string())
+        .and_then(|data| KeyVersion::from_bytes(version, &data).map_err(|e| e.to_string()))
+        .map_err(|e| format!("That isn't an exported key: {e}"))?;
+
+    // Importing the same key twice is fine, so scripts can run again.
+    if let Ok(ring) = load_key_ring(&name) && let Some(existing) = ring.get(version) {
+        if *existing == key {
+            return Ok(vec![format!("Already imported {name}.{version} {}", fingerprint(&key.key))]);
+        }
+        return Err(format!("{name}.{version} already exists with a different key. Revoke it and rotate instead."));
+    }
+
+    save(&name, &key)?;
+    Ok(vec![format!("Imported {name}.{version} {}", fingerprint(&key.key))])
+}
+
+fn rotate(name: &str, delay: u64, overlap: u64) -> Result<Vec<String>, String> {
+    let ring = ring(name)?;
+    let now = unix_time();
+    let starts = now + delay;
+    let mut lines = Vec::new();
+
+    let version = ring.versions().last().map_or(0, |key| key.version + 1);
+    let key = KeyVersion {
+        not_before: if delay > 0 { Some(starts) } else { None },
+        ..KeyVersion::new(version, generate_key())
+    };
+
+    // The old version is still accepted for the overlap, unless it already expires before then.
+    if let Some(current) = ring.current_at(now) {
+        let not_after = starts + overlap;
+        if current.not_after.is_none_or(|time| time > not_after) {
+            let current = KeyVersion { not_after: Some(not_after), ..current.clone() };
+            update(name, &current)?;
+            lines.push(format!("Expiring {name}.{} at {not_after}", current.version));
+        }
+    }
+
+    save(name, &key)?;
+    lines.push(format!("Rotated {name} to {name}.{version} {} starting at {starts}", fingerprint(&key.key)));
+    Ok(lines)
+}
+
+fn revoke(spec: &str) -> Result<Vec<String>, String> {
+    let (name, version) = parse_spec(spec);
+    let ring = ring(&name)?;
+    // `not_after` is inclusive and 0 means no limit, so it has to be in the past.
+    let not_after = unix_time().saturating_sub(1).max(1);
+
+    let versions = ring
+        .versions()
+        .iter()
+        .filter(|key| version.is_none_or(|version| key.version == version))
+        .collect::<Vec<_>>();
+    if versions.is_empty() {
+        return Err(format!("{spec} doesn't exist."));
+    }
+    let mut lines = Vec::new();
+    for key in versions {
+        update(&name, &KeyVersion { not_after: Some(not_after), ..key.clone() })?;
+        lines.push(format!("Revoked {name}.{} {}", key.version, fingerprint(&key.key)));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_spec() {
+        assert_eq!(parse_spec("cluster_key"), ("cluster_key".to_string(), None));
+        assert_eq!(parse_spec("cluster_key.2"), ("cluster_key".to_string(), Some(2)));
+        assert_eq!(parse_spec("cluster.key"), ("cluster.key".to_string(), None));
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let dir = "keys/testrunner_sustenet_keys";
+        let master_dir = "keys/testrunner_sustenet_keys_master";
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(master_dir);
+        set_key_store(KeyStore::new(dir).with_passphrase("correct horse").unwrap());
+
+        let generated = run(&["generate", "eu_west"]).unwrap();
+        assert!(generated[0].starts_with("Generated eu_west.0 "));
+        assert!(run(&["generate", "eu_west"]).is_err());
+        assert!(run(&["generate", "eu_west.1"]).is_err());
+        // A key that can't be read still isn't generated over.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = format!("{dir}/eu_west");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(run(&["generate", "eu_west"]).unwrap_err().contains("already exists"));
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+
+        let exported = run(&["export", "eu_west"]).unwrap().remove(0);
+        let fingerprint = run(&["fingerprint", "eu_west"]).unwrap().remove(0);
+        assert!(fingerprint.starts_with("eu_west.0 "));
+
+        // Importing on the master gives the same key, and can be run again.
+        set_key_store(KeyStore::new(master_dir));
+        let imported = run(&["import", "eu_west.0", &exported]).unwrap();
+        assert_eq!(imported, vec![format!("Imported {fingerprint}")]);
+        assert_eq!(run(&["import", "eu_west.0", &exported]).unwrap(), vec![format!("Already imported {fingerprint}")]);
+        assert_eq!(run(&["fingerprint", "eu_west.0"]).unwrap(), vec![fingerprint.clone()]);
+        assert!(run(&["import", "eu_west", &exported]).is_err());
+        let other = base64_encode(&KeyVersion::new(0, generate_key()).to_bytes());
+        assert!(run(&["import", "eu_west.0", &other]).is_err());
+
+        set_key_store(KeyStore::new(dir).with_passphrase("correct horse").unwrap());
+        let rotated = run(&["rotate", "eu_west", "--overlap", "60"]).unwrap();
+        assert_eq!(rotated.len(), 2);
+        assert!(rotated[0].starts_with("Expiring eu_west.0 at "));
+        assert!(rotated[1].starts_with("Rotated eu_west to eu_west.1 "));
+        let listed = run(&["list"]).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].starts_with(&fingerprint) && listed[0].contains(" accepted "));
+        assert!(listed[1].starts_with("eu_west.1 ") && listed[1].contains(" current "));
+
+        let revoked = run(&["revoke", "eu_west.0"]).unwrap();
+        assert_eq!(revoked, vec![format!("Revoked {fingerprint}")]);
+        assert!(run(&["list"]).unwrap()[0].contains(" expired "));
+        assert!(run(&["revoke", "eu_west.5"]).is_err());
+        assert!(run(&["revoke", "us_east"]).is_err());
+        assert!(run(&["rotate", "eu_west", "--delay"]).is_err());
+        assert_eq!(run(&["unknown"]), Err(USAGE.to_string()));
+    }
+}
@@ -1,13 +1,80 @@
+/// The passphrase for the key files. It's read from the environment variable
+/// named by `all.key_passphrase_env` first, so it doesn't have to be in the file.
+fn key_passphrase(settings: &config::Config) -> Option<String> {
+    settings
+        .get::<String>("all.key_passphrase_env")
+        .ok()
+        .and_then(|var| std::env::var(var).ok())
+        .or_else(|| settings.get::<String>("all.key_passphrase").ok())
+        .filter(|passphrase| !passphrase.is_empty())
+}
+
 pub mod master {
     use config::{ Config, File, FileFormat::Toml };
 
     use crate::utils::constants::MASTER_PORT;
 
+    /// What to do when a cluster registers with an identity that's already connected.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DuplicateClusters {
+        /// Disconnect the old cluster and keep the new one. Good for clusters that
+        /// reconnect before the Master Server notices they dropped.
+        Replace,
+        /// Keep the old cluster and disconnect the new one.
+        Reject,
+    }
+
     pub struct Settings {
         pub server_name: String,
 
         pub max_connections: u32,
         pub port: u16,
+
+        /// The name of the Master Server's identity in `keys_dir/identity`. Clients
+        /// check it signed their session before they send their token.
+        pub key_name: String,
+        /// The auth server's public keys that tokens can be signed with. They're
+        /// paths to `.pub` files in the keys directory.
+        pub auth_keys: Vec<String>,
+        /// How many seconds a join ticket is valid for.
+        pub ticket_ttl: u64,
+        /// How many seconds a slot is held on a cluster for a routed player.
+        pub reservation_timeout: u64,
+        /// How many reservations a connection can hold at once. 0 means no limit.
+        pub reservations_per_connection: u32,
+        /// How many reservations an IP can hold at once. 0 means no limit.
+        pub reservations_per_ip: u32,
+        /// How many times an IP can ask to join a cluster per window. 0 means no limit.
+        pub join_attempts_per_ip: u32,
+        /// How many seconds the join limit is counted over.
+        pub join_attempt_window: u64,
+        pub duplicate_clusters: DuplicateClusters,
+        /// Where cluster IDs are saved so clusters keep them when the Master Server restarts.
+        pub cluster_ids_file: String,
+        /// How many seconds a cluster has to answer its challenge.
+        pub challenge_timeout: u64,
+
+        /// How many times an IP can ask to become a cluster per window. 0 means no limit.
+        pub cluster_attempts_per_ip: u32,
+        /// How many times a key name can be asked for per window. 0 means no limit.
+        pub cluster_attempts_per_key: u32,
+        /// How many seconds the attempt limits are counted over.
+        pub cluster_attempt_window: u64,
+        /// How many failed challenges an IP gets before it's locked out. 0 means never.
+        pub cluster_failures_before_lockout: u32,
+        /// How many seconds an IP is locked out for.
+        pub cluster_lockout: u64,
+
+        /// How often the keys directory is checked for changes, in seconds. 0 means never.
+        pub key_reload_interval: u64,
+
+        pub keys_dir: String,
+        /// Encrypts the key files when it's set. See `KeyStore::with_passphrase`.
+        pub key_passphrase: Option<String>,
+        /// Where bans are read from. See `bans`.
+        pub bans_file: String,
+        /// Where revoked tokens are read from. They're pushed to the clusters. See `revocations`.
+        pub revocations_file: String,
     }
 
     pub fn read() -> Settings {
@@ -30,6 +97,46 @@ pub mod master {
                     }
                 Err(_) => MASTER_PORT,
             },
+
+            key_name: settings.get::<String>("master.key_name").unwrap_or("master".to_string()),
+            auth_keys: settings
+                .get::<Vec<String>>("master.auth_keys")
+                .unwrap_or(vec!["identity/auth.pub".to_string()]),
+            ticket_ttl: settings.get::<u64>("master.ticket_ttl").unwrap_or(30),
+            reservation_timeout: settings.get::<u64>("master.reservation_timeout").unwrap_or(30),
+            reservations_per_connection: settings
+                .get::<u32>("master.reservations_per_connection")
+                .unwrap_or(1),
+            reservations_per_ip: settings.get::<u32>("master.reservations_per_ip").unwrap_or(10),
+            join_attempts_per_ip: settings.get::<u32>("master.join_attempts_per_ip").unwrap_or(20),
+            join_attempt_window: settings.get::<u64>("master.join_attempt_window").unwrap_or(60),
+            duplicate_clusters: match settings.get::<String>("master.duplicate_clusters") {
+                Ok(policy) if policy.eq_ignore_ascii_case("reject") => DuplicateClusters::Reject,
+                _ => DuplicateClusters::Replace,
+            },
+            cluster_ids_file: settings
+                .get::<String>("master.cluster_ids_file")
+                .unwrap_or("cluster_ids.txt".to_string()),
+            challenge_timeout: settings.get::<u64>("master.challenge_timeout").unwrap_or(10),
+
+            cluster_attempts_per_ip: settings.get::<u32>("master.cluster_attempts_per_ip").unwrap_or(5),
+            cluster_attempts_per_key: settings
+                .get::<u32>("master.cluster_attempts_per_key")
+                .unwrap_or(20),
+            cluster_attempt_window: settings.get::<u64>("master.cluster_attempt_window").unwrap_or(60),
+            cluster_failures_before_lockout: settings
+                .get::<u32>("master.cluster_failures_before_lockout")
+                .unwrap_or(3),
+            cluster_lockout: settings.get::<u64>("master.cluster_lockout").unwrap_or(300),
+
+            key_reload_interval: settings.get::<u64>("master.key_reload_interval").unwrap_or(10),
+
+            keys_dir: settings.get::<String>("all.keys_dir").unwrap_or("keys".to_string()),
+            key_passphrase: super::key_passphrase(&settings),
+            bans_file: settings.get::<String>("all.bans_file").unwrap_or("bans.txt".to_string()),
+            revocations_file: settings
+                .get::<String>("all.revocations_file")
+                .unwrap_or("revocations.txt".to_string()),
         }
     }
 }
@@ -48,8 +155,24 @@ pub mod cluster {
         pub key_name: String,
         pub master_ip: String,
         pub master_port: u16,
+        /// Used by other clusters to message a group of clusters at once.
+        pub tags: Vec<String>,
+        /// Tells clusters with the same key apart. The Master Server identifies a
+        /// cluster by its key name and this, so it keeps its cluster ID across reconnects.
+        pub cluster_uuid: Option<String>,
+        /// How often the keys directory is checked for a new key version, in seconds. 0 means never.
+        pub key_reload_interval: u64,
 
         pub domain_pub_key: Option<String>,
+        /// The auth server's public keys that tokens can be signed with. They're
+        /// paths to `.pub` files in the keys directory.
+        pub auth_keys: Vec<String>,
+
+        pub keys_dir: String,
+        /// Encrypts the key files when it's set. See `KeyStore::with_passphrase`.
+        pub key_passphrase: Option<String>,
+        /// Where bans are read from. See `bans`.
+        pub bans_file: String,
     }
 
     pub fn read() -> Settings {
@@ -87,11 +210,154 @@ pub mod cluster {
                     }
                 Err(_) => MASTER_PORT,
             },
+            tags: settings.get::<Vec<String>>("cluster.tags").unwrap_or_default(),
+            cluster_uuid: settings.get::<String>("cluster.cluster_uuid").ok(),
+            key_reload_interval: settings.get::<u64>("cluster.key_reload_interval").unwrap_or(10),
+
+            domain_pub_key: settings.get::<String>("cluster.domain_pub_key").ok(),
+            auth_keys: settings
+                .get::<Vec<String>>("cluster.auth_keys")
+                .unwrap_or(vec!["identity/auth.pub".to_string()]),
+
+            keys_dir: settings.get::<String>("all.keys_dir").unwrap_or("keys".to_string()),
+            key_passphrase: super::key_passphrase(&settings),
+            bans_file: settings.get::<String>("all.bans_file").unwrap_or("bans.txt".to_string()),
+        }
+    }
+}
+
+pub mod auth {
+    use config::{ Config, File, FileFormat::Toml };
+
+    use crate::utils::constants::AUTH_PORT;
+
+    pub struct Settings {
+        pub server_name: String,
+
+        pub max_connections: u32,
+        pub port: u16,
+
+        /// The SQLite database the accounts are kept in.
+        pub database: String,
+        /// The name of the server's identity in the key store. Clients check it like a cluster's.
+        pub key_name: String,
+        /// How many failed logins a connection gets before it's disconnected.
+        pub max_failed_logins: u32,
+        /// How many failed logins an IP gets in `login_failure_window`, across connections.
+        pub login_failures_per_ip: u32,
+        /// How many failed logins a username gets in `login_failure_window`, from any IP.
+        pub login_failures_per_account: u32,
+        /// How many seconds failed logins are counted for.
+        pub login_failure_window: u64,
+        /// How many seconds a token is valid for.
+        pub token_ttl: u64,
+        /// How many seconds a refresh token is valid for.
+        pub refresh_ttl: u64,
+        /// The name authenticator apps show next to the username.
+        pub totp_issuer: String,
+
+        /// A JWKS file with the keys of an outside identity provider. Players can
+        /// log in with its JWTs if this or `jwks_url` is set. The file wins.
+        pub jwks_file: Option<String>,
+        pub jwks_url: Option<String>,
+        /// How many seconds keys fetched from `jwks_url` are cached for.
+        pub jwks_refresh: u64,
+        /// The `iss` the JWTs need. Any issuer is accepted if it's unset.
+        pub jwt_issuer: Option<String>,
+        /// The `aud` the JWTs need. Any audience is accepted if it's unset.
+        pub jwt_audience: Option<String>,
+        /// The claim new accounts take their username from. Dots go into nested objects.
+        pub jwt_username_claim: String,
+
+        pub keys_dir: String,
+        /// Encrypts the key files when it's set. See `KeyStore::with_passphrase`.
+        pub key_passphrase: Option<String>,
+        /// Where bans are read from. See `bans`.
+        pub bans_file: String,
+        /// Where revoked tokens are saved. The Master Server reads the same file.
+        pub revocations_file: String,
+    }
 
-            domain_pub_key: match settings.get::<String>("cluster.domain_pub_key") {
-                Ok(domain) => Some(domain),
-                Err(_) => None,
+    pub fn read() -> Settings {
+        let settings = Config::builder()
+            .add_source(File::new("Config.toml", Toml))
+            .build()
+            .expect("Failed to read the configuration file.");
+
+        Settings {
+            server_name: settings
+                .get::<String>("all.server_name")
+                .unwrap_or("Auth Server".to_string()),
+
+            max_connections: settings.get::<u32>("all.max_connections").unwrap_or(0),
+            port: match settings.get::<u16>("all.port") {
+                Ok(port) =>
+                    match port {
+                        0 => AUTH_PORT,
+                        _ => port,
+                    }
+                Err(_) => AUTH_PORT,
             },
+
+            database: settings.get::<String>("auth.database").unwrap_or("auth.db".to_string()),
+            key_name: settings.get::<String>("auth.key_name").unwrap_or("auth".to_string()),
+            max_failed_logins: settings.get::<u32>("auth.max_failed_logins").unwrap_or(5),
+            login_failures_per_ip: settings.get::<u32>("auth.login_failures_per_ip").unwrap_or(20),
+            login_failures_per_account: settings.get::<u32>("auth.login_failures_per_account").unwrap_or(10),
+            login_failure_window: settings.get::<u64>("auth.login_failure_window").unwrap_or(900),
+            token_ttl: settings.get::<u64>("auth.token_ttl").unwrap_or(3600),
+            refresh_ttl: settings.get::<u64>("auth.refresh_ttl").unwrap_or(2592000),
+            totp_issuer: settings.get::<String>("auth.totp_issuer").unwrap_or("Sustenet".to_string()),
+
+            jwks_file: settings.get::<String>("auth.jwks_file").ok(),
+            jwks_url: settings.get::<String>("auth.jwks_url").ok(),
+            jwks_refresh: settings.get::<u64>("auth.jwks_refresh").unwrap_or(3600),
+            jwt_issuer: settings.get::<String>("auth.jwt_issuer").ok(),
+            jwt_audience: settings.get::<String>("auth.jwt_audience").ok(),
+            jwt_username_claim: settings
+                .get::<String>("auth.jwt_username_claim")
+                .unwrap_or("preferred_username".to_string()),
+
+            keys_dir: settings.get::<String>("all.keys_dir").unwrap_or("keys".to_string()),
+            key_passphrase: super::key_passphrase(&settings),
+            bans_file: settings.get::<String>("all.bans_file").unwrap_or("bans.txt".to_string()),
+            revocations_file: settings
+                .get::<String>("all.revocations_file")
+                .unwrap_or("revocations.txt".to_string()),
+        }
+    }
+}
+
+/// Settings for tools that only need the keys or bans, like `sustenet-keys` and `sustenet-bans`.
+pub mod keys {
+    use config::{ Config, File, FileFormat::Toml };
+
+    pub struct Settings {
+        pub keys_dir: String,
+        pub key_passphrase: Option<String>,
+        pub bans_file: String,
+        pub revocations_file: String,
+        /// From `[auth]`. Revocations last as long as the tokens and refresh tokens they cover.
+        pub token_ttl: u64,
+        pub refresh_ttl: u64,
+    }
+
+    /// Reads the `[all]` section of *Config.toml* if there is one, `auth.token_ttl`, and `auth.refresh_ttl`.
+    pub fn read() -> Settings {
+        let settings = Config::builder()
+            .add_source(File::new("Config.toml", Toml).required(false))
+            .build()
+            .expect("Failed to read the configuration file.");
+
+        Settings {
+            keys_dir: settings.get::<String>("all.keys_dir").unwrap_or("keys".to_string()),
+            key_passphrase: super::key_passphrase(&settings),
+            bans_file: settings.get::<String>("all.bans_file").unwrap_or("bans.txt".to_string()),
+            revocations_file: settings
+                .get::<String>("all.revocations_file")
+                .unwrap_or("revocations.txt".to_string()),
+            token_ttl: settings.get::<u64>("auth.token_ttl").unwrap_or(3600),
+            refresh_ttl: settings.get::<u64>("auth.refresh_ttl").unwrap_or(2592000),
         }
     }
 }
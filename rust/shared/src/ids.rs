@@ -0,0 +1,207 @@
+/// A reference to an ID from an `IdAllocator`. The index can be reused once it's
+/// released, but the generation can't, so a stale handle never matches a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+impl Handle {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Packs the handle into a u64 so it can be sent over the network.
+    pub fn to_u64(&self) -> u64 {
+        ((self.generation as u64) << 32) | (self.index as u64)
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Handle {
+            index: value as u32,
+            generation: (value >> 32) as u32,
+        }
+    }
+}
+
+impl std::fmt::Display for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.index, self.generation)
+    }
+}
+
+struct Slot {
+    generation: u32,
+    occupied: bool,
+}
+
+/// Hands out generational handles with O(1) allocate and release.
+///
+/// Released indices are reused before new ones are made. When the highest
+/// indices are released the slots are trimmed, so memory goes back down after
+/// a spike of connections.
+pub struct IdAllocator {
+    slots: Vec<Slot>,
+    /// Released indices. Can hold indices that were trimmed away, which are
+    /// skipped when they're popped.
+    free: Vec<u32>,
+    /// Every handle gets a new generation, even across trimmed slots.
+    next_generation: u32,
+    len: usize,
+    /// 0 means there's no limit.
+    max: usize,
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl IdAllocator {
+    /// `max` is how many handles can be allocated at once. 0 means there's no limit.
+    pub fn new(max: usize) -> Self {
+        IdAllocator {
+            slots: Vec::new(),
+            free: Vec::new(),
+            next_generation: 0,
+            len: 0,
+            max,
+        }
+    }
+
+    /// Returns `None` if `max` handles are already allocated.
+    pub fn allocate(&mut self) -> Option<Handle> {
+        if self.max != 0 && self.len >= self.max {
+            return None;
+        }
+
+        let generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1);
+        self.len += 1;
+
+        while let Some(index) = self.free.pop() {
+            if let Some(slot) = self.slots.get_mut(index as usize) && !slot.occupied {
+                slot.generation = generation;
+                slot.occupied = true;
+                return Some(Handle { index, generation });
+            }
+        }
+
+        let index = self.slots.len() as u32;
+        self.slots.push(Slot { generation, occupied: true });
+        Some(Handle { index, generation })
+    }
+
+    /// Returns `false` if the handle is stale or was never allocated.
+    pub fn release(&mut self, handle: Handle) -> bool {
+        if !self.is_valid(handle) {
+            return false;
+        }
+
+        self.slots[handle.index as usize].occupied = false;
+        self.free.push(handle.index);
+        self.len -= 1;
+        self.trim();
+        true
+    }
+
+    /// Whether the handle is still the one allocated at its index.
+    pub fn is_valid(&self, handle: Handle) -> bool {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) => slot.occupied && slot.generation == handle.generation,
+            None => false,
+        }
+    }
+
+    /// How many handles are allocated.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drops released slots from the end and gives back memory that's no longer needed.
+    fn trim(&mut self) {
+        let old_len = self.slots.len();
+        while self.slots.last().is_some_and(|slot| !slot.occupied) {
+            self.slots.pop();
+        }
+        if self.slots.len() == old_len {
+            return;
+        }
+
+        // Trimmed indices are only cleaned out of the free list once they
+        // outnumber the slots, which keeps this O(1) amortized.
+        if self.free.len() > self.slots.len() {
+            let len = self.slots.len() as u32;
+            self.free.retain(|index| *index < len);
+            self.free.sort_unstable();
+            self.free.dedup();
+            self.free.shrink_to_fit();
+        }
+        if self.slots.capacity() > self.slots.len() * 2 {
+            self.slots.shrink_to_fit();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_reuses_index_with_new_generation() {
+        let mut ids = IdAllocator::default();
+        let first = ids.allocate().unwrap();
+        let _second = ids.allocate().unwrap();
+        assert!(ids.release(first));
+
+        let third = ids.allocate().unwrap();
+        assert_eq!(third.index(), first.index());
+        assert_ne!(third, first);
+        assert!(!ids.is_valid(first));
+        assert!(!ids.release(first));
+        assert!(ids.is_valid(third));
+    }
+
+    #[test]
+    pub fn test_max() {
+        let mut ids = IdAllocator::new(1);
+        let first = ids.allocate().unwrap();
+        assert!(ids.allocate().is_none());
+        ids.release(first);
+        assert!(ids.allocate().is_some());
+    }
+
+    #[test]
+    pub fn test_trims_after_spike() {
+        let mut ids = IdAllocator::default();
+        let handles = (0..1000).map(|_| ids.allocate().unwrap()).collect::<Vec<_>>();
+        for handle in handles.iter() {
+            assert!(ids.release(*handle));
+        }
+        assert!(ids.is_empty());
+        assert_eq!(ids.slots.len(), 0);
+        assert!(ids.free.is_empty());
+
+        // Old handles stay stale even though their slots were trimmed and remade.
+        let handle = ids.allocate().unwrap();
+        assert_eq!(handle.index(), 0);
+        assert!(!ids.is_valid(handles[0]));
+    }
+
+    #[test]
+    pub fn test_u64_round_trip() {
+        let mut ids = IdAllocator::default();
+        ids.allocate();
+        let handle = ids.allocate().unwrap();
+        assert_eq!(Handle::from_u64(handle.to_u64()), handle);
+    }
+}
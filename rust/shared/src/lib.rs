@@ -1,9 +1,13 @@
 use tokio::sync::mpsc::Sender;
 
+pub mod bans;
 pub mod config;
+pub mod ids;
 pub mod logging;
 pub mod network;
 pub mod packets;
+pub mod revocations;
+pub mod roles;
 pub mod utils;
 
 pub mod security;
@@ -17,9 +21,79 @@ pub trait ServerPlugin: Send + Sync {
         &self,
         tx: Sender<Box<[u8]>>,
         command: u8,
-        reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        reader: &'plug mut network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>>;
 
+    /// Called with commands from clients that come after `cluster::FromClient::Undrain`.
+    /// `session` says who the player is, so game commands can be gated by role.
+    fn receive_client<'plug>(
+        &self,
+        _tx: Sender<Box<[u8]>>,
+        _session: roles::SessionInfo,
+        _command: u8,
+        _reader: &'plug mut network::LinkReader<'_>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>> {
+        Box::pin(async {})
+    }
+
+    /// Called when another cluster sends a message through the Master Server.
+    /// `from` is the cluster ID of the sender.
+    fn cluster_message(
+        &self,
+        _tx: Sender<Box<[u8]>>,
+        _from: u32,
+        _data: Vec<u8>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    /// Called when the Master Server answers a player lookup. `cluster` is
+    /// the ID of the cluster the player is on, if they're online.
+    fn player_location(
+        &self,
+        _tx: Sender<Box<[u8]>>,
+        _player_id: String,
+        _cluster: Option<u32>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        Box::pin(async {})
+    }
+
+    /// Only used when debugging is enabled.
+    fn info(&self, message: &str);
+}
+
+/// Lets game-specific logic run on the Master Server without forking it.
+/// Only `receive` and `info` have to be implemented.
+pub trait MasterPlugin: Send + Sync {
+    /// Called with commands the Master Server doesn't handle itself. `connection`
+    /// is the connection that sent it and `cluster` is its cluster ID if it's a
+    /// verified cluster.
+    fn receive<'plug>(
+        &self,
+        tx: Sender<Box<[u8]>>,
+        connection: ids::Handle,
+        cluster: Option<u32>,
+        command: u8,
+        reader: &'plug mut network::LinkReader<'_>
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>>;
+
+    fn client_connected(&self, _connection: ids::Handle) {}
+
+    fn client_disconnected(&self, _connection: ids::Handle) {}
+
+    /// Called when a cluster is verified. It's called again if the cluster
+    /// reconnects. A cluster that replaces a duplicate of itself is unregistered first.
+    fn cluster_registered(&self, _cluster_id: u32, _name: &str) {}
+
+    /// Called when a cluster disconnects or is replaced.
+    fn cluster_unregistered(&self, _cluster_id: u32) {}
+
+    /// Called when a player asks to join a cluster. Returns the cluster to send
+    /// them to, or `None` to turn them away.
+    fn route(&self, _player_id: &str, cluster_id: u32) -> Option<u32> {
+        Some(cluster_id)
+    }
+
     /// Only used when debugging is enabled.
     fn info(&self, message: &str);
 }
@@ -31,14 +105,14 @@ pub trait ClientPlugin: Send + Sync {
         &self,
         tx: Sender<Box<[u8]>>,
         command: u8,
-        reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        reader: &'plug mut network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>>;
 
     fn receive_cluster<'plug>(
         &self,
         tx: Sender<Box<[u8]>>,
         command: u8,
-        reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        reader: &'plug mut network::LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>>;
 
     /// Only used when debugging is enabled.
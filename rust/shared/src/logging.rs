@@ -10,6 +10,7 @@ pub enum LogType {
     Master,
     Cluster,
     Client,
+    Auth,
     System,
 }
 
@@ -43,6 +44,7 @@ macro_rules! log_message {
                 LogType::Master => "[Master]",
                 LogType::Cluster => "[Cluster]",
                 LogType::Client => "[Client]",
+                LogType::Auth => "[Auth]",
                 LogType::System => "[System]",
             };
 
@@ -51,10 +53,12 @@ macro_rules! log_message {
     };
 }
 
-use crate::{ log_message, utils::constants::DEBUGGING };
+use crate::utils::constants::DEBUGGING;
+
+type PluginInfo = Box<dyn Fn(&str) + Send + Sync + 'static>;
 
 pub struct Logger {
-    plugin_info: std::sync::OnceLock<Box<dyn Fn(&str) + Send + Sync + 'static>>,
+    plugin_info: std::sync::OnceLock<PluginInfo>,
     log_type: LogType,
 }
 impl Logger {
@@ -1,3 +1,12 @@
+use tokio::io::{ AsyncRead, AsyncReadExt };
+
+use crate::ids::Handle;
+use crate::security::session::SecureReader;
+
+/// How connections are read. They're plaintext until their handshake finishes
+/// and sealed after that.
+pub type LinkReader<'a> = SecureReader<tokio::io::BufReader<tokio::net::tcp::ReadHalf<'a>>>;
+
 pub enum Protocols {
     TCP,
     UDP,
@@ -5,9 +14,9 @@ pub enum Protocols {
 
 /// Enum to represent all possible events that can be sent to the event loop.
 pub enum Event {
-    Connection(u32),
-    Disconnection(u32),
-    ReceivedData(u32, Vec<u8>),
+    Connection(Handle),
+    Disconnection(Handle),
+    ReceivedData(Handle, Vec<u8>),
 }
 
 #[derive(Eq)]
@@ -39,4 +48,26 @@ impl PartialEq for ClusterInfo {
         // For example, if ClusterInfo has a field `id` of type i32:
         self.id == other.id
     }
-}
\ No newline at end of file
+}
+/// Reads bytes prefixed by their length as a u8.
+pub async fn read_bytes<R>(reader: &mut R) -> std::io::Result<Vec<u8>> where R: AsyncRead + Unpin {
+    let len = reader.read_u8().await? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Reads an envelope from `security::envelope`. It's prefixed by its length as a u16.
+pub async fn read_envelope<R>(reader: &mut R) -> std::io::Result<Vec<u8>> where R: AsyncRead + Unpin {
+    let len = reader.read_u16().await? as usize;
+    let mut envelope = vec![0u8; len];
+    reader.read_exact(&mut envelope).await?;
+    Ok(envelope)
+}
+
+/// Reads a string prefixed by its length as a u8.
+pub async fn read_string<R>(reader: &mut R) -> std::io::Result<String> where R: AsyncRead + Unpin {
+    String::from_utf8(read_bytes(reader).await?).map_err(|e|
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    )
+}
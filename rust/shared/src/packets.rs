@@ -1,32 +1,143 @@
+/// The status in `auth::ToClient::Authenticate`, `cluster::ToClient::Authenticate`,
+/// `cluster::ToClient::CommandStatus`, `cluster::ToClient::Refused`, and `master::ToUnknown::JoinRefused`.
+/// It's the HTTP status without the middle digit.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 20,
+    /// The username or password isn't allowed.
+    BadRequest = 40,
+    /// The username or password is wrong.
+    Unauthorized = 41,
+    /// Too many failed attempts. The server disconnects after sending it.
+    TooManyAttempts = 42,
+    /// The connection's roles don't allow it.
+    Forbidden = 43,
+    /// Whatever was asked for doesn't exist, like the key a token was signed with.
+    NotFound = 44,
+    /// The account needs a one-time code from its authenticator app. It's 428.
+    TotpRequired = 48,
+    /// The username is taken.
+    Conflict = 49,
+    ServerError = 50,
+    /// The server isn't taking new connections, like a cluster that's draining. It's 503.
+    Unavailable = 53,
+}
+
+impl Status {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        match status {
+            20 => Some(Status::Ok),
+            40 => Some(Status::BadRequest),
+            41 => Some(Status::Unauthorized),
+            42 => Some(Status::TooManyAttempts),
+            43 => Some(Status::Forbidden),
+            44 => Some(Status::NotFound),
+            48 => Some(Status::TotpRequired),
+            49 => Some(Status::Conflict),
+            50 => Some(Status::ServerError),
+            53 => Some(Status::Unavailable),
+            _ => None,
+        }
+    }
+}
+
 pub mod master {
     #[repr(u8)]
     pub enum FromUnknown {
         /// Sends a list of names and IPs to whoever requested it.
         RequestClusters,
-        /// Just a way to gracefully disconnect the client.
+        /// Asks to join a cluster. Contains the cluster ID, the ephemeral X25519
+        /// public key the client will start its session with the cluster with, and
+        /// the token from the auth server prefixed by its u16 length. Only sent
+        /// after `StartSession`. The player ID comes from the token. The Master
+        /// Server answers with a ticket and the client disconnects.
         JoinCluster,
 
-        /// They send the name of the cluster's key to the Master Server.
-        /// If the key doesn't exist, the server will do nothing but
-        /// stay silent. If it does exist, it will send a generated
-        /// passphrase that's encrypted with AES.
+        /// They send the name and version of the cluster's key and a nonce to the
+        /// Master Server. It answers with `VerifyCluster`. If the key doesn't
+        /// exist or the version isn't accepted, the challenge is sealed with a
+        /// throwaway key instead, so it looks the same but can never be answered.
         BecomeCluster,
         /// When they send the decrypted key back to the Master Server.
         AnswerCluster,
+
+        // Cluster Section
+
+        /// Relays a message to a single cluster by its ID.
+        SendToCluster,
+        /// Relays a message to every cluster that registered with the given tag.
+        SendToTag,
+        /// Relays a message to every other registered cluster.
+        BroadcastClusters,
+
+        /// A player has joined the cluster. Contains their player ID.
+        PlayerJoined,
+        /// A player has left the cluster. Contains their player ID.
+        PlayerLeft,
+        /// Asks which cluster a player is on. Contains their player ID.
+        LocatePlayer,
+        /// Switches a verified cluster to a new version of its key. Contains the
+        /// version and a proof sealed with it in an envelope prefixed by its u16 length.
+        RotateKey,
+
+        /// Sends the client's ephemeral X25519 public key, like `cluster::FromClient::StartSession`.
+        /// It has to be the first thing the client sends. Everything after it is sealed.
+        StartSession,
+        /// Asks for the Master Server's public key. Sent if the cached key's
+        /// version doesn't match the one in `SessionStarted`. Commands after it
+        /// go to the plugin.
+        RequestKey,
     }
     #[repr(u8)]
     pub enum ToUnknown {
-        /// Sends a list of cluster servers containing their name, ip, and port.
+        /// Sends a list of cluster servers containing their id, name, ip, and port.
         SendClusters,
 
-        /// Generates a passphrase that's encrypted with AES and sends
-        /// it waiting for it to be sent back. It's stored in their name.
+        /// Generates a passphrase and sends it sealed in an envelope prefixed by
+        /// its u16 length, then waits for it to be sent back. The connection's
+        /// handshake state holds it until it's answered or the challenge times out.
         VerifyCluster,
         /// Once validated, the cluster is moved to the cluster list and
-        /// notifies them that they're now a cluster.
+        /// notifies them that they're now a cluster. Contains their cluster ID.
         CreateCluster,
-        
-        // Cluster things go here.
+
+        // Cluster Section
+
+        /// A message relayed from another cluster. Contains the sender's
+        /// cluster ID followed by the message.
+        ClusterMessage,
+        /// Answers `LocatePlayer`. Contains the player ID, a u8 that's 1 if
+        /// they were found, and the ID of the cluster they're on.
+        PlayerLocation,
+        /// The cluster's key was rotated. Contains the new version.
+        KeyRotated,
+
+        /// The connection's IP is banned. Contains when the ban expires as a u64
+        /// that's 0 if it never does, and the reason as a u8 length-prefixed
+        /// string. The connection is closed after it.
+        Banned,
+        /// Tokens the auth server revoked. Sent to a cluster once it's verified
+        /// and whenever there are new ones. Laid out like `revocations::to_packets`.
+        TokensRevoked,
+
+        /// Sends a signed ticket that the client gives the cluster it's joining.
+        /// Contains the cluster ID and the ticket prefixed by its u16 length.
+        SendTicket,
+        /// The cluster the client asked to join is full. Contains the cluster ID.
+        ClusterFull,
+
+        /// Answers `StartSession` with the version of the Master Server's Ed25519 key,
+        /// its ephemeral X25519 public key, and a signature over both ephemeral keys.
+        SessionStarted,
+        /// Answers `RequestKey` with the version of the key and the public key.
+        SendPubKey,
+        /// Answers `JoinCluster` when it's refused. Contains the cluster ID and a
+        /// `Status`. It's 40 if the token is invalid or expired, 41 if it was
+        /// revoked, 42 if the client asks too often or holds too many slots, 44 if
+        /// the token was signed by a key the Master Server doesn't trust, and 50
+        /// if the Master Server can't check tokens.
+        JoinRefused,
     }
 }
 
@@ -39,14 +150,33 @@ pub mod cluster {
         /// Gracefully disconnect the client.
         LeaveCluster,
 
-        /// Only works if the server doesn't have a domain config. Sends the pub key.
-        /// Ran if the cache key doesn't match.
+        /// Asks for the cluster's public key. Sent after the session starts if the
+        /// cached key's version doesn't match the one in `SessionStarted`.
         RequestKey,
-        /// Encrypts the password and sends it.
-        SendPassword,
+        /// Sends the token from the auth server, prefixed by its u16 length. The
+        /// cluster checks it and answers with `Authenticate`.
+        SendToken,
 
         /// Moves the player's position.
-        Move
+        Move,
+
+        /// Sends the client's ephemeral X25519 public key. This must be the first
+        /// thing sent to a cluster or the client is disconnected.
+        StartSession,
+        /// Sends the ticket from the Master Server once the client trusts the
+        /// cluster's key. It's sealed like everything after the session starts.
+        SendTicket,
+
+        /// Disconnects every client authenticated as the account with the u64
+        /// account ID. Needs `Permission::Kick`. Answered with `CommandStatus`.
+        Kick,
+        /// Stops the cluster from taking new players. The ones already on it
+        /// stay. Needs `Permission::Drain`. Answered with `CommandStatus`.
+        Drain,
+        /// Lets the cluster take new players again after `Drain`. Needs
+        /// `Permission::Drain`. Answered with `CommandStatus`. Commands after
+        /// it go to the plugin.
+        Undrain,
     }
 
     pub enum ToClient {
@@ -57,17 +187,81 @@ pub mod cluster {
         /// Disconnects the client from the cluster.
         LeaveCluster,
 
-        // Sends the cached version of the key.
+        /// Answers `RequestKey` when "domain_pub_key" is set in the Config. Sends
+        /// the version of the key and the URL to fetch it from.
         VersionOfKey,
-        /// Sends the public key to the client. This only works if "domain_pub_key"
-        /// is not set in the Config.
+        /// Answers `RequestKey` with the version of the key and the public key.
+        /// This is only sent if "domain_pub_key" is not set in the Config.
         SendPubKey,
-        /// This sends back the status to the user. It'll have a status code.
-        /// 20 = 200, 44 = 404, 40 = 400, 50 = 500.
-        /// If 20, it will send the user their ID (this was assigned on initial connection).
+        /// Answers `SendToken` with a status code. 20 = 200, 40 = 400, 44 = 404, 50 = 500.
+        /// It's 40 if the token is invalid, expired, or for another player, 41 if it
+        /// was revoked, 44 if it was signed by a key the cluster doesn't trust, and
+        /// 50 if the cluster can't check tokens. If 20, it's followed by the account
+        /// ID from the token. A client can send a refreshed token at any time.
         Authenticate,
 
         /// Sends the player's new position.
-        Move
+        Move,
+
+        /// Answers `StartSession` with the version of the cluster's Ed25519 key,
+        /// its ephemeral X25519 public key, and a signature over both ephemeral keys.
+        SessionStarted,
+        /// Answers `Kick`, `Drain`, and `Undrain` with the command and a status. It's 43 if
+        /// the client's roles don't allow it and 44 if the account isn't on the cluster.
+        CommandStatus,
+        /// The client's IP or account is banned. It's laid out like `master::ToUnknown::Banned`.
+        /// It's sent unsealed if the IP is banned, since there isn't a session yet.
+        Banned,
+        /// The cluster isn't taking the client. Contains a `Status` and the reason
+        /// as a u8 length-prefixed string. It's 53 if the cluster is draining. It's
+        /// sent unsealed before the connection is closed, since there isn't a session yet.
+        Refused,
+    }
+}
+
+pub mod auth {
+    pub enum FromClient {
+        /// Sends the client's ephemeral X25519 public key, like `cluster::FromClient::StartSession`.
+        /// Everything after it is sealed.
+        StartSession,
+        /// Asks for the auth server's public key. Sent if the cached key's
+        /// version doesn't match the one in `SessionStarted`.
+        RequestKey,
+        /// Creates an account. Contains the username and the password as u8 length-prefixed strings.
+        Register,
+        /// Logs in. Contains the username and the password as u8 length-prefixed strings.
+        Login,
+        /// Logs in with a JWT from an outside identity provider, prefixed by its
+        /// u16 length. The account is made the first time. It's answered with 44
+        /// if the server doesn't accept JWTs.
+        LoginExternal,
+        /// Gets a new token with the 32-byte refresh token from `Authenticate`.
+        /// The refresh token can only be used once. A new one comes with the answer.
+        Refresh,
+        /// Contains the username, the old password, and the new one as u8
+        /// length-prefixed strings. The account's other tokens are revoked, and
+        /// it's answered like `Login`.
+        ChangePassword,
+        /// Sends the one-time code after `Authenticate` answered with `Status::TotpRequired`,
+        /// as a u8 length-prefixed string. It's answered like `Login`.
+        SendTotp,
+    }
+
+    pub enum ToClient {
+        /// Answers `StartSession` with the version of the server's Ed25519 key,
+        /// its ephemeral X25519 public key, and a signature over both ephemeral keys.
+        SessionStarted,
+        /// Answers `RequestKey` with the version of the key and the public key.
+        SendPubKey,
+        /// Answers `Register` and `Login` with a `Status`. If it's `Ok`, it's
+        /// followed by the account's ID as a u64, its username, its token
+        /// prefixed by its u16 length, and a 32-byte refresh token. If it's
+        /// `TotpRequired`, it's followed by a u16 length-prefixed `otpauth://`
+        /// URL if the account still has to set up its authenticator, and an
+        /// empty one if it already has.
+        Authenticate,
+        /// The client's IP or account is banned. It's laid out like `master::ToUnknown::Banned`,
+        /// and it's sent unsealed if the IP is banned.
+        Banned,
     }
 }
@@ -0,0 +1,372 @@
+//! Revoked tokens.
+//!
+//! A revocation covers every token for an account that was issued at or before
+//! `revoked_at`. The auth server adds one when a password changes, and
+//! `sustenet-bans` adds one when an account is banned or revoked. They're kept
+//! in a text file that the Master Server watches and pushes to clusters, which
+//! disconnect the sessions they cover.
+//!
+//! ```text
+//! # account_id revoked_at expires_at
+//! 42 1767225600 1767229200
+//! ```
+//!
+//! `expires_at` is when the last token or refresh token it covers expires.
+//! It's dropped after that.
+
+use std::path::{ Path, PathBuf };
+use std::sync::{ LazyLock, Mutex, OnceLock, RwLock };
+use std::time::{ Duration, SystemTime };
+
+use tokio::io::{ AsyncRead, AsyncReadExt };
+use tokio::sync::broadcast;
+
+use crate::logging::{ LogType, Logger };
+use crate::security::token::Token;
+use crate::utils::{ lock_file, unix_time, write_atomic };
+
+/// How many revocations are sent in one packet.
+pub const PER_PACKET: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Revocation {
+    pub account_id: u64,
+    /// Unix time in seconds. Tokens issued at or before it are revoked.
+    pub revoked_at: u64,
+    /// Unix time in seconds.
+    pub expires_at: u64,
+}
+
+impl Revocation {
+    /// Revokes the account's tokens now. `token_ttl` and `refresh_ttl` are how long
+    /// the auth server's tokens and refresh tokens last. Refresh tokens are checked
+    /// against it too, so it lasts until both have expired.
+    pub fn now(account_id: u64, token_ttl: u64, refresh_ttl: u64) -> Self {
+        let revoked_at = unix_time();
+        Revocation { account_id, revoked_at, expires_at: revoked_at + token_ttl.max(refresh_ttl) }
+    }
+
+    pub fn covers(&self, token: &Token) -> bool {
+        token.account_id == self.account_id && token.issued_at <= self.revoked_at
+    }
+
+    pub fn is_active_at(&self, now: u64) -> bool {
+        now < self.expires_at
+    }
+
+    fn parse_line(line: &str) -> Result<Self, String> {
+        let fields = line.split_whitespace().map(|field| field.parse::<u64>()).collect::<Vec<_>>();
+        match fields.as_slice() {
+            [Ok(account_id), Ok(revoked_at), Ok(expires_at)] =>
+                Ok(Revocation { account_id: *account_id, revoked_at: *revoked_at, expires_at: *expires_at }),
+            _ => Err("A revocation is an account ID, when it was revoked, and when it expires.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Revocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.account_id, self.revoked_at, self.expires_at)
+    }
+}
+
+/// The packets for the revocations. Each is the command and a u16 count, followed
+/// by the account ID, `revoked_at`, and `expires_at` of each one as u64s.
+pub fn to_packets(command: u8, revocations: &[Revocation]) -> Vec<Vec<u8>> {
+    revocations
+        .chunks(PER_PACKET)
+        .map(|chunk| {
+            let mut data = vec![command];
+            data.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            for revocation in chunk {
+                data.extend_from_slice(&revocation.account_id.to_be_bytes());
+                data.extend_from_slice(&revocation.revoked_at.to_be_bytes());
+                data.extend_from_slice(&revocation.expires_at.to_be_bytes());
+            }
+            data
+        })
+        .collect()
+}
+
+/// Reads a packet made by `to_packets`, after the command.
+pub async fn read_revocations<R>(reader: &mut R) -> std::io::Result<Vec<Revocation>> where R: AsyncRead + Unpin {
+    let count = reader.read_u16().await?;
+    let mut revocations = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        revocations.push(Revocation {
+            account_id: reader.read_u64().await?,
+            revoked_at: reader.read_u64().await?,
+            expires_at: reader.read_u64().await?,
+        });
+    }
+    Ok(revocations)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RevocationList {
+    revocations: Vec<Revocation>,
+}
+
+impl RevocationList {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut revocations = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            revocations.push(Revocation::parse_line(line).map_err(|e| format!("Line {}: {e}", number + 1))?);
+        }
+        Ok(RevocationList { revocations })
+    }
+
+    /// A file that doesn't exist is an empty list.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => RevocationList::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RevocationList::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Writes the list through a temporary file, so a server reading it never sees half of it.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut text = "# account_id revoked_at expires_at\n".to_string();
+        for revocation in self.revocations.iter() {
+            text.push_str(&format!("{revocation}\n"));
+        }
+        write_atomic(path, text.as_bytes())
+    }
+
+    /// Loads the list, changes it, and saves it while holding a lock on `<path>.lock`.
+    /// The auth server and `sustenet-bans` both write the file, so neither can
+    /// overwrite a revocation the other just added.
+    pub fn update<T>(path: &Path, change: impl FnOnce(&mut RevocationList) -> T) -> Result<T, String> {
+        let lock = lock_file(path).map_err(|e| format!("Failed to lock the revocations: {e}"))?;
+        let mut list = RevocationList::load(path)?;
+        let result = change(&mut list);
+        list.save(path).map_err(|e| e.to_string())?;
+        drop(lock);
+        Ok(result)
+    }
+
+    pub fn revocations(&self) -> &[Revocation] {
+        &self.revocations
+    }
+
+    /// Returns `false` if the list already had it.
+    pub fn add(&mut self, revocation: Revocation) -> bool {
+        if self.revocations.contains(&revocation) {
+            return false;
+        }
+        self.revocations.push(revocation);
+        true
+    }
+
+    /// Drops the revocations whose tokens have all expired.
+    pub fn prune(&mut self, now: u64) {
+        self.revocations.retain(|revocation| revocation.is_active_at(now));
+    }
+
+    pub fn is_revoked_at(&self, token: &Token, now: u64) -> bool {
+        self.revocations.iter().any(|revocation| revocation.covers(token) && revocation.is_active_at(now))
+    }
+
+    /// The latest time the account's tokens were revoked at, if they ever were.
+    pub fn revoked_at(&self, account_id: u64, now: u64) -> Option<u64> {
+        self.revocations
+            .iter()
+            .filter(|revocation| revocation.account_id == account_id && revocation.is_active_at(now))
+            .map(|revocation| revocation.revoked_at)
+            .max()
+    }
+}
+
+/// The file the revocations are saved to, on the servers that have one.
+struct RevocationFile {
+    path: PathBuf,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+static REVOCATIONS: LazyLock<RwLock<RevocationList>> = LazyLock::new(|| RwLock::new(RevocationList::default()));
+static REVOCATION_FILE: OnceLock<RevocationFile> = OnceLock::new();
+/// Every revocation that's new to this server, whether it came from the file or not.
+static UPDATES: LazyLock<broadcast::Sender<Revocation>> = LazyLock::new(|| broadcast::channel(1024).0);
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger::new(LogType::System));
+
+/// Sets the file revocations are read from and saved to. Only the first call does anything.
+/// Clusters don't have one. They get revocations from the Master Server.
+pub fn set_revocation_file(path: impl Into<PathBuf>) -> Result<(), String> {
+    let path = path.into();
+    let list = RevocationList::load(&path)?;
+    let modified = modified(&path);
+    if REVOCATION_FILE.set(RevocationFile { path, modified: Mutex::new(modified) }).is_ok() {
+        add(list.revocations());
+    }
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads the revocation file if it changed. A file that fails to parse is ignored.
+/// Checks don't call it, so servers with a file call it on an interval. See `watch`.
+pub fn reload() {
+    let Some(file) = REVOCATION_FILE.get() else {
+        return;
+    };
+    let modified = modified(&file.path);
+    let list = {
+        let mut last = file.modified.lock().expect("The revocation file lock was poisoned.");
+        if *last == modified {
+            return;
+        }
+        *last = modified;
+        RevocationList::load(&file.path)
+    };
+    match list {
+        Ok(list) => {
+            add(list.revocations());
+        }
+        Err(e) => LOGGER.error(format!("Failed to reload the revocations at '{}': {e}", file.path.display()).as_str()),
+    }
+}
+
+/// Reloads the revocation file every `interval` on a background task, so checking
+/// a token doesn't have to look at the file. Needs a Tokio runtime.
+pub fn watch(interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            reload();
+        }
+    });
+}
+
+/// Gets every revocation that's new to this server from now on.
+pub fn subscribe() -> broadcast::Receiver<Revocation> {
+    UPDATES.subscribe()
+}
+
+/// Adds the revocations without saving them. Returns the ones that are new and still active.
+pub fn add(revocations: &[Revocation]) -> Vec<Revocation> {
+    let now = unix_time();
+    let mut list = REVOCATIONS.write().expect("The revocation list lock was poisoned.");
+    list.prune(now);
+    let added = revocations
+        .iter()
+        .filter(|revocation| revocation.is_active_at(now) && list.add(**revocation))
+        .copied()
+        .collect::<Vec<_>>();
+    for revocation in added.iter() {
+        let _ = UPDATES.send(*revocation);
+    }
+    added
+}
+
+/// Adds the revocation and saves it to the revocation file if there is one.
+/// The file is read again first, so revocations other servers added are kept.
+pub fn revoke(revocation: Revocation) -> Result<(), String> {
+    add(&[revocation]);
+    let Some(file) = REVOCATION_FILE.get() else {
+        return Ok(());
+    };
+    let mut modified = file.modified.lock().expect("The revocation file lock was poisoned.");
+    let now = unix_time();
+    let list = RevocationList::update(&file.path, |list| {
+        list.prune(now);
+        list.add(revocation);
+        list.clone()
+    })?;
+    *modified = self::modified(&file.path);
+    drop(modified);
+    add(list.revocations());
+    Ok(())
+}
+
+/// Whether the token was revoked.
+pub fn is_revoked(token: &Token) -> bool {
+    REVOCATIONS.read().expect("The revocation list lock was poisoned.").is_revoked_at(token, unix_time())
+}
+
+/// The latest time the account's tokens were revoked at, if they were.
+pub fn revoked_at(account_id: u64) -> Option<u64> {
+    REVOCATIONS.read().expect("The revocation list lock was poisoned.").revoked_at(account_id, unix_time())
+}
+
+/// Every revocation that's still active.
+pub fn active() -> Vec<Revocation> {
+    let now = unix_time();
+    let mut list = REVOCATIONS.write().expect("The revocation list lock was poisoned.");
+    list.prune(now);
+    list.revocations().to_vec()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_revocation_list() {
+        let mut list = RevocationList::parse(
+            "# account_id revoked_at expires_at
+            42 1000 4600
+            42 2000 5600
+            7 1000 4600"
+        ).unwrap();
+
+        let token = |account_id: u64, issued_at: u64| Token {
+            account_id,
+            username: "player".to_string(),
+            roles: Vec::new(),
+            issued_at,
+            expires_at: issued_at + 3600,
+        };
+        assert!(list.is_revoked_at(&token(42, 2000), 3000));
+        assert!(!list.is_revoked_at(&token(42, 2001), 3000));
+        assert!(!list.is_revoked_at(&token(8, 1000), 3000));
+        assert!(!list.is_revoked_at(&token(7, 1000), 4600));
+        assert_eq!(list.revoked_at(42, 3000), Some(2000));
+
+        assert!(!list.add(Revocation { account_id: 7, revoked_at: 1000, expires_at: 4600 }));
+        list.prune(5000);
+        assert_eq!(list.revocations(), &[Revocation { account_id: 42, revoked_at: 2000, expires_at: 5600 }]);
+        assert!(RevocationList::parse("42 soon 4600").is_err());
+    }
+
+    #[test]
+    pub fn test_refresh_after_token_ttl() {
+        let (token_ttl, refresh_ttl) = (3600, 86400);
+        let issued_at = unix_time();
+        let revocation = Revocation::now(42, token_ttl, refresh_ttl);
+        let mut list = RevocationList::default();
+        list.add(revocation);
+
+        // A refresh token issued before the revocation is still refused once its tokens would have expired.
+        let later = revocation.revoked_at + token_ttl + 1;
+        assert!(list.revoked_at(42, later).is_some_and(|revoked_at| issued_at <= revoked_at));
+        list.prune(later);
+        assert_eq!(list.revocations(), &[revocation]);
+
+        // It's dropped once the refresh tokens it covers have expired too.
+        assert_eq!(list.revoked_at(42, revocation.revoked_at + refresh_ttl), None);
+    }
+
+    #[test]
+    pub fn test_update_keeps_other_revocations() {
+        let path = std::env::temp_dir().join(format!("revocations_testrunner_{}.txt", std::process::id()));
+        let first = Revocation { account_id: 1, revoked_at: 1000, expires_at: u64::MAX };
+        let second = Revocation { account_id: 2, revoked_at: 1000, expires_at: u64::MAX };
+        RevocationList::update(&path, |list| list.add(first)).unwrap();
+        // Another writer's revocation is read back before this one is added.
+        RevocationList::update(&path, |list| list.add(second)).unwrap();
+        assert_eq!(RevocationList::load(&path).unwrap().revocations(), &[first, second]);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("txt.lock"));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::ids::Handle;
+use crate::security::token::Token;
+
+/// What an account is. Roles are given by the auth server and carried in the
+/// account's token, so a cluster only trusts them if the token checks out.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Role {
+    /// Every authenticated player.
+    Player,
+    Moderator,
+    Admin,
+    /// Trusted services, like bots and other servers. Verified clusters have it on the Master Server.
+    Server,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Player, Role::Moderator, Role::Admin, Role::Server];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Server => "server",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Role::ALL.into_iter().find(|role| role.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What a connection is allowed to do. Plugins can check roles directly for
+/// their own commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// Disconnect a player.
+    Kick,
+    /// Keep a player out.
+    Ban,
+    /// Stop a cluster from taking new players.
+    Drain,
+    /// Change an account's roles.
+    ManageRoles,
+}
+
+impl Permission {
+    /// The roles that have the permission.
+    pub fn roles(&self) -> &'static [Role] {
+        match self {
+            Permission::Kick | Permission::Ban => &[Role::Moderator, Role::Admin, Role::Server],
+            Permission::Drain => &[Role::Admin, Role::Server],
+            Permission::ManageRoles => &[Role::Admin],
+        }
+    }
+}
+
+/// A set of roles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Roles(u8);
+
+impl Roles {
+    /// No roles, like a connection that hasn't authenticated.
+    pub const NONE: Roles = Roles(0);
+
+    pub fn of(roles: &[Role]) -> Self {
+        roles.iter().fold(Roles::NONE, |set, role| set.with(*role))
+    }
+
+    /// The roles in a token. Names that aren't roles are ignored, and every
+    /// token has `Role::Player`.
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Self {
+        names
+            .iter()
+            .filter_map(|name| Role::from_name(name.as_ref()))
+            .fold(Roles::of(&[Role::Player]), |set, role| set.with(role))
+    }
+
+    pub fn from_token(token: &Token) -> Self {
+        Roles::from_names(&token.roles)
+    }
+
+    pub fn with(self, role: Role) -> Self {
+        Roles(self.0 | (1 << (role as u8)))
+    }
+
+    pub fn has(&self, role: Role) -> bool {
+        self.0 & (1 << (role as u8)) != 0
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        permission.roles().iter().any(|role| self.has(*role))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Role> + '_ {
+        Role::ALL.into_iter().filter(|role| self.has(*role))
+    }
+}
+
+impl std::fmt::Display for Roles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names = self.iter().map(|role| role.name()).collect::<Vec<_>>();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Who sent a command to a cluster. Given to `ServerPlugin::receive_client`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub client: Handle,
+    /// From the client's ticket.
+    pub player_id: String,
+    /// Set if the client sent a valid token.
+    pub account_id: Option<u64>,
+    pub username: Option<String>,
+    /// Just `Role::Player` if the client didn't send a token.
+    pub roles: Roles,
+}
+
+impl SessionInfo {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.roles.can(permission)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_roles() {
+        let roles = Roles::from_names(&["Moderator", "cheater"]);
+        assert!(roles.has(Role::Player) && roles.has(Role::Moderator) && !roles.has(Role::Admin));
+        assert!(roles.can(Permission::Kick) && !roles.can(Permission::Drain));
+        assert_eq!(roles.to_string(), "player, moderator");
+
+        let admin = Roles::from_names(&["admin"]);
+        assert!(admin.can(Permission::Drain) && admin.can(Permission::ManageRoles));
+        assert!(!Roles::of(&[Role::Server]).can(Permission::ManageRoles));
+        assert!(!Roles::NONE.can(Permission::Kick));
+    }
+}
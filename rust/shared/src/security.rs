@@ -1,6 +1,8 @@
 pub mod base64engine {
     use base64::{ Engine, alphabet, engine::{ self, general_purpose } };
 
+    pub use base64::DecodeError;
+
     const CUSTOM_ENGINE: engine::GeneralPurpose = engine::GeneralPurpose::new(
         &alphabet::URL_SAFE,
         general_purpose::NO_PAD
@@ -9,134 +11,1413 @@ pub mod base64engine {
         CUSTOM_ENGINE.encode(input)
     }
 
-    pub fn base64_decode(input: &str) -> Vec<u8> {
-        CUSTOM_ENGINE.decode(input).unwrap()
+    pub fn base64_decode(input: &str) -> Result<Vec<u8>, DecodeError> {
+        CUSTOM_ENGINE.decode(input)
     }
 }
 
 pub mod aes {
-    use std::{ collections::HashMap, fs::File, io::{ Read, Write }, vec };
+    use std::{
+        collections::HashMap,
+        fs::{ File, OpenOptions },
+        io::{ Read, Write },
+        path::{ Path, PathBuf },
+        sync::{ LazyLock, RwLock },
+    };
 
-    use aes_gcm::{
+    use crate::utils::unix_time;
+
+    pub use aes_gcm::{
         Aes256Gcm, // Or `Aes128Gcm`
         Key,
-        Nonce,
-        aead::{ Aead, AeadCore, KeyInit, OsRng },
     };
+    use aes_gcm::{ Nonce, aead::{ Aead, AeadCore, KeyInit, OsRng, Payload, rand_core::RngCore } };
+    use sha2::{ Digest, Sha256 };
 
-    pub fn create_keys_dir() -> std::io::Result<()> {
-        if std::fs::DirBuilder::new().recursive(true).create("keys").is_err() {
+    use crate::packets::master::FromUnknown;
+
+    use super::envelope::{ Context, Header, header, open, seal };
+
+    /// Why data couldn't be encrypted or decrypted.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CryptoError {
+        /// The data is too short to have a nonce and a tag.
+        TooShort,
+        /// The data is too long to encrypt.
+        TooLong,
+        /// The data wasn't encrypted with the key or was changed after.
+        Decrypt,
+        /// The envelope's header is malformed.
+        Malformed,
+    }
+
+    impl std::fmt::Display for CryptoError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                CryptoError::TooShort => write!(f, "The data is too short to decrypt."),
+                CryptoError::TooLong => write!(f, "The data is too long to encrypt."),
+                CryptoError::Decrypt => write!(f, "The data couldn't be decrypted with the key."),
+                CryptoError::Malformed => write!(f, "The envelope's header is malformed."),
+            }
+        }
+    }
+
+    impl std::error::Error for CryptoError {}
+
+    /// The length of the nonce `encrypt` puts in front of the data.
+    pub const NONCE_LEN: usize = 12;
+    /// The length of the tag at the end of encrypted data.
+    pub const TAG_LEN: usize = 16;
+
+    /// Marks a key file that's encrypted with the store's wrapping key.
+    const WRAPPED_MAGIC: &[u8; 4] = b"SNWK";
+    /// The file in the keys directory that holds the salt for the passphrase.
+    const SALT_FILE: &str = ".salt";
+    const SALT_LEN: usize = 16;
+
+    /// Where keys are saved and how they're protected on disk.
+    ///
+    /// Key files are only readable by their owner. Files that other users can
+    /// read are refused. If the store has a passphrase, key files are also
+    /// encrypted with a key derived from it, so a copy of the directory is
+    /// useless without the passphrase. Unencrypted files are encrypted in place
+    /// the first time they're read with a passphrase, so existing keys keep working.
+    /// Saving never overwrites an existing file.
+    #[derive(Clone)]
+    pub struct KeyStore {
+        dir: PathBuf,
+        wrapping_key: Option<Key<Aes256Gcm>>,
+    }
+
+    static KEY_STORE: LazyLock<RwLock<KeyStore>> = LazyLock::new(|| RwLock::new(KeyStore::new("keys")));
+
+    /// Sets the store used by `save_key`, `load_all_keys`, and the rest. It's `keys` by default.
+    pub fn set_key_store(store: KeyStore) {
+        *KEY_STORE.write().expect("The key store lock was poisoned.") = store;
+    }
+
+    pub fn key_store() -> KeyStore {
+        KEY_STORE.read().expect("The key store lock was poisoned.").clone()
+    }
+
+    impl KeyStore {
+        pub fn new(dir: impl Into<PathBuf>) -> Self {
+            KeyStore { dir: dir.into(), wrapping_key: None }
+        }
+
+        /// Encrypts the key files with a key derived from the passphrase with
+        /// Argon2. The salt is made the first time and kept in the directory.
+        pub fn with_passphrase(mut self, passphrase: &str) -> std::io::Result<Self> {
+            self.create_dir()?;
+            let salt = match self.read_private(SALT_FILE) {
+                Ok(salt) if salt.len() == SALT_LEN => salt,
+                Ok(_) => {
+                    return Err(
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "The salt file is the wrong size.")
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let mut salt = vec![0u8; SALT_LEN];
+                    OsRng.fill_bytes(&mut salt);
+                    create_private(&self.dir.join(SALT_FILE))?.write_all(&salt)?;
+                    salt
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+
+            let mut key = Key::<Aes256Gcm>::default();
+            argon2::Argon2
+                ::default()
+                .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut_slice())
+                .map_err(|e| std::io::Error::other(format!("Failed to derive the wrapping key: {e}")))?;
+            self.wrapping_key = Some(key);
+            Ok(self)
+        }
+
+        pub fn dir(&self) -> &Path {
+            &self.dir
+        }
+
+        pub fn is_wrapped(&self) -> bool {
+            self.wrapping_key.is_some()
+        }
+
+        /// Creates the directory so only its owner can use it.
+        pub fn create_dir(&self) -> std::io::Result<()> {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder
+                .create(&self.dir)
+                .map_err(|e| std::io::Error::other(format!("Failed to create the '{}' directory: {e}", self.dir.display())))
+        }
+
+        /// Saves the data at `file_name` in the directory, which can be in a
+        /// subdirectory. Fails if the file already exists.
+        pub fn write(&self, file_name: &str, data: &[u8]) -> std::io::Result<()> {
+            let path = self.dir.join(file_name);
+            if let Some(parent) = path.parent() {
+                KeyStore::new(parent).create_dir()?;
+            }
+            create_private(&path)?.write_all(&self.wrap(file_name, data)?)
+        }
+
+        /// Replaces the file at `file_name` by writing a new one next to it and
+        /// renaming it over, so the old one is never left half written.
+        pub fn replace(&self, file_name: &str, data: &[u8]) -> std::io::Result<()> {
+            let path = self.dir.join(file_name);
+            let mut suffix = [0u8; 8];
+            OsRng.fill_bytes(&mut suffix);
+            let temp = path.with_file_name(
+                format!(
+                    ".{}.{}.tmp",
+                    path.file_name().and_then(|name| name.to_str()).unwrap_or("key"),
+                    u64::from_be_bytes(suffix)
+                )
+            );
+            let written = create_private(&temp).and_then(|mut file| file.write_all(&self.wrap(file_name, data)?));
+            match written.and_then(|_| std::fs::rename(&temp, &path)) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    let _ = std::fs::remove_file(&temp);
+                    Err(e)
+                }
+            }
+        }
+
+        /// Encrypts the data with the wrapping key if there is one.
+        fn wrap(&self, file_name: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(match &self.wrapping_key {
+                Some(key) => {
+                    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                    let aad = wrapped_aad(file_name);
+                    let ciphertext = Aes256Gcm::new(key)
+                        .encrypt(&nonce, Payload { msg: data, aad: &aad })
+                        .map_err(|_| std::io::Error::other(CryptoError::TooLong))?;
+                    [WRAPPED_MAGIC.as_slice(), nonce.as_slice(), ciphertext.as_slice()].concat()
+                }
+                None => data.to_vec(),
+            })
+        }
+
+        /// Reads the file at `file_name`. If the store has a passphrase and the
+        /// file isn't encrypted yet, it's encrypted on disk before it's returned.
+        pub fn read(&self, file_name: &str) -> std::io::Result<Vec<u8>> {
+            let data = self.read_private(file_name)?;
+            let Some(wrapped) = data.strip_prefix(WRAPPED_MAGIC) else {
+                if self.wrapping_key.is_some() {
+                    self.replace(file_name, &data).map_err(|e|
+                        std::io::Error::new(e.kind(), format!("Failed to encrypt '{file_name}': {e}"))
+                    )?;
+                }
+                return Ok(data);
+            };
+            let Some(key) = &self.wrapping_key else {
+                return Err(
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("'{file_name}' is encrypted, but no key passphrase is set.")
+                    )
+                );
+            };
+            if wrapped.len() < NONCE_LEN + TAG_LEN {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, CryptoError::TooShort));
+            }
+            let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+            let aad = wrapped_aad(file_name);
+            Aes256Gcm::new(key)
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+                .map_err(|_|
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("'{file_name}' couldn't be decrypted. Is the key passphrase right?")
+                    )
+                )
+        }
+
+        fn read_private(&self, file_name: &str) -> std::io::Result<Vec<u8>> {
+            let path = self.dir.join(file_name);
+            let mut file = File::open(&path)?;
+            check_private(&path, &file)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+
+    /// Ties a wrapped file to its name so it can't be swapped with another key's file.
+    fn wrapped_aad(file_name: &str) -> Vec<u8> {
+        [b"sustenet key file".as_slice(), file_name.as_bytes()].concat()
+    }
+
+    /// Creates the file so only its owner can read and write it. Fails if it
+    /// already exists, so a key is never truncated.
+    fn create_private(path: &Path) -> std::io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options.open(path)?;
+        // The umask can still take bits away from the mode.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(file)
+    }
+
+    #[cfg(unix)]
+    fn check_private(path: &Path, file: &File) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if file.metadata()?.permissions().mode() & 0o077 != 0 {
             return Err(
                 std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to create the 'keys' directory."
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("'{}' can be read by other users. Run `chmod 600` on it.", path.display())
                 )
             );
         }
+        Ok(())
+    }
 
+    #[cfg(not(unix))]
+    fn check_private(_path: &Path, _file: &File) -> std::io::Result<()> {
         Ok(())
     }
 
+    pub fn create_keys_dir() -> std::io::Result<()> {
+        key_store().create_dir()
+    }
+
     pub fn generate_key() -> Key<Aes256Gcm> {
         Aes256Gcm::generate_key(OsRng)
     }
 
+    /// A short fingerprint of the key. It's safe to share and stays the same
+    /// for as long as the key does.
+    pub fn fingerprint(key: &Key<Aes256Gcm>) -> String {
+        let hash = Sha256::digest(key.as_slice());
+        super::base64engine::base64_encode(&hash[..16])
+    }
+
     pub fn save_key(name: &str, key: Key<Aes256Gcm>) -> std::io::Result<()> {
-        let mut file = File::create(format!("keys/{name}"))?;
-        file.write_all(key.as_slice())?;
-        Ok(())
+        key_store().write(name, key.as_slice())
     }
 
     pub fn load_key(name: &str) -> std::io::Result<Key<Aes256Gcm>> {
-        let mut file = match File::open(format!("keys/{name}")) {
-            Ok(file) => file,
-            Err(_) => {
-                return Err(
-                    std::io::Error::new(std::io::ErrorKind::NotFound, "Failed to open file.")
-                );
+        let buf = key_store().read(name)?;
+        if buf.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Key is empty."));
+        }
+        if buf.len() != 32 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Key is not 32 bytes."));
+        }
+        Ok(Key::<Aes256Gcm>::from_slice(buf.as_slice()).to_owned())
+    }
+
+    /// One version of a key. Versions let a key be rotated without every
+    /// server switching over at the same moment.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct KeyVersion {
+        pub version: u32,
+        pub key: Key<Aes256Gcm>,
+        /// Unix time in seconds before which the version can't be used.
+        pub not_before: Option<u64>,
+        /// Unix time in seconds after which the version can't be used.
+        pub not_after: Option<u64>,
+    }
+
+    impl KeyVersion {
+        pub fn new(version: u32, key: Key<Aes256Gcm>) -> Self {
+            KeyVersion { version, key, not_before: None, not_after: None }
+        }
+
+        pub fn is_valid_at(&self, now: u64) -> bool {
+            self.not_before.is_none_or(|not_before| now >= not_before) &&
+                self.not_after.is_none_or(|not_after| now <= not_after)
+        }
+
+        /// The key followed by `not_before` and `not_after` as big-endian u64s,
+        /// where 0 means there's no limit. The timestamps are left out if neither is set.
+        /// This is what's saved in the key file.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut data = self.key.to_vec();
+            if self.not_before.is_some() || self.not_after.is_some() {
+                data.extend_from_slice(&self.not_before.unwrap_or(0).to_be_bytes());
+                data.extend_from_slice(&self.not_after.unwrap_or(0).to_be_bytes());
             }
-        };
-        let mut buf = vec![];
-        match file.read_to_end(&mut buf) {
-            Ok(_) => {
-                if buf.is_empty() {
-                    return Err(
-                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Key is empty.")
-                    );
+            data
+        }
+
+        pub fn from_bytes(version: u32, data: &[u8]) -> std::io::Result<Self> {
+            if data.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Key is empty."));
+            }
+
+            let limit = |bytes: &[u8]| {
+                match u64::from_be_bytes(bytes.try_into().unwrap()) {
+                    0 => None,
+                    time => Some(time),
                 }
+            };
+            match data.len() {
+                32 => Ok(KeyVersion::new(version, Key::<Aes256Gcm>::from_slice(data).to_owned())),
+                48 =>
+                    Ok(KeyVersion {
+                        version,
+                        key: Key::<Aes256Gcm>::from_slice(&data[..32]).to_owned(),
+                        not_before: limit(&data[32..40]),
+                        not_after: limit(&data[40..48]),
+                    }),
+                _ =>
+                    Err(
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "Key is not 32 or 48 bytes."
+                        )
+                    ),
+            }
+        }
+    }
 
-                if buf.len() != 32 {
-                    return Err(
-                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Key is not 32 bytes.")
-                    );
+    /// Every version of a key, sorted from oldest to newest.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct KeyRing {
+        versions: Vec<KeyVersion>,
+    }
+
+    impl KeyRing {
+        /// Adds the version, replacing it if it's already in the ring.
+        pub fn insert(&mut self, key: KeyVersion) {
+            match self.versions.binary_search_by_key(&key.version, |existing| existing.version) {
+                Ok(index) => {
+                    self.versions[index] = key;
                 }
+                Err(index) => self.versions.insert(index, key),
             }
-            Err(_) => {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to read file."));
+        }
+
+        pub fn get(&self, version: u32) -> Option<&KeyVersion> {
+            self.versions.iter().find(|key| key.version == version)
+        }
+
+        pub fn versions(&self) -> &[KeyVersion] {
+            &self.versions
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.versions.is_empty()
+        }
+
+        /// The newest version that's valid right now. This is the one to sign and encrypt with.
+        pub fn current(&self) -> Option<&KeyVersion> {
+            self.current_at(unix_time())
+        }
+
+        pub fn current_at(&self, now: u64) -> Option<&KeyVersion> {
+            self.versions.iter().rev().find(|key| key.is_valid_at(now))
+        }
+
+        /// Returns the version if it's still accepted. Only the current version
+        /// and the one before it are, so servers have until the previous
+        /// version's `not_after` to pick up the new one.
+        pub fn accepts(&self, version: u32) -> Option<&KeyVersion> {
+            self.accepts_at(version, unix_time())
+        }
+
+        pub fn accepts_at(&self, version: u32, now: u64) -> Option<&KeyVersion> {
+            self.versions
+                .iter()
+                .rev()
+                .filter(|key| key.is_valid_at(now))
+                .take(2)
+                .find(|key| key.version == version)
+        }
+    }
+
+    pub type KeyMap = HashMap<String, KeyRing>;
+
+    /// Splits the name of a file in `keys` into the key's name and version.
+    /// `name.2` is version 2 of `name`. A file without a version is version 0.
+    pub fn parse_key_file_name(file_name: &str) -> (String, u32) {
+        match file_name.rsplit_once('.') {
+            Some((name, version)) if !name.is_empty() => {
+                match version.parse::<u32>() {
+                    Ok(version) => (name.to_string(), version),
+                    Err(_) => (file_name.to_string(), 0),
+                }
             }
+            _ => (file_name.to_string(), 0),
         }
-        Ok(Key::<Aes256Gcm>::from_slice(buf.as_slice()).to_owned())
     }
 
-    pub type KeyMap = HashMap<String, Key<Aes256Gcm>>;
+    fn key_file_name(name: &str, version: u32) -> String {
+        match version {
+            0 => name.to_string(),
+            _ => format!("{name}.{version}"),
+        }
+    }
+
+    /// Saves a new version. Fails if the version already has a file.
+    pub fn save_key_version(name: &str, key: &KeyVersion) -> std::io::Result<()> {
+        key_store().write(&key_file_name(name, key.version), &key.to_bytes())
+    }
+
+    /// Replaces a version that's already saved, like to change when it expires.
+    pub fn update_key_version(name: &str, key: &KeyVersion) -> std::io::Result<()> {
+        key_store().replace(&key_file_name(name, key.version), &key.to_bytes())
+    }
 
-    pub fn load_all_keys() -> std::io::Result<HashMap<String, Key<Aes256Gcm>>> {
-        let mut keys = HashMap::new();
+    fn load_key_file(store: &KeyStore, file_name: &str, version: u32) -> std::io::Result<KeyVersion> {
+        KeyVersion::from_bytes(version, &store.read(file_name)?)
+    }
 
-        let entries = match std::fs::read_dir("keys") {
+    /// Loads every version of every key in the keys directory. Fails if a key
+    /// file can't be read, like one that other users can read or one that
+    /// doesn't decrypt with the passphrase. Subdirectories and hidden files are skipped.
+    pub fn load_all_keys() -> std::io::Result<KeyMap> {
+        let (keys, skipped) = load_readable_keys()?;
+        match skipped.into_iter().next() {
+            Some((file_name, e)) => Err(skipped_error(&file_name, e)),
+            None => Ok(keys),
+        }
+    }
+
+    /// Like `load_all_keys`, but the files that can't be read are returned
+    /// with their errors instead of failing, so they can be logged.
+    pub fn load_readable_keys() -> std::io::Result<(KeyMap, Vec<(String, std::io::Error)>)> {
+        read_key_files(&key_store())
+    }
+
+    pub(crate) fn read_key_files(store: &KeyStore) -> std::io::Result<(KeyMap, Vec<(String, std::io::Error)>)> {
+        let mut keys = KeyMap::new();
+        let mut skipped = Vec::new();
+
+        let entries = match std::fs::read_dir(store.dir()) {
             Ok(entries) => entries,
             Err(_) => {
                 return Err(
-                    std::io::Error::new(std::io::ErrorKind::NotFound, "Directory 'keys' missing.")
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Directory '{}' missing.", store.dir().display())
+                    )
                 );
             }
         };
 
         for entry in entries {
             let entry = match entry {
-                Ok(entry) => entry,
-                Err(_) => {
+                Ok(entry) if entry.file_type().is_ok_and(|file_type| file_type.is_file()) => entry,
+                _ => {
                     continue;
                 }
             };
-            let name = match entry.file_name().to_str() {
-                Some(name) => name.to_string(),
-                None => {
+            let file_name = match entry.file_name().to_str() {
+                Some(file_name) if !file_name.starts_with('.') => file_name.to_string(),
+                _ => {
                     continue;
                 }
             };
-            let key = match load_key(name.as_str()) {
+            let (name, version) = parse_key_file_name(&file_name);
+            let key = match load_key_file(store, &file_name, version) {
                 Ok(key) => key,
-                Err(_) => {
+                Err(e) => {
+                    skipped.push((file_name, e));
                     continue;
                 }
             };
-            keys.insert(name, key);
+            keys.entry(name).or_default().insert(key);
+        }
+        Ok((keys, skipped))
+    }
+
+    fn skipped_error(file_name: &str, e: std::io::Error) -> std::io::Error {
+        std::io::Error::new(e.kind(), format!("Failed to load the key file '{file_name}': {e}"))
+    }
+
+    /// Loads every version of a single key. Fails if one of its files can't be
+    /// read. It's only `NotFound` if the key has no files at all.
+    pub fn load_key_ring(name: &str) -> std::io::Result<KeyRing> {
+        read_key_ring(&key_store(), name)
+    }
+
+    pub(crate) fn read_key_ring(store: &KeyStore, name: &str) -> std::io::Result<KeyRing> {
+        let (mut keys, skipped) = read_key_files(store)?;
+        if let Some((file_name, e)) = skipped.into_iter().find(|(file_name, _)| parse_key_file_name(file_name).0 == name) {
+            return Err(skipped_error(&file_name, e));
+        }
+        match keys.remove(name) {
+            Some(ring) => Ok(ring),
+            None =>
+                Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Key {name} doesn't exist."))),
         }
-        Ok(keys)
     }
 
-    pub fn encrypt(data: &[u8], key: &Key<Aes256Gcm>) -> Vec<u8> {
+    /// Encrypts with a random nonce, which is put in front of the output.
+    pub fn encrypt(data: &[u8], key: &Key<Aes256Gcm>) -> Result<Vec<u8>, CryptoError> {
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng); // 96-bits; unique per message
-        let cipher = Aes256Gcm::new(&key);
+        let cipher = Aes256Gcm::new(key);
+
+        let ciphered_data = cipher.encrypt(&nonce, data).map_err(|_| CryptoError::TooLong)?;
+        Ok([nonce.as_slice(), ciphered_data.as_slice()].concat())
+    }
+
+    /// Encrypts with a nonce the caller picked. The nonce isn't included in the
+    /// output, and it must never be used twice with the same key.
+    pub fn encrypt_with_nonce(
+        data: &[u8],
+        key: &Key<Aes256Gcm>,
+        nonce: &[u8; NONCE_LEN]
+    ) -> Result<Vec<u8>, CryptoError> {
+        Aes256Gcm::new(key).encrypt(Nonce::from_slice(nonce), data).map_err(|_| CryptoError::TooLong)
+    }
+
+    /// Decrypts data from `encrypt`.
+    pub fn decrypt(data: &[u8], key: &Key<Aes256Gcm>) -> Result<Vec<u8>, CryptoError> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::TooShort);
+        }
+        let (nonce, data) = data.split_at(NONCE_LEN);
+        Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), data).map_err(|_| CryptoError::Decrypt)
+    }
+
+    pub fn decrypt_with_nonce(
+        data: &[u8],
+        key: &Key<Aes256Gcm>,
+        nonce: &[u8; NONCE_LEN]
+    ) -> Result<Vec<u8>, CryptoError> {
+        if data.len() < TAG_LEN {
+            return Err(CryptoError::TooShort);
+        }
+        Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), data).map_err(|_| CryptoError::Decrypt)
+    }
+
+    /// How old a rotation proof can be, in seconds.
+    const ROTATION_PROOF_AGE: u64 = 60;
+
+    /// Proves a verified cluster has a new version of its key. It's the time,
+    /// sealed with the new version for `RotateKey` on this cluster.
+    pub fn rotation_proof(cluster_id: u32, key_name: &str, key: &KeyVersion) -> Result<Vec<u8>, CryptoError> {
+        let header = Header { key_id: key_name.to_string(), key_version: key.version };
+        seal(&unix_time().to_be_bytes(), &header, &key.key, &rotation_context(&cluster_id.to_be_bytes()))
+    }
+
+    pub fn check_rotation_proof(proof: &[u8], key_name: &str, key: &KeyVersion, cluster_id: u32) -> bool {
+        match header(proof) {
+            Ok(header) if header.key_id == key_name && header.key_version == key.version => (),
+            _ => {
+                return false;
+            }
+        }
+        let Ok(data) = open(proof, &key.key, &rotation_context(&cluster_id.to_be_bytes())) else {
+            return false;
+        };
+        let Ok(time) = <[u8; 8]>::try_from(data.as_slice()) else {
+            return false;
+        };
+        unix_time().abs_diff(u64::from_be_bytes(time)) <= ROTATION_PROOF_AGE
+    }
+
+    fn rotation_context(cluster_id: &[u8]) -> Context<'_> {
+        Context { packet: FromUnknown::RotateKey as u8, connection: cluster_id }
+    }
+}
+
+/// Sealed messages that say which key opens them and what they're for.
+///
+/// An envelope is a header followed by the ciphertext. The header is the
+/// format, the key's ID as a u8 length-prefixed string, the key's version as a
+/// u32, a commitment to the key, and the nonce. The header and the `Context`
+/// are authenticated with the data, so an envelope only opens for the packet
+/// and connection it was sealed for. AES-GCM on its own lets a crafted
+/// ciphertext open under more than one key, which the commitment prevents.
+pub mod envelope {
+    use aes_gcm::{ Aes256Gcm, Key, Nonce, aead::{ Aead, AeadCore, KeyInit, OsRng, Payload } };
+    use sha2::{ Digest, Sha256 };
+
+    use crate::utils::constants::PROTOCOL_VERSION;
+
+    use super::aes::{ CryptoError, NONCE_LEN, TAG_LEN };
+
+    /// The version of the envelope format.
+    pub const FORMAT: u8 = 1;
+    const COMMITMENT_LEN: usize = 32;
+
+    /// What an envelope is for. Both sides have to build the same one.
+    pub struct Context<'a> {
+        /// The packet the envelope is sent in.
+        pub packet: u8,
+        /// Whatever ties the envelope to one connection, like a nonce or a cluster ID.
+        pub connection: &'a [u8],
+    }
+
+    /// Which key opens an envelope.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Header {
+        pub key_id: String,
+        pub key_version: u32,
+    }
+
+    fn aad(header: &[u8], context: &Context) -> Vec<u8> {
+        [
+            b"sustenet envelope".as_slice(),
+            &[PROTOCOL_VERSION, context.packet],
+            &(context.connection.len() as u16).to_be_bytes(),
+            context.connection,
+            header,
+        ].concat()
+    }
+
+    fn commitment(key: &Key<Aes256Gcm>, nonce: &[u8]) -> [u8; COMMITMENT_LEN] {
+        Sha256::new()
+            .chain_update(b"sustenet key commitment")
+            .chain_update(key.as_slice())
+            .chain_update(nonce)
+            .finalize()
+            .into()
+    }
+
+    pub fn seal(
+        data: &[u8],
+        header: &Header,
+        key: &Key<Aes256Gcm>,
+        context: &Context
+    ) -> Result<Vec<u8>, CryptoError> {
+        if header.key_id.len() > (u8::MAX as usize) {
+            return Err(CryptoError::Malformed);
+        }
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let mut envelope = vec![FORMAT, header.key_id.len() as u8];
+        envelope.extend_from_slice(header.key_id.as_bytes());
+        envelope.extend_from_slice(&header.key_version.to_be_bytes());
+        envelope.extend_from_slice(&commitment(key, &nonce));
+        envelope.extend_from_slice(&nonce);
+
+        let payload = Payload { msg: data, aad: &aad(&envelope, context) };
+        let ciphertext = Aes256Gcm::new(key).encrypt(&nonce, payload).map_err(|_| CryptoError::TooLong)?;
+        envelope.extend_from_slice(&ciphertext);
+        Ok(envelope)
+    }
+
+    /// Returns the header and how long it is.
+    fn parse(data: &[u8]) -> Result<(Header, usize), CryptoError> {
+        if data.first() != Some(&FORMAT) {
+            return Err(CryptoError::Malformed);
+        }
+        let id_len = *data.get(1).ok_or(CryptoError::TooShort)? as usize;
+        let len = 2 + id_len + 4 + COMMITMENT_LEN + NONCE_LEN;
+        if data.len() < len + TAG_LEN {
+            return Err(CryptoError::TooShort);
+        }
+
+        let key_id = String::from_utf8(data[2..2 + id_len].to_vec()).map_err(|_| CryptoError::Malformed)?;
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&data[2 + id_len..6 + id_len]);
+        Ok((Header { key_id, key_version: u32::from_be_bytes(version) }, len))
+    }
+
+    /// Reads the header without opening the envelope, so the receiver knows which key to use.
+    pub fn header(data: &[u8]) -> Result<Header, CryptoError> {
+        parse(data).map(|(header, _)| header)
+    }
+
+    pub fn open(data: &[u8], key: &Key<Aes256Gcm>, context: &Context) -> Result<Vec<u8>, CryptoError> {
+        let (_, len) = parse(data)?;
+        let (header, ciphertext) = data.split_at(len);
+        let (committed, nonce) = header[len - NONCE_LEN - COMMITMENT_LEN..].split_at(COMMITMENT_LEN);
+        if commitment(key, nonce) != committed {
+            return Err(CryptoError::Decrypt);
+        }
+
+        let payload = Payload { msg: ciphertext, aad: &aad(header, context) };
+        Aes256Gcm::new(key).decrypt(Nonce::from_slice(nonce), payload).map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+/// Tickets the Master Server issues when it routes a client to a cluster.
+/// They're sealed with the key the Master Server and the cluster share, so
+/// the cluster knows the Master Server approved the player.
+pub mod ticket {
+    use aes_gcm::{ Aes256Gcm, Key };
+
+    use crate::utils::unix_time;
+
+    use crate::packets::cluster::FromClient;
+
+    use super::aes::CryptoError;
+    use super::envelope::{ self, Context, Header };
+
+    /// Tickets are tied to the public key the client starts its session with
+    /// the cluster with, so a ticket that's seen by someone else can't be used
+    /// on another connection. The client sends that key to the Master Server
+    /// when it asks to join.
+    fn context(client_public: &[u8]) -> Context<'_> {
+        Context { packet: FromClient::SendTicket as u8, connection: client_public }
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum TicketError {
+        /// The ticket couldn't be decrypted or parsed.
+        Invalid,
+        /// The ticket was sealed with a key version that isn't accepted.
+        UnknownKey,
+        /// The ticket was issued for a different cluster.
+        WrongCluster,
+        /// The ticket is past its expiry.
+        Expired,
+    }
+
+    impl std::fmt::Display for TicketError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                TicketError::Invalid => write!(f, "The ticket is invalid."),
+                TicketError::UnknownKey => write!(f, "The ticket was sealed with an unknown key version."),
+                TicketError::WrongCluster => write!(f, "The ticket is for a different cluster."),
+                TicketError::Expired => write!(f, "The ticket has expired."),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct JoinTicket {
+        pub player_id: String,
+        pub cluster_id: u32,
+        /// Unix time in seconds.
+        pub expires_at: u64,
+    }
+
+    impl JoinTicket {
+        pub fn new(player_id: String, cluster_id: u32, ttl: u64) -> Self {
+            JoinTicket {
+                player_id,
+                cluster_id,
+                expires_at: unix_time() + ttl,
+            }
+        }
+
+        /// Seals the ticket in an envelope with a version of the cluster's key.
+        /// The envelope's header tells the cluster which version opens it.
+        /// It only opens for a session started with `client_public`.
+        pub fn seal(
+            &self,
+            key_name: &str,
+            key_version: u32,
+            key: &Key<Aes256Gcm>,
+            client_public: &[u8]
+        ) -> Result<Vec<u8>, CryptoError> {
+            let mut data = Vec::with_capacity(13 + self.player_id.len());
+            data.extend_from_slice(&self.cluster_id.to_be_bytes());
+            data.extend_from_slice(&self.expires_at.to_be_bytes());
+            data.push(self.player_id.len() as u8);
+            data.extend_from_slice(self.player_id.as_bytes());
+            let header = Header { key_id: key_name.to_string(), key_version };
+            envelope::seal(&data, &header, key, &context(client_public))
+        }
+
+        /// The name and version of the key the ticket was sealed with.
+        pub fn header(data: &[u8]) -> Result<Header, TicketError> {
+            envelope::header(data).map_err(|_| TicketError::Invalid)
+        }
 
-        let ciphered_data = cipher.encrypt(&nonce, data).expect("Failed to encrypt data.");
-        [nonce.as_slice(), ciphered_data.as_slice()].concat()
+        /// Decrypts a ticket without checking who it's for or if it has expired.
+        pub fn open(data: &[u8], key: &Key<Aes256Gcm>, client_public: &[u8]) -> Result<Self, TicketError> {
+            let data = envelope::open(data, key, &context(client_public)).map_err(|_| TicketError::Invalid)?;
+
+            if data.len() < 13 || data.len() != 13 + (data[12] as usize) {
+                return Err(TicketError::Invalid);
+            }
+            let cluster_id = u32::from_be_bytes(data[0..4].try_into().unwrap());
+            let expires_at = u64::from_be_bytes(data[4..12].try_into().unwrap());
+            let player_id = String::from_utf8(data[13..].to_vec()).map_err(
+                |_| TicketError::Invalid
+            )?;
+
+            Ok(JoinTicket { player_id, cluster_id, expires_at })
+        }
+
+        /// Decrypts a ticket and makes sure it's for this cluster and hasn't expired.
+        pub fn verify(
+            data: &[u8],
+            key: &Key<Aes256Gcm>,
+            cluster_id: u32,
+            client_public: &[u8]
+        ) -> Result<Self, TicketError> {
+            let ticket = Self::open(data, key, client_public)?;
+            if ticket.cluster_id != cluster_id {
+                return Err(TicketError::WrongCluster);
+            }
+            if ticket.expires_at < unix_time() {
+                return Err(TicketError::Expired);
+            }
+            Ok(ticket)
+        }
+    }
+}
+
+/// Sealed connections. Once both sides agree on a secret, each direction gets
+/// its own key and every message after that is sent as a sealed frame: a u32
+/// length followed by the ciphertext. Frames are numbered by a counter that's
+/// used as the nonce, so a frame that's replayed, dropped, or reordered fails
+/// to open.
+pub mod session {
+    use std::io::{ Error, ErrorKind };
+    use std::pin::Pin;
+    use std::task::{ Context, Poll, ready };
+
+    use aes_gcm::aead::{ OsRng, rand_core::RngCore };
+    use aes_gcm::{ Aes256Gcm, Key };
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+    use tokio::io::{ AsyncRead, ReadBuf };
+
+    use super::aes::{ CryptoError, TAG_LEN, decrypt_with_nonce, encrypt_with_nonce };
+
+    /// How long the nonce each side contributes to the master link is.
+    pub const NONCE_LEN: usize = 32;
+    /// The largest sealed frame that will be read.
+    pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+    pub fn generate_nonce() -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    fn counter_nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Seals the frames going one way.
+    pub struct Sealer {
+        key: Key<Aes256Gcm>,
+        counter: u64,
+    }
+
+    impl Sealer {
+        /// Fails if the frame would be longer than `MAX_FRAME_LEN`.
+        pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+            if data.len() + TAG_LEN > MAX_FRAME_LEN {
+                return Err(CryptoError::TooLong);
+            }
+            let sealed = encrypt_with_nonce(data, &self.key, &counter_nonce(self.counter))?;
+            self.counter += 1;
+            Ok([(sealed.len() as u32).to_be_bytes().as_slice(), sealed.as_slice()].concat())
+        }
+    }
+
+    /// Opens the frames coming the other way. They have to arrive in order.
+    pub struct Opener {
+        key: Key<Aes256Gcm>,
+        counter: u64,
+    }
+
+    impl Opener {
+        /// Opens a frame without its length prefix.
+        pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, CryptoError> {
+            let data = decrypt_with_nonce(frame, &self.key, &counter_nonce(self.counter))?;
+            self.counter += 1;
+            Ok(data)
+        }
     }
 
-    pub fn decrypt(data: &[u8], key: &Key<Aes256Gcm>) -> Vec<u8> {
-        let (nonce, data) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce);
-        let cipher = Aes256Gcm::new(&key);
-        cipher
-            .decrypt(nonce, data)
-            .expect("Failed to decrypt data. Maybe the key doesn't match the name?")
+    /// Derives a key for each direction from a shared secret. The initiator is
+    /// whoever opened the connection. Both sides have to use the same `salt` and `info`.
+    pub fn derive_keys(secret: &[u8], salt: &[u8], info: &[u8], initiator: bool) -> (Sealer, Opener) {
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>
+            ::new(Some(salt), secret)
+            .expand(info, &mut okm)
+            .expect("64 bytes is a valid length for HKDF-SHA256.");
+        let (initiator_key, responder_key) = okm.split_at(32);
+        let (send, receive) = match initiator {
+            true => (initiator_key, responder_key),
+            false => (responder_key, initiator_key),
+        };
+
+        (
+            Sealer { key: Key::<Aes256Gcm>::clone_from_slice(send), counter: 0 },
+            Opener { key: Key::<Aes256Gcm>::clone_from_slice(receive), counter: 0 },
+        )
+    }
+
+    /// Passes reads straight through until an `Opener` is set. After that it
+    /// reads sealed frames and hands out what's inside them.
+    ///
+    /// It has to wrap the `BufReader` rather than sit inside it, or bytes the
+    /// `BufReader` read ahead of the switch would skip decryption.
+    pub struct SecureReader<R> {
+        inner: R,
+        opener: Option<Opener>,
+        /// The length prefix of the frame being read.
+        header: [u8; 4],
+        header_read: usize,
+        /// The frame being read. Empty until its header is read.
+        frame: Vec<u8>,
+        frame_read: usize,
+        /// Opened bytes that haven't been read yet.
+        plaintext: Vec<u8>,
+        plaintext_read: usize,
+    }
+
+    impl<R> SecureReader<R> {
+        pub fn new(inner: R) -> Self {
+            SecureReader {
+                inner,
+                opener: None,
+                header: [0u8; 4],
+                header_read: 0,
+                frame: Vec::new(),
+                frame_read: 0,
+                plaintext: Vec::new(),
+                plaintext_read: 0,
+            }
+        }
+
+        /// Everything read after this has to be a sealed frame.
+        pub fn set_opener(&mut self, opener: Opener) {
+            self.opener = Some(opener);
+        }
+
+        pub fn is_secure(&self) -> bool {
+            self.opener.is_some()
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> SecureReader<R> {
+        /// Reads into `buf` until it's full. Returns `false` on EOF.
+        fn poll_fill(
+            inner: &mut R,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+            filled: &mut usize
+        ) -> Poll<std::io::Result<bool>> {
+            while *filled < buf.len() {
+                let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+                ready!(Pin::new(&mut *inner).poll_read(cx, &mut read_buf))?;
+                if read_buf.filled().is_empty() {
+                    return Poll::Ready(Ok(false));
+                }
+                *filled += read_buf.filled().len();
+            }
+            Poll::Ready(Ok(true))
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for SecureReader<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            let Some(opener) = this.opener.as_mut() else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+
+            // Frames can be empty, so keep going until there's something to hand out.
+            while this.plaintext_read == this.plaintext.len() {
+                if this.frame.is_empty() {
+                    if !ready!(Self::poll_fill(&mut this.inner, cx, &mut this.header, &mut this.header_read))? {
+                        // A clean EOF between frames.
+                        return Poll::Ready(Ok(()));
+                    }
+                    let len = u32::from_be_bytes(this.header) as usize;
+                    if len == 0 || len > MAX_FRAME_LEN {
+                        return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, "Invalid frame length.")));
+                    }
+                    this.frame = vec![0u8; len];
+                    this.frame_read = 0;
+                }
+
+                if !ready!(Self::poll_fill(&mut this.inner, cx, &mut this.frame, &mut this.frame_read))? {
+                    return Poll::Ready(Err(Error::new(ErrorKind::UnexpectedEof, "The frame was cut off.")));
+                }
+                this.plaintext = match opener.open(&this.frame) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => {
+                        return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, e)));
+                    }
+                };
+                this.plaintext_read = 0;
+                this.frame = Vec::new();
+                this.header_read = 0;
+            }
+
+            let len = buf.remaining().min(this.plaintext.len() - this.plaintext_read);
+            buf.put_slice(&this.plaintext[this.plaintext_read..this.plaintext_read + len]);
+            this.plaintext_read += len;
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+/// The key exchange a client runs with a cluster. Each side makes an
+/// ephemeral X25519 key, and the cluster signs both public keys with its
+/// long-term Ed25519 identity so the client knows who it's talking to.
+pub mod exchange {
+    use std::fs::File;
+    use std::io::Write;
+
+    use aes_gcm::aead::{ OsRng, rand_core::RngCore };
+    use ed25519_dalek::{ Signature, Signer, SigningKey, Verifier, VerifyingKey };
+    use x25519_dalek::{ EphemeralSecret, PublicKey };
+
+    use super::aes::key_store;
+    use super::session::{ Opener, Sealer, derive_keys };
+
+    pub const PUBLIC_KEY_LEN: usize = 32;
+    pub const SIGNATURE_LEN: usize = 64;
+
+    /// A cluster's long-term signing key. The version goes up each time the
+    /// key is replaced so clients know to fetch the new one.
+    pub struct Identity {
+        version: u32,
+        key: SigningKey,
+    }
+
+    impl Identity {
+        pub fn generate(version: u32) -> Self {
+            let mut seed = [0u8; 32];
+            OsRng.fill_bytes(&mut seed);
+            Identity { version, key: SigningKey::from_bytes(&seed) }
+        }
+
+        pub fn version(&self) -> u32 {
+            self.version
+        }
+
+        pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+            self.key.verifying_key().to_bytes()
+        }
+
+        pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+            self.key.sign(data).to_bytes()
+        }
+
+        /// Loads the identity from `identity/<name>` in the key store, making one if it doesn't exist.
+        ///
+        /// The file is a u32 version followed by the 32-byte seed. A file with
+        /// only the seed is version 0. The public key is also written to
+        /// `identity/<name>.pub` so it can be hosted at `domain_pub_key`.
+        pub fn load_or_generate(name: &str) -> std::io::Result<Self> {
+            let store = key_store();
+            let file_name = format!("identity/{name}");
+            let identity = match store.read(&file_name) {
+                Ok(data) => {
+                    let (version, seed) = match data.len() {
+                        32 => (0, data.as_slice()),
+                        36 => (u32::from_be_bytes([data[0], data[1], data[2], data[3]]), &data[4..]),
+                        _ => {
+                            return Err(
+                                std::io::Error::new(std::io::ErrorKind::InvalidData, "The identity file is the wrong size.")
+                            );
+                        }
+                    };
+                    let mut key = [0u8; 32];
+                    key.copy_from_slice(seed);
+                    Identity { version, key: SigningKey::from_bytes(&key) }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let identity = Self::generate(0);
+                    store.write(
+                        &file_name,
+                        &[identity.version.to_be_bytes().as_slice(), identity.key.as_bytes()].concat()
+                    )?;
+                    identity
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            File::create(store.dir().join(format!("{file_name}.pub")))?.write_all(&identity.public_key())?;
+            Ok(identity)
+        }
+    }
+
+    /// What the cluster signs. Binding both ephemeral keys stops the signature
+    /// from being reused in another session.
+    pub fn transcript(key_version: u32, client_public: &[u8], cluster_public: &[u8]) -> Vec<u8> {
+        [
+            b"sustenet client session".as_slice(),
+            &key_version.to_be_bytes(),
+            client_public,
+            cluster_public,
+        ].concat()
+    }
+
+    pub fn verify(identity: &[u8; PUBLIC_KEY_LEN], data: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+        match VerifyingKey::from_bytes(identity) {
+            Ok(identity) => identity.verify(data, &Signature::from_bytes(signature)).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// One side of the exchange.
+    pub struct Exchange {
+        secret: EphemeralSecret,
+        public: PublicKey,
+    }
+
+    impl Default for Exchange {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Exchange {
+        pub fn new() -> Self {
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let public = PublicKey::from(&secret);
+            Exchange { secret, public }
+        }
+
+        pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+            self.public.to_bytes()
+        }
+
+        /// Finishes the exchange with the other side's public key. The client is
+        /// the initiator. Returns `None` if the other side sent a key that
+        /// doesn't contribute to the secret, like the all-zero key.
+        pub fn finish(self, peer_public: &[u8; PUBLIC_KEY_LEN], initiator: bool) -> Option<(Sealer, Opener)> {
+            let public = self.public.to_bytes();
+            let secret = self.secret.diffie_hellman(&PublicKey::from(*peer_public));
+            if !secret.was_contributory() {
+                return None;
+            }
+            let salt = match initiator {
+                true => [public, *peer_public].concat(),
+                false => [*peer_public, public].concat(),
+            };
+            Some(derive_keys(secret.as_bytes(), &salt, b"sustenet client session", initiator))
+        }
+    }
+}
+
+/// Tokens the auth server signs after a login. Clusters check them with the
+/// auth server's public key, so they know who a player is without asking anyone.
+///
+/// A token is the format, the ID of the signing key as a u8 length-prefixed
+/// string, the account ID as a u64, when it was issued and when it expires as
+/// u64 Unix times, the username, the number of roles as a u8 and each role,
+/// followed by an Ed25519 signature over all of it.
+pub mod token {
+    use std::collections::HashMap;
+
+    use ed25519_dalek::{ Signature, Verifier, VerifyingKey };
+    use sha2::{ Digest, Sha256 };
+
+    use crate::logging::Logger;
+    use crate::utils::{ constants::PROTOCOL_VERSION, unix_time };
+
+    use super::exchange::{ Identity, PUBLIC_KEY_LEN, SIGNATURE_LEN };
+
+    /// The version of the token format.
+    pub const FORMAT: u8 = 1;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TokenError {
+        /// The token couldn't be parsed, or a field is too long to sign.
+        Malformed,
+        /// The token was signed by a key that isn't trusted.
+        UnknownKey,
+        /// The signature doesn't match the token.
+        BadSignature,
+        /// The token is past its expiry.
+        Expired,
+    }
+
+    impl std::fmt::Display for TokenError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                TokenError::Malformed => write!(f, "The token is malformed."),
+                TokenError::UnknownKey => write!(f, "The token was signed by an unknown key."),
+                TokenError::BadSignature => write!(f, "The token's signature is invalid."),
+                TokenError::Expired => write!(f, "The token has expired."),
+            }
+        }
+    }
+
+    impl std::error::Error for TokenError {}
+
+    /// Identifies a public key in a token without sending the whole key.
+    pub fn key_id(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+        let hash = Sha256::digest(public_key);
+        super::base64engine::base64_encode(&hash[..16])
+    }
+
+    /// The public keys tokens can be signed with, by their key ID.
+    #[derive(Clone, Debug, Default)]
+    pub struct TrustedKeys {
+        keys: HashMap<String, [u8; PUBLIC_KEY_LEN]>,
+    }
+
+    impl TrustedKeys {
+        pub fn insert(&mut self, public_key: [u8; PUBLIC_KEY_LEN]) {
+            self.keys.insert(key_id(&public_key), public_key);
+        }
+
+        pub fn get(&self, key_id: &str) -> Option<&[u8; PUBLIC_KEY_LEN]> {
+            self.keys.get(key_id)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.keys.is_empty()
+        }
+
+        /// Loads the `.pub` files at `paths`, which are relative to `keys_dir`.
+        /// Files that can't be read or aren't a public key are logged and skipped.
+        pub fn load(keys_dir: &str, paths: &[String], logger: &Logger) -> Self {
+            let mut trusted = TrustedKeys::default();
+            for path in paths.iter() {
+                match std::fs::read(std::path::Path::new(keys_dir).join(path)) {
+                    Ok(key) =>
+                        match <[u8; PUBLIC_KEY_LEN]>::try_from(key.as_slice()) {
+                            Ok(key) => {
+                                logger.info(format!("Trusting tokens signed with {} from '{keys_dir}/{path}'.", key_id(&key)).as_str());
+                                trusted.insert(key);
+                            }
+                            Err(_) => logger.error(format!("'{keys_dir}/{path}' isn't an auth server's public key.").as_str()),
+                        }
+                    Err(e) => logger.warning(format!("Failed to read the auth key at '{keys_dir}/{path}': {e}").as_str()),
+                }
+            }
+            if trusted.is_empty() {
+                logger.warning("No auth keys are loaded. Clients can't send tokens.");
+            }
+            trusted
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Token {
+        pub account_id: u64,
+        pub username: String,
+        pub roles: Vec<String>,
+        /// Unix time in seconds.
+        pub issued_at: u64,
+        /// Unix time in seconds.
+        pub expires_at: u64,
+    }
+
+    impl Token {
+        pub fn new(account_id: u64, username: String, roles: Vec<String>, ttl: u64) -> Self {
+            let issued_at = unix_time();
+            Token { account_id, username, roles, issued_at, expires_at: issued_at + ttl }
+        }
+
+        /// The player ID the token is for. Tickets for the player have to use the same one.
+        pub fn player_id(&self) -> String {
+            self.account_id.to_string()
+        }
+
+        pub fn sign(&self, identity: &Identity) -> Result<Vec<u8>, TokenError> {
+            let fits = |field: &str| field.len() <= (u8::MAX as usize);
+            if !fits(&self.username) || self.roles.len() > (u8::MAX as usize) || !self.roles.iter().all(|role| fits(role)) {
+                return Err(TokenError::Malformed);
+            }
+
+            let key_id = key_id(&identity.public_key());
+            let mut data = vec![FORMAT, key_id.len() as u8];
+            data.extend_from_slice(key_id.as_bytes());
+            data.extend_from_slice(&self.account_id.to_be_bytes());
+            data.extend_from_slice(&self.issued_at.to_be_bytes());
+            data.extend_from_slice(&self.expires_at.to_be_bytes());
+            data.push(self.username.len() as u8);
+            data.extend_from_slice(self.username.as_bytes());
+            data.push(self.roles.len() as u8);
+            for role in self.roles.iter() {
+                data.push(role.len() as u8);
+                data.extend_from_slice(role.as_bytes());
+            }
+            let signature = identity.sign(&signed_data(&data));
+            data.extend_from_slice(&signature);
+            Ok(data)
+        }
+
+        /// Reads a token and the ID of the key that signed it without checking the signature.
+        pub fn decode(data: &[u8]) -> Result<(Self, String), TokenError> {
+            let body = data.len().checked_sub(SIGNATURE_LEN).ok_or(TokenError::Malformed)?;
+            let mut reader = Reader { data: &data[..body] };
+            if reader.take(1)? != [FORMAT] {
+                return Err(TokenError::Malformed);
+            }
+            let key_id = reader.string()?;
+            let account_id = reader.u64()?;
+            let issued_at = reader.u64()?;
+            let expires_at = reader.u64()?;
+            let username = reader.string()?;
+            let roles = (0..reader.take(1)?[0]).map(|_| reader.string()).collect::<Result<Vec<_>, _>>()?;
+            if !reader.data.is_empty() {
+                return Err(TokenError::Malformed);
+            }
+            Ok((Token { account_id, username, roles, issued_at, expires_at }, key_id))
+        }
+
+        /// Checks the signature with the trusted key it names and makes sure it hasn't expired.
+        pub fn verify(data: &[u8], keys: &TrustedKeys) -> Result<Self, TokenError> {
+            Self::verify_at(data, keys, unix_time())
+        }
+
+        pub fn verify_at(data: &[u8], keys: &TrustedKeys, now: u64) -> Result<Self, TokenError> {
+            let (token, key_id) = Self::decode(data)?;
+            let public_key = keys.get(&key_id).ok_or(TokenError::UnknownKey)?;
+            let public_key = VerifyingKey::from_bytes(public_key).map_err(|_| TokenError::UnknownKey)?;
+            let (body, signature) = data.split_at(data.len() - SIGNATURE_LEN);
+            let signature = Signature::from_slice(signature).map_err(|_| TokenError::Malformed)?;
+            public_key.verify(&signed_data(body), &signature).map_err(|_| TokenError::BadSignature)?;
+            if token.expires_at < now {
+                return Err(TokenError::Expired);
+            }
+            Ok(token)
+        }
+    }
+
+    /// Binds the signature to tokens, so nothing else the key signs can pass for one.
+    fn signed_data(body: &[u8]) -> Vec<u8> {
+        [b"sustenet token".as_slice(), &[PROTOCOL_VERSION], body].concat()
+    }
+
+    struct Reader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Result<&'a [u8], TokenError> {
+            if self.data.len() < len {
+                return Err(TokenError::Malformed);
+            }
+            let (taken, rest) = self.data.split_at(len);
+            self.data = rest;
+            Ok(taken)
+        }
+
+        fn u64(&mut self) -> Result<u64, TokenError> {
+            Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+        }
+
+        fn string(&mut self) -> Result<String, TokenError> {
+            let len = self.take(1)?[0] as usize;
+            String::from_utf8(self.take(len)?.to_vec()).map_err(|_| TokenError::Malformed)
+        }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::{ aes::*, base64engine::* };
+    use super::{ aes::*, base64engine::*, envelope, exchange, session, ticket::*, token };
 
     #[test]
     pub fn test_create_keys_dir() {
@@ -153,23 +1434,28 @@ pub mod tests {
     pub fn test_key_gen_encode_and_decode() {
         let key = generate_key();
         let keyb64 = base64_encode(key.as_slice());
-        let key2 = base64_decode(&keyb64);
+        let key2 = base64_decode(&keyb64).unwrap();
         assert_ne!(key.as_slice(), keyb64.as_bytes());
-        assert_eq!(key.as_slice(), key2)
+        assert_eq!(key.as_slice(), key2);
+        assert!(base64_decode("not base64!").is_err());
     }
 
     #[test]
     pub fn test_encrypt_and_decrypt() {
         let key = generate_key();
         let data = b"Hello, World!";
-        let encrypted_data = encrypt(data, &key);
-        let decrypted_data = decrypt(encrypted_data.as_slice(), &key);
+        let encrypted_data = encrypt(data, &key).unwrap();
+        let decrypted_data = decrypt(encrypted_data.as_slice(), &key).unwrap();
         assert_ne!(data, encrypted_data.as_slice());
         assert_eq!(data, decrypted_data.as_slice());
+
+        assert_eq!(decrypt(&encrypted_data, &generate_key()), Err(CryptoError::Decrypt));
+        assert_eq!(decrypt(&encrypted_data[..20], &key), Err(CryptoError::TooShort));
     }
 
     #[test]
     pub fn test_save_key() {
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner");
         match save_key("cluster_key_testrunner", generate_key()) {
             Ok(_) => {}
             Err(e) => {
@@ -182,6 +1468,7 @@ pub mod tests {
     pub fn test_save_key_and_load_key() {
         let key = generate_key();
 
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner2");
         match save_key("cluster_key_testrunner2", key) {
             Ok(_) => {}
             Err(e) => {
@@ -205,6 +1492,7 @@ pub mod tests {
             panic!("Failed to create keys directory: {:?}", e);
         }
 
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner3");
         match save_key("cluster_key_testrunner3", generate_key()) {
             Ok(_) => {}
             Err(e) => {
@@ -227,6 +1515,7 @@ pub mod tests {
             panic!("Failed to create keys directory: {:?}", e);
         }
 
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner4");
         match save_key("cluster_key_testrunner4", generate_key()) {
             Ok(_) => {}
             Err(e) => {
@@ -234,6 +1523,7 @@ pub mod tests {
             }
         };
 
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner5");
         match save_key("cluster_key_testrunner5", generate_key()) {
             Ok(_) => {}
             Err(e) => {
@@ -251,6 +1541,253 @@ pub mod tests {
         assert!(keys.len() >= 2);
     }
 
+    #[test]
+    pub fn test_key_store() {
+        let dir = "keys/testrunner_store";
+        let _ = std::fs::remove_dir_all(dir);
+        let store = KeyStore::new(dir).with_passphrase("correct horse").unwrap();
+        let key = generate_key();
+        store.write("cluster_key", key.as_slice()).unwrap();
+        assert_eq!(store.read("cluster_key").unwrap(), key.as_slice());
+
+        // The file on disk is encrypted, and it only opens with the passphrase and under its own name.
+        let raw = std::fs::read(format!("{dir}/cluster_key")).unwrap();
+        assert!(!raw.windows(32).any(|window| window == key.as_slice()));
+        assert!(KeyStore::new(dir).read("cluster_key").is_err());
+        assert!(KeyStore::new(dir).with_passphrase("wrong").unwrap().read("cluster_key").is_err());
+        std::fs::copy(format!("{dir}/cluster_key"), format!("{dir}/other_key")).unwrap();
+        assert!(store.read("other_key").is_err());
+        // Saving never overwrites a key.
+        assert_eq!(store.write("cluster_key", generate_key().as_slice()).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(store.read("cluster_key").unwrap(), key.as_slice());
+
+        // Keys from before the passphrase was set are encrypted the first time they're read.
+        let plain = generate_key();
+        KeyStore::new(dir).write("plain_key", plain.as_slice()).unwrap();
+        assert_eq!(store.read("plain_key").unwrap(), plain.as_slice());
+        assert!(std::fs::read(format!("{dir}/plain_key")).unwrap().starts_with(b"SNWK"));
+        assert_eq!(store.read("plain_key").unwrap(), plain.as_slice());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = format!("{dir}/cluster_key");
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert_eq!(store.read("cluster_key").unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[test]
+    pub fn test_unreadable_key_ring() {
+        let dir = "keys/testrunner_unreadable";
+        let _ = std::fs::remove_dir_all(dir);
+        let store = KeyStore::new(dir).with_passphrase("correct horse").unwrap();
+        store.write("cluster_key", &KeyVersion::new(0, generate_key()).to_bytes()).unwrap();
+        store.write("other_key", &KeyVersion::new(0, generate_key()).to_bytes()).unwrap();
+        assert_eq!(read_key_ring(&store, "missing_key").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // A key that can't be read isn't mistaken for one that doesn't exist.
+        let wrong = KeyStore::new(dir).with_passphrase("wrong").unwrap();
+        assert_eq!(read_key_ring(&wrong, "cluster_key").unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = format!("{dir}/other_key");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert_eq!(read_key_ring(&store, "other_key").unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+            assert!(read_key_ring(&store, "cluster_key").is_ok());
+            let (keys, skipped) = read_key_files(&store).unwrap();
+            assert!(keys.contains_key("cluster_key"));
+            assert_eq!(skipped.len(), 1);
+        }
+    }
+
+    #[test]
+    pub fn test_key_versions() {
+        let mut ring = KeyRing::default();
+        ring.insert(KeyVersion { not_after: Some(200), ..KeyVersion::new(1, generate_key()) });
+        ring.insert(KeyVersion::new(0, generate_key()));
+        ring.insert(KeyVersion { not_before: Some(100), ..KeyVersion::new(2, generate_key()) });
+
+        assert_eq!(ring.current_at(50).map(|key| key.version), Some(1));
+        assert_eq!(ring.current_at(150).map(|key| key.version), Some(2));
+        // During the rotation window the previous version is still accepted, but not the one before it.
+        assert!(ring.accepts_at(1, 150).is_some());
+        assert!(ring.accepts_at(0, 150).is_none());
+        assert!(ring.accepts_at(1, 250).is_none());
+        assert!(ring.accepts_at(0, 250).is_some());
+
+        assert_eq!(parse_key_file_name("cluster_key.2"), ("cluster_key".to_string(), 2));
+        assert_eq!(parse_key_file_name("cluster_key"), ("cluster_key".to_string(), 0));
+        assert_eq!(parse_key_file_name("cluster.key"), ("cluster.key".to_string(), 0));
+    }
+
+    #[test]
+    pub fn test_save_key_version_and_load_key_ring() {
+        if let Err(e) = create_keys_dir() {
+            panic!("Failed to create keys directory: {:?}", e);
+        }
+
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner6.1");
+        let _ = std::fs::remove_file("keys/cluster_key_testrunner6.2");
+        let first = KeyVersion::new(1, generate_key());
+        let second = KeyVersion { not_before: Some(1), ..KeyVersion::new(2, generate_key()) };
+        save_key_version("cluster_key_testrunner6", &first).expect("Failed to save key.");
+        save_key_version("cluster_key_testrunner6", &second).expect("Failed to save key.");
+
+        let ring = load_key_ring("cluster_key_testrunner6").expect("Failed to load the key ring.");
+        assert_eq!(ring.versions(), &[first.clone(), second.clone()]);
+
+        // A saved version is only changed on purpose.
+        let expiring = KeyVersion { not_after: Some(2), ..first };
+        assert_eq!(save_key_version("cluster_key_testrunner6", &expiring).unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        update_key_version("cluster_key_testrunner6", &expiring).expect("Failed to update the key.");
+        let ring = load_key_ring("cluster_key_testrunner6").expect("Failed to load the key ring.");
+        assert_eq!(ring.versions(), &[expiring, second]);
+    }
+
+    #[test]
+    pub fn test_fingerprint() {
+        let key = generate_key();
+        assert_eq!(fingerprint(&key), fingerprint(&key));
+        assert_ne!(fingerprint(&key), fingerprint(&generate_key()));
+    }
+
+    #[test]
+    pub fn test_rotation_proof() {
+        let key = KeyVersion::new(2, generate_key());
+        let proof = rotation_proof(4, "cluster_key", &key).unwrap();
+        assert!(check_rotation_proof(&proof, "cluster_key", &key, 4));
+        assert!(!check_rotation_proof(&proof, "cluster_key", &key, 5));
+        assert!(!check_rotation_proof(&proof, "other_key", &key, 4));
+        assert!(!check_rotation_proof(&proof, "cluster_key", &KeyVersion::new(2, generate_key()), 4));
+        assert!(!check_rotation_proof(&proof, "cluster_key", &KeyVersion { version: 3, ..key }, 4));
+    }
+
+    #[test]
+    pub fn test_envelope() {
+        let key = generate_key();
+        let header = envelope::Header { key_id: "cluster_key".to_string(), key_version: 1 };
+        let context = envelope::Context { packet: 2, connection: b"nonce" };
+        let sealed = envelope::seal(b"hello", &header, &key, &context).unwrap();
+        assert_eq!(envelope::header(&sealed), Ok(header));
+        assert_eq!(envelope::open(&sealed, &key, &context), Ok(b"hello".to_vec()));
+
+        // It doesn't open for another packet, connection, or key.
+        let other = envelope::Context { packet: 3, connection: b"nonce" };
+        assert_eq!(envelope::open(&sealed, &key, &other), Err(CryptoError::Decrypt));
+        let other = envelope::Context { packet: 2, connection: b"other" };
+        assert_eq!(envelope::open(&sealed, &key, &other), Err(CryptoError::Decrypt));
+        assert_eq!(envelope::open(&sealed, &generate_key(), &context), Err(CryptoError::Decrypt));
+
+        // The header is authenticated too.
+        let mut changed = sealed.clone();
+        changed[2] = b'd';
+        assert_eq!(envelope::open(&changed, &key, &context), Err(CryptoError::Decrypt));
+        assert_eq!(envelope::open(&sealed[..20], &key, &context), Err(CryptoError::TooShort));
+    }
+
+    #[test]
+    pub fn test_join_ticket() {
+        let key = generate_key();
+        let client_public = [1u8; exchange::PUBLIC_KEY_LEN];
+        let ticket = JoinTicket::new("player".to_string(), 7, 30);
+        let sealed = ticket.seal("cluster_key", 3, &key, &client_public).unwrap();
+        assert_eq!(
+            JoinTicket::header(&sealed),
+            Ok(envelope::Header { key_id: "cluster_key".to_string(), key_version: 3 })
+        );
+
+        assert_eq!(JoinTicket::verify(&sealed, &key, 7, &client_public), Ok(ticket));
+        assert_eq!(JoinTicket::verify(&sealed, &key, 8, &client_public), Err(TicketError::WrongCluster));
+        assert_eq!(JoinTicket::verify(&sealed, &generate_key(), 7, &client_public), Err(TicketError::Invalid));
+        assert_eq!(JoinTicket::verify(&sealed[..8], &key, 7, &client_public), Err(TicketError::Invalid));
+        // Another session can't use it.
+        assert_eq!(JoinTicket::verify(&sealed, &key, 7, &[2u8; exchange::PUBLIC_KEY_LEN]), Err(TicketError::Invalid));
+
+        let expired = JoinTicket { expires_at: 0, ..JoinTicket::new("player".to_string(), 7, 0) };
+        assert_eq!(
+            JoinTicket::verify(&expired.seal("cluster_key", 0, &key, &client_public).unwrap(), &key, 7, &client_public),
+            Err(TicketError::Expired)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_secure_reader() {
+        use tokio::io::AsyncReadExt;
+
+        let (mut sealer, _) = session::derive_keys(b"secret", b"salt", b"test", true);
+        let (_, opener) = session::derive_keys(b"secret", b"salt", b"test", false);
+        let mut data = vec![1u8, 2];
+        data.extend_from_slice(&sealer.seal(&[3, 4, 5]).unwrap());
+        data.extend_from_slice(&sealer.seal(&[]).unwrap());
+        data.extend_from_slice(&sealer.seal(&[6]).unwrap());
+
+        let mut reader = session::SecureReader::new(data.as_slice());
+        assert_eq!(reader.read_u16().await.unwrap(), 0x0102);
+        reader.set_opener(opener);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, vec![3, 4, 5, 6]);
+
+        // A replayed frame has the wrong counter.
+        let (mut sealer, _) = session::derive_keys(b"secret", b"salt", b"test", true);
+        let (_, opener) = session::derive_keys(b"secret", b"salt", b"test", false);
+        let frame = sealer.seal(&[1]).unwrap();
+        let replayed = [frame.as_slice(), frame.as_slice()].concat();
+        let mut reader = session::SecureReader::new(replayed.as_slice());
+        reader.set_opener(opener);
+        assert_eq!(reader.read_u8().await.unwrap(), 1);
+        assert!(reader.read_u8().await.is_err());
+    }
+
+    #[test]
+    pub fn test_key_exchange() {
+        let identity = exchange::Identity::generate(1);
+        let client = exchange::Exchange::new();
+        let cluster = exchange::Exchange::new();
+        let (client_public, cluster_public) = (client.public_key(), cluster.public_key());
+
+        let transcript = exchange::transcript(identity.version(), &client_public, &cluster_public);
+        let signature = identity.sign(&transcript);
+        assert!(exchange::verify(&identity.public_key(), &transcript, &signature));
+        let other = exchange::transcript(identity.version(), &cluster_public, &client_public);
+        assert!(!exchange::verify(&identity.public_key(), &other, &signature));
+
+        let (mut client_sealer, mut client_opener) = client.finish(&cluster_public, true).unwrap();
+        let (mut cluster_sealer, mut cluster_opener) = cluster.finish(&client_public, false).unwrap();
+        let frame = client_sealer.seal(b"hello").unwrap();
+        assert_eq!(cluster_opener.open(&frame[4..]), Ok(b"hello".to_vec()));
+        let frame = cluster_sealer.seal(b"hi").unwrap();
+        assert_eq!(client_opener.open(&frame[4..]), Ok(b"hi".to_vec()));
+
+        assert!(exchange::Exchange::new().finish(&[0u8; 32], true).is_none());
+    }
+
+    #[test]
+    pub fn test_token() {
+        let identity = exchange::Identity::generate(0);
+        let mut keys = token::TrustedKeys::default();
+        keys.insert(identity.public_key());
+
+        let issued = token::Token::new(7, "player".to_string(), vec!["moderator".to_string()], 60);
+        let signed = issued.sign(&identity).unwrap();
+        assert_eq!(token::Token::verify(&signed, &keys), Ok(issued.clone()));
+        assert_eq!(token::Token::verify_at(&signed, &keys, issued.expires_at + 1), Err(token::TokenError::Expired));
+        assert_eq!(
+            token::Token::verify(&signed, &token::TrustedKeys::default()),
+            Err(token::TokenError::UnknownKey)
+        );
+
+        // Changing the roles breaks the signature.
+        let mut changed = signed.clone();
+        let role = changed.len() - 64 - 1;
+        changed[role] = b'x';
+        assert_eq!(token::Token::verify(&changed, &keys), Err(token::TokenError::BadSignature));
+        assert_eq!(token::Token::verify(&signed[..40], &keys), Err(token::TokenError::Malformed));
+    }
+
     #[test]
     pub fn test_path() {
         println!("Path: {:?}", workspace_dir());
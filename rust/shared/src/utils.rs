@@ -12,8 +12,37 @@ pub fn shutdown_channel() -> Result<tokio::sync::broadcast::Receiver<bool>, ctrl
     Ok(rx)
 }
 
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Locks `<path>.lock` until the returned file is dropped. It's a separate file
+/// since files saved with `write_atomic` are replaced, not written in place.
+pub fn lock_file(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Writes the file through a temporary file next to it, so anything reading it
+/// sees either the old contents or the new ones and never half of a file.
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
 pub mod constants {
     pub const VERSION: &str = "0.1.4";
+    /// Goes up whenever the wire format changes. Envelopes are bound to it.
+    pub const PROTOCOL_VERSION: u8 = 1;
 
     pub const DEBUGGING: bool = cfg!(debug_assertions);
 
@@ -24,6 +53,7 @@ pub mod constants {
     pub const DEFAULT_IP: &str = "127.0.0.1";
     pub const MASTER_PORT: u16 = 6256;
     pub const CLUSTER_PORT: u16 = 6257;
+    pub const AUTH_PORT: u16 = 6258;
 
     pub const TERMINAL_BG_GRAY: &str = "\x1b[47m";
     pub const TERMINAL_DEFAULT: &str = "\x1b[39m";
@@ -1,5 +1,6 @@
 use sustenet::cluster::{ cleanup, start_with_config, LOGGER };
 use sustenet::shared::ServerPlugin;
+use sustenet::shared::network::LinkReader;
 use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc::Sender;
 
@@ -17,7 +18,7 @@ impl Reia {
     async fn handle_data(
         tx: Sender<Box<[u8]>>,
         command: u8,
-        reader: &mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        reader: &mut LinkReader<'_>
     ) {
         LOGGER.info(&format!("Received new command: {}", command));
 
@@ -52,7 +53,7 @@ impl ServerPlugin for Reia {
         &self,
         tx: Sender<Box<[u8]>>,
         command: u8,
-        reader: &'plug mut tokio::io::BufReader<tokio::net::tcp::ReadHalf<'_>>
+        reader: &'plug mut LinkReader<'_>
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'plug>> {
         Box::pin(Self::handle_data(tx, command, reader))
     }